@@ -0,0 +1,31 @@
+//! Command-line client for the rustic-garden daemon's control port.
+
+use rustic_garden::ctl::{self, Invocation};
+use std::process;
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let invocation = match Invocation::parse(&args, ctl::garden_timezone) {
+        Ok(invocation) => invocation,
+        Err(message) => {
+            eprintln!("{}", message);
+            process::exit(2);
+        }
+    };
+
+    let response = match ctl::send(&invocation.address, &invocation.request()) {
+        Ok(response) => response,
+        Err(e) => {
+            eprintln!("cannot reach the daemon at {}: {}", invocation.address, e);
+            process::exit(1);
+        }
+    };
+
+    match invocation.render(response) {
+        Ok(out) => print!("{}", out),
+        Err(message) => {
+            eprintln!("error: {}", message);
+            process::exit(1);
+        }
+    }
+}
@@ -1,47 +1,467 @@
 extern crate log;
 
-use super::config_persist::{ConfigPersist, SchedulePersist};
-use log::{error, info};
+use super::clock::{Clock, SystemClock};
+use super::config_persist::{
+    ConfigPersist, FlowMeterPersist, LeakDetectionPersist, SchedulePersist, ValvePersist,
+};
+use super::conflicts::{self, Conflict};
+use super::constants::CONFLICT_CHECK_DAYS;
+use super::error::{Error, Result};
+use super::migrations::{self, SCHEMA_VERSION};
+use chrono::offset::LocalResult;
+use chrono::{
+    DateTime, Duration, FixedOffset, Local, NaiveDate, NaiveDateTime, Offset, TimeZone, Utc,
+};
+use chrono_tz::Tz;
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use std::fmt;
 use std::io;
 use std::io::{BufReader, Read, Write};
+use std::mem;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Arc;
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct Schedule {
     name: String,
-    start_offset_min: u64,
+    start_offsets_min: Vec<u64>,
     duration_min: u64,
     repeat_period_days: u64,
     valves: Vec<String>,
 }
 
+impl Schedule {
+    /// Creates a schedule running every `repeat_period_days` days, at each of `start_offsets_min`
+    /// minutes after midnight, for `duration_min` minutes.
+    pub fn new(
+        name: String,
+        start_offsets_min: Vec<u64>,
+        duration_min: u64,
+        repeat_period_days: u64,
+        valves: Vec<String>,
+    ) -> Schedule {
+        Schedule {
+            name,
+            start_offsets_min,
+            duration_min,
+            repeat_period_days,
+            valves,
+        }
+    }
+
+    /// The name of the schedule.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The minutes after midnight of each start on a day the schedule runs.
+    pub fn start_offsets_min(&self) -> &[u64] {
+        &self.start_offsets_min
+    }
+
+    /// How long each run lasts, in minutes.
+    pub fn duration_min(&self) -> u64 {
+        self.duration_min
+    }
+
+    /// The number of days between days the schedule runs.
+    pub fn repeat_period_days(&self) -> u64 {
+        self.repeat_period_days
+    }
+
+    /// The valves opened while the schedule runs.
+    pub fn valves(&self) -> &[String] {
+        &self.valves
+    }
+
+    /// Returns the runs of this schedule that overlap the window `[from, until)`, in start order.
+    ///
+    /// A schedule runs on every day whose number of days since 1970-01-01 is a multiple of
+    /// `repeat_period_days`, starting each of `start_offsets_min` minutes after midnight on that
+    /// day by the wall clock in `tz`, and lasting `duration_min` real minutes. See `resolve_local` for how
+    /// start times that fall in a daylight saving transition are handled.
+    pub fn occurrences<Z: TimeZone>(
+        &self,
+        tz: &Z,
+        from: DateTime<Utc>,
+        until: DateTime<Utc>,
+    ) -> Vec<Occurrence> {
+        let mut occurrences = Vec::new();
+        if self.repeat_period_days == 0 {
+            warn!("schedule {} has a zero repeat period", self.name);
+            return occurrences;
+        }
+
+        // Start early enough to catch runs from previous days still going at `from`
+        let latest_offset = self.start_offsets_min.iter().copied().max().unwrap_or(0);
+        let reach_days = (latest_offset + self.duration_min) / MINUTES_PER_DAY + 1;
+        let mut day =
+            from.with_timezone(tz).date().naive_local() - Duration::days(reach_days as i64);
+        let last_day = until.with_timezone(tz).date().naive_local();
+
+        while day <= last_day {
+            if days_since_epoch(day).rem_euclid(self.repeat_period_days as i64) == 0 {
+                for offset in &self.start_offsets_min {
+                    let wall_clock = day.and_hms(0, 0, 0) + Duration::minutes(*offset as i64);
+                    let start = resolve_local(tz, wall_clock);
+                    let end = start + Duration::minutes(self.duration_min as i64);
+                    if start < until && end > from {
+                        occurrences.push(Occurrence {
+                            schedule: self.name.clone(),
+                            start: start.with_timezone(&start.offset().fix()),
+                            end: end.with_timezone(&end.offset().fix()),
+                            valves: self.valves.clone(),
+                        });
+                    }
+                }
+            }
+            day = day.succ();
+        }
+
+        occurrences.sort_by_key(|occurrence| occurrence.start);
+        occurrences
+    }
+}
+
+/// Converts a wall clock time in `tz` to the instant a run starting then should begin.
+///
+/// Daylight saving transitions make some wall clock times happen twice or not at all. A run
+/// always happens exactly once, by these rules:
+///
+/// * A repeated time, when clocks fall back, resolves to its first occurrence.
+/// * A skipped time, when clocks spring forward, is moved forward by the length of the gap, so
+///   02:30 on a day clocks jump from 02:00 to 03:00 resolves to 03:30.
+pub(crate) fn resolve_local<Z: TimeZone>(tz: &Z, wall_clock: NaiveDateTime) -> DateTime<Utc> {
+    match tz.from_local_datetime(&wall_clock) {
+        LocalResult::Single(t) => t.with_timezone(&Utc),
+        LocalResult::Ambiguous(first, _) => first.with_timezone(&Utc),
+        LocalResult::None => {
+            // Read the time with the offset in effect before the gap, transitions are months apart
+            let before = tz
+                .from_local_datetime(&(wall_clock - Duration::days(1)))
+                .earliest()
+                .expect("day before a transition is unambiguous");
+            let offset = before.offset().fix();
+            DateTime::<Utc>::from_utc(wall_clock - Duration::seconds(offset.local_minus_utc() as i64), Utc)
+        }
+    }
+}
+
+/// A single run of a schedule.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Occurrence {
+    /// The name of the schedule.
+    pub schedule: String,
+
+    /// The time when the run starts, with the calendar's UTC offset at the time.
+    pub start: DateTime<FixedOffset>,
+
+    /// The time when the run ends, with the calendar's UTC offset at the time.
+    pub end: DateTime<FixedOffset>,
+
+    /// The valves open during the run.
+    pub valves: Vec<String>,
+}
+
+const MINUTES_PER_DAY: u64 = super::constants::MINUTES_PER_DAY as u64;
+
+fn days_since_epoch(day: NaiveDate) -> i64 {
+    (day - NaiveDate::from_ymd(1970, 1, 1)).num_days()
+}
+
+/// A change to the configuration, sent to subscribers after each update.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ConfigChange {
+    /// A schedule was added or changed.
+    ScheduleSaved(String),
+
+    /// A schedule was removed.
+    ScheduleDeleted(String),
+
+    /// A valve was added or changed.
+    ValveSaved(String),
+
+    /// A valve was removed.
+    ValveDeleted(String),
+
+    /// The timezone, master valve, flow meter or limits changed.
+    SettingsChanged,
+}
+
+impl fmt::Display for ConfigChange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigChange::ScheduleSaved(name) => write!(f, "schedule {} added or changed", name),
+            ConfigChange::ScheduleDeleted(name) => write!(f, "schedule {} removed", name),
+            ConfigChange::ValveSaved(name) => write!(f, "valve {} added or changed", name),
+            ConfigChange::ValveDeleted(name) => write!(f, "valve {} removed", name),
+            ConfigChange::SettingsChanged => write!(f, "settings changed"),
+        }
+    }
+}
+
+/// What to do with schedules still using a valve that is deleted.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DeletePolicy {
+    /// Refuse to delete the valve.
+    Fail,
+
+    /// Remove the valve from every schedule, and as the master valve.
+    Cascade,
+}
+
+/// Owns the garden's configuration: valves, schedules and settings.
+///
+/// All changes go through `update`, which applies them together or not at all and tells
+/// subscribers what changed.
 pub struct Calendar {
     cache: ConfigPersist,
-    persistent_store: Box<dyn Write>,
+    persistent_store: Box<dyn Write + Send>,
+    clock: Arc<dyn Clock>,
+    migrated_from: Option<String>,
+    subscribers: Vec<Sender<ConfigChange>>,
 }
 
 impl Calendar {
     /// Creates a new, empty Calendar.
-    pub fn new(persistent_store: Box<dyn Write>) -> Calendar {
+    pub fn new(persistent_store: Box<dyn Write + Send>) -> Calendar {
+        Calendar::with_clock(persistent_store, Arc::new(SystemClock))
+    }
+
+    /// Creates a new, empty Calendar that checks for conflicts from the given clock's time.
+    pub fn with_clock(persistent_store: Box<dyn Write + Send>, clock: Arc<dyn Clock>) -> Calendar {
         Calendar {
-            cache: ConfigPersist::new(String::from("0.1")),
+            cache: ConfigPersist::new(String::from(SCHEMA_VERSION)),
             persistent_store,
+            clock,
+            migrated_from: None,
+            subscribers: Vec::new(),
+        }
+    }
+
+    /// Returns a channel receiving every change made from now on.
+    pub fn subscribe(&mut self) -> Receiver<ConfigChange> {
+        let (sender, receiver) = mpsc::channel();
+        self.subscribers.push(sender);
+        receiver
+    }
+
+    /// Applies `edit` to a copy of the configuration, then saves the copy in its place.
+    ///
+    /// Nothing changes if `edit` fails, if it leaves a schedule or the master valve naming a valve
+    /// that does not exist, or if saving fails. Subscribers are told about each changed valve and
+    /// schedule once the new configuration is saved.
+    pub fn update<T, F>(&mut self, edit: F) -> Result<T>
+    where
+        F: FnOnce(&mut ConfigPersist) -> Result<T>,
+    {
+        let mut updated = self.cache.clone();
+        let result = edit(&mut updated)?;
+        self.commit(updated, true)?;
+        Ok(result)
+    }
+
+    /// Replaces the configuration with one read from `source`, with the same checks as `update`.
+    ///
+    /// Meant for picking up edits made to the file outside the daemon, so the source is not
+    /// written back. Returns what changed. Nothing changes if the source is invalid.
+    pub fn reload(&mut self, source: &mut dyn Read) -> Result<Vec<ConfigChange>> {
+        let mut loaded = Calendar::new(Box::new(io::sink()));
+        loaded.initialize(source)?;
+        self.commit(loaded.cache, false)
+    }
+
+    /// Swaps in `updated` if it adds no references to missing valves, saving it if `save` is set,
+    /// then tells subscribers what changed.
+    fn commit(&mut self, updated: ConfigPersist, save: bool) -> Result<Vec<ConfigChange>> {
+        let existing = self.cache.dangling_references();
+        if let Some((user, valve)) = updated
+            .dangling_references()
+            .into_iter()
+            .find(|reference| !existing.contains(reference))
+        {
+            return Err(Error::InvalidSchedule(format!(
+                "{} uses unknown valve {}",
+                user, valve
+            )));
+        }
+
+        let previous = mem::replace(&mut self.cache, updated);
+        if save {
+            if let Err(e) = self.sync() {
+                self.cache = previous;
+                return Err(e);
+            }
         }
+
+        let changes = diff(&previous, &self.cache);
+        // A subscriber that hung up is dropped
+        self.subscribers.retain(|subscriber| {
+            changes
+                .iter()
+                .all(|change| subscriber.send(change.clone()).is_ok())
+        });
+        Ok(changes)
+    }
+
+    /// Adds a new valve or overwrites an existing one with the same name.
+    pub fn create_or_replace_valve(&mut self, valve: ValvePersist) -> Result<()> {
+        info!("create or replace valve {}: {:?}", valve.name, valve);
+        self.update(|config| {
+            config.create_or_replace_valve(valve);
+            Ok(())
+        })
+    }
+
+    /// Removes a valve, with `policy` deciding what happens to schedules that still use it.
+    pub fn delete_valve(&mut self, name: &str, policy: DeletePolicy) -> Result<()> {
+        info!("delete valve {} ({:?})", name, policy);
+        self.update(|config| {
+            if !config.delete_valve(name) {
+                return Err(Error::ValveNotFound(String::from(name)));
+            }
+            if policy == DeletePolicy::Cascade {
+                config.remove_valve_references(name);
+            }
+            Ok(())
+        })
     }
 
     /// Add a new schedule or overwrite an existing one with the same name
-    pub fn create_or_replace_schedule(&mut self, schedule: Schedule) -> io::Result<()> {
+    ///
+    /// Returns any conflicts the schedule has with the others over the coming weeks. Conflicts
+    /// do not prevent the schedule from being saved.
+    pub fn create_or_replace_schedule(&mut self, schedule: Schedule) -> Result<Vec<Conflict>> {
         info!(
             "create or replace schedule {}: {:?}",
             schedule.name, schedule
         );
-        self.cache.create_or_replace_schedule(schedule.into());
-        self.sync()
+        let name = schedule.name.clone();
+        self.update(|config| {
+            config.create_or_replace_schedule(schedule.into());
+            Ok(())
+        })?;
+
+        let conflicts: Vec<Conflict> = self
+            .find_conflicts(self.clock.now(), CONFLICT_CHECK_DAYS)
+            .into_iter()
+            .filter(|conflict| conflict.schedules.contains(&name))
+            .collect();
+        for conflict in &conflicts {
+            warn!("schedule {} conflicts: {:?}", name, conflict);
+        }
+        Ok(conflicts)
     }
 
-    pub fn delete_schedule(&mut self, name: &str) -> io::Result<()> {
+    pub fn delete_schedule(&mut self, name: &str) -> Result<()> {
         info!("delete schedule {}", name);
-        self.cache.delete_schedule(name);
-        self.sync()
+        self.update(|config| {
+            if !config.delete_schedule(name) {
+                return Err(Error::ScheduleNotFound(String::from(name)));
+            }
+            Ok(())
+        })
+    }
+
+    /// Gets a schedule by name.
+    pub fn get(&self, name: &str) -> Option<Schedule> {
+        self.cache
+            .get_schedule(name)
+            .map(|schedule_persist| Schedule::from(schedule_persist.clone()))
+    }
+
+    /// Returns the runs of all schedules over the next `days` days from `from`, in start order.
+    ///
+    /// Runs are computed in the configured timezone, or the system's local time if none is set.
+    pub fn project(&self, from: DateTime<Utc>, days: u32) -> Vec<Occurrence> {
+        let until = from + Duration::days(days as i64);
+        let timezone = self.timezone();
+        let mut occurrences: Vec<Occurrence> = self
+            .list()
+            .flat_map(|schedule| match &timezone {
+                Some(tz) => schedule.occurrences(tz, from, until),
+                None => schedule.occurrences(&Local, from, until),
+            })
+            .collect();
+        occurrences.sort_by(|a, b| a.start.cmp(&b.start).then(a.schedule.cmp(&b.schedule)));
+        occurrences
+    }
+
+    /// Returns the run of the named schedule in progress at `at`, if any.
+    pub fn occurrence_at(&self, name: &str, at: DateTime<Utc>) -> Option<Occurrence> {
+        let schedule = self.get(name)?;
+        let until = at + Duration::seconds(1);
+        let occurrences = match self.timezone() {
+            Some(tz) => schedule.occurrences(&tz, at, until),
+            None => schedule.occurrences(&Local, at, until),
+        };
+        occurrences.into_iter().find(|occurrence| occurrence.start <= at)
+    }
+
+    /// Returns the runs starting in the next `days` days from `from`, in start order.
+    ///
+    /// If `valve` is given, only runs that open it are returned.
+    pub fn upcoming(&self, from: DateTime<Utc>, days: u32, valve: Option<&str>) -> Vec<Occurrence> {
+        self.project(from, days)
+            .into_iter()
+            .filter(|occurrence| occurrence.start >= from)
+            .filter(|occurrence| match valve {
+                Some(v) => occurrence.valves.iter().any(|name| name == v),
+                None => true,
+            })
+            .collect()
+    }
+
+    /// Finds overlapping use of a valve and excess open valves over the next `days` days.
+    pub fn find_conflicts(&self, from: DateTime<Utc>, days: u32) -> Vec<Conflict> {
+        let occurrences = self.project(from, days);
+        conflicts::analyze(&occurrences, self.cache.max_open_valves())
+    }
+
+    /// The configured IANA timezone, or `None` to use the system's local time.
+    pub fn timezone(&self) -> Option<Tz> {
+        self.cache
+            .timezone()
+            .map(|name| name.parse().expect("timezone validated on initialize"))
+    }
+
+    /// The calendar date at `at` in the configured timezone.
+    pub fn date_of(&self, at: DateTime<Utc>) -> NaiveDate {
+        match self.timezone() {
+            Some(tz) => at.with_timezone(&tz).date().naive_local(),
+            None => at.with_timezone(&Local).date().naive_local(),
+        }
+    }
+
+    /// The instant `date` begins in the configured timezone.
+    pub fn start_of_day(&self, date: NaiveDate) -> DateTime<Utc> {
+        let midnight = date.and_hms(0, 0, 0);
+        match self.timezone() {
+            Some(tz) => resolve_local(&tz, midnight),
+            None => resolve_local(&Local, midnight),
+        }
+    }
+
+    /// The configured valves.
+    pub fn valves(&self) -> impl Iterator<Item = &ValvePersist> {
+        self.cache.iter_valves()
+    }
+
+    /// The name of the master valve, if any.
+    pub fn master_valve(&self) -> Option<&str> {
+        self.cache.master_valve()
+    }
+
+    /// The configured flow meter, if any.
+    pub fn flow_meter(&self) -> Option<&FlowMeterPersist> {
+        self.cache.flow_meter()
+    }
+
+    /// The configured leak detection thresholds.
+    pub fn leak_detection(&self) -> &LeakDetectionPersist {
+        self.cache.leak_detection()
     }
 
     pub fn list(&self) -> impl Iterator<Item = Schedule> + '_ {
@@ -51,53 +471,111 @@ impl Calendar {
             .map(|schedule_persist| Schedule::from(schedule_persist.clone()));
     }
 
-    pub fn initialize(&mut self, source: &mut dyn Read) -> io::Result<()> {
+    pub fn initialize(&mut self, source: &mut dyn Read) -> Result<()> {
         let reader = BufReader::new(source);
 
         // Deserialize
-        let r = serde_yaml::from_reader(reader);
-        if let Err(e) = r {
-            return Err(io::Error::new(io::ErrorKind::InvalidData, e));
+        let mut value = serde_yaml::from_reader(reader)?;
+        let migrated_from = migrations::migrate(&mut value)?;
+        let data: ConfigPersist = serde_yaml::from_value(value)?;
+
+        if let Some(name) = data.timezone() {
+            if let Err(e) = name.parse::<Tz>() {
+                error!("unknown timezone {}: {}", name, e);
+                return Err(Error::InvalidSchedule(format!("unknown timezone {}", name)));
+            }
         }
-        let value = r.unwrap();
-
-        return match serde_yaml::from_value(value) {
-            Ok(data) => {
-                self.cache = data;
-                Ok(())
+        if let Some(meter) = data.flow_meter() {
+            if meter.pulses_per_liter.is_nan() || meter.pulses_per_liter <= 0.0 {
+                return Err(Error::InvalidSchedule(format!(
+                    "flow meter pulses_per_liter must be positive, not {}",
+                    meter.pulses_per_liter
+                )));
             }
-            Err(e) => Err(io::Error::new(io::ErrorKind::InvalidData, e)),
-        };
+        }
+
+        self.cache = data;
+        self.migrated_from = migrated_from;
+        Ok(())
+    }
+
+    /// The schema version the schedules were upgraded from on `initialize`, or `None` if they
+    /// were already current.
+    pub fn migrated_from(&self) -> Option<&str> {
+        self.migrated_from.as_deref()
+    }
+
+    /// Writes the schedules to persistent storage, e.g. after they were migrated.
+    pub fn save(&mut self) -> Result<()> {
+        self.sync()
     }
 }
 
 impl Calendar {
     /// Syncs the in-memory schedules cache to persistent storage.
-    fn sync(&mut self) -> io::Result<()> {
+    fn sync(&mut self) -> Result<()> {
         // Convert to serde_yaml
         let r = serde_yaml::to_value(&self.cache);
         if let Err(e) = r {
-            return Err(io::Error::new(io::ErrorKind::InvalidData, e));
+            return Err(Error::Storage(io::Error::new(io::ErrorKind::InvalidData, e)));
         }
         let value = r.unwrap();
 
         // Serialize
         let data = serde_yaml::to_string(&value);
         if let Err(e) = data {
-            return Err(io::Error::new(io::ErrorKind::InvalidData, e));
+            return Err(Error::Storage(io::Error::new(io::ErrorKind::InvalidData, e)));
         }
         let data: String = data.unwrap();
 
         // Return the result of writing to storage
-        return self.persistent_store.write_all(data.as_bytes());
+        self.persistent_store.write_all(data.as_bytes())?;
+        self.persistent_store.flush()?;
+        Ok(())
     }
 }
 
+/// Lists the changes from `before` to `after`.
+fn diff(before: &ConfigPersist, after: &ConfigPersist) -> Vec<ConfigChange> {
+    let mut changes = Vec::new();
+
+    for valve in after.iter_valves() {
+        if before.get_valve(&valve.name) != Some(valve) {
+            changes.push(ConfigChange::ValveSaved(valve.name.clone()));
+        }
+    }
+    for valve in before.iter_valves() {
+        if after.get_valve(&valve.name).is_none() {
+            changes.push(ConfigChange::ValveDeleted(valve.name.clone()));
+        }
+    }
+
+    // Schedules only compare by name, compare every field instead
+    let schedule = |config: &ConfigPersist, name: &str| {
+        config.get_schedule(name).cloned().map(Schedule::from)
+    };
+    for s in after.iter_schedules() {
+        if schedule(before, &s.name) != schedule(after, &s.name) {
+            changes.push(ConfigChange::ScheduleSaved(s.name.clone()));
+        }
+    }
+    for s in before.iter_schedules() {
+        if after.get_schedule(&s.name).is_none() {
+            changes.push(ConfigChange::ScheduleDeleted(s.name.clone()));
+        }
+    }
+
+    if !before.same_settings(after) {
+        changes.push(ConfigChange::SettingsChanged);
+    }
+    changes
+}
+
 impl From<SchedulePersist> for Schedule {
     fn from(p: SchedulePersist) -> Schedule {
         Schedule {
             name: p.name,
-            start_offset_min: p.start_offset_min,
+            start_offsets_min: p.start_offsets_min,
             duration_min: p.duration_min,
             repeat_period_days: p.repeat_period_days,
             valves: p.valves,
@@ -109,7 +587,7 @@ impl From<Schedule> for SchedulePersist {
     fn from(p: Schedule) -> SchedulePersist {
         SchedulePersist {
             name: p.name,
-            start_offset_min: p.start_offset_min,
+            start_offsets_min: p.start_offsets_min,
             duration_min: p.duration_min,
             repeat_period_days: p.repeat_period_days,
             valves: p.valves,
@@ -120,6 +598,8 @@ impl From<Schedule> for SchedulePersist {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::conflicts::ConflictKind;
+    use chrono_tz::America::Los_Angeles;
     use std::{mem, ptr};
 
     #[test]
@@ -133,6 +613,266 @@ mod tests {
         assert!(c.list().find(|s| schedule_name == s.name).is_some());
     }
 
+    #[test]
+    fn get_schedule_by_name() {
+        let mut c = Calendar::new(Box::new(Vec::<u8>::new()));
+        let schedule_name = String::from("test schedule");
+
+        assert!(c.get(&schedule_name).is_none());
+        assert!(c.create_or_replace_schedule(any_schedule(&schedule_name)).is_ok());
+        assert_eq!(c.get(&schedule_name).expect("schedule exists").name(), schedule_name);
+    }
+
+    #[test]
+    fn occurrences_repeat_every_period_at_offset() {
+        let schedule = daily_at("every other day", 420, 30);
+        let schedule = Schedule {
+            repeat_period_days: 2,
+            ..schedule
+        };
+        // 2020-07-01 is day 18444 since the epoch
+        let from = Los_Angeles.ymd(2020, 7, 1).and_hms(0, 0, 0).with_timezone(&Utc);
+
+        let runs = schedule.occurrences(&Los_Angeles, from, from + Duration::days(5));
+
+        let starts: Vec<_> = runs.iter().map(|o| o.start).collect();
+        assert_eq!(
+            starts,
+            vec![
+                Los_Angeles.ymd(2020, 7, 1).and_hms(7, 0, 0),
+                Los_Angeles.ymd(2020, 7, 3).and_hms(7, 0, 0),
+                Los_Angeles.ymd(2020, 7, 5).and_hms(7, 0, 0),
+            ]
+        );
+        assert_eq!(runs[0].end, Los_Angeles.ymd(2020, 7, 1).and_hms(7, 30, 0));
+    }
+
+    #[test]
+    fn occurrences_include_run_in_progress_from_previous_day() {
+        let schedule = daily_at("overnight", 23 * 60, 120);
+        let from = Los_Angeles.ymd(2020, 7, 2).and_hms(0, 30, 0).with_timezone(&Utc);
+
+        let runs = schedule.occurrences(&Los_Angeles, from, from + Duration::hours(1));
+
+        assert_eq!(runs.len(), 1);
+        assert_eq!(runs[0].start, Los_Angeles.ymd(2020, 7, 1).and_hms(23, 0, 0));
+    }
+
+    #[test]
+    fn occurrences_keep_wall_clock_time_across_dst() {
+        let schedule = daily_at("morning", 420, 30);
+        let from = Utc.ymd(2020, 3, 7).and_hms(0, 0, 0);
+
+        let runs = schedule.occurrences(&Los_Angeles, from, from + Duration::days(3));
+
+        // 07:00 PST is 15:00 UTC, 07:00 PDT is 14:00 UTC
+        let starts: Vec<_> = runs.iter().map(|o| o.start.with_timezone(&Utc)).collect();
+        assert_eq!(
+            starts,
+            vec![
+                Utc.ymd(2020, 3, 7).and_hms(15, 0, 0),
+                Utc.ymd(2020, 3, 8).and_hms(14, 0, 0),
+                Utc.ymd(2020, 3, 9).and_hms(14, 0, 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn occurrence_in_spring_forward_gap_moves_forward_by_gap() {
+        // Clocks jump from 02:00 PST to 03:00 PDT, 02:30 never happens
+        let schedule = daily_at("night", 150, 20);
+        let from = Los_Angeles.ymd(2020, 3, 8).and_hms(0, 0, 0).with_timezone(&Utc);
+
+        let runs = schedule.occurrences(&Los_Angeles, from, from + Duration::hours(12));
+
+        assert_eq!(runs.len(), 1);
+        assert_eq!(runs[0].start, Los_Angeles.ymd(2020, 3, 8).and_hms(3, 30, 0));
+        assert_eq!(runs[0].end, Los_Angeles.ymd(2020, 3, 8).and_hms(3, 50, 0));
+    }
+
+    #[test]
+    fn occurrence_in_fall_back_overlap_runs_once_at_first_time() {
+        // Clocks fall back from 02:00 PDT to 01:00 PST, 01:30 happens twice
+        let schedule = daily_at("night", 90, 20);
+        let from = Los_Angeles.ymd(2020, 11, 1).and_hms(0, 0, 0).with_timezone(&Utc);
+
+        let runs = schedule.occurrences(&Los_Angeles, from, from + Duration::hours(12));
+
+        assert_eq!(runs.len(), 1);
+        assert_eq!(runs[0].start.with_timezone(&Utc), Utc.ymd(2020, 11, 1).and_hms(8, 30, 0));
+    }
+
+    #[test]
+    fn initialize_rejects_unknown_timezone() {
+        let mut c = Calendar::new(Box::new(Vec::<u8>::new()));
+        let yaml = "version: \"0.2\"\ntimezone: Mars/Olympus_Mons\nvalves: []\nschedules: []\n";
+
+        assert!(c.initialize(&mut yaml.as_bytes()).is_err());
+    }
+
+    #[test]
+    fn initialize_rejects_flow_meter_without_pulses() {
+        let mut c = Calendar::new(Box::new(Vec::<u8>::new()));
+        let yaml = "version: \"0.2\"\nflow_meter:\n  pin: 17\n  pulses_per_liter: 0\nvalves: []\n\
+                    schedules: []\n";
+
+        assert!(matches!(
+            c.initialize(&mut yaml.as_bytes()),
+            Err(Error::InvalidSchedule(_))
+        ));
+    }
+
+    #[test]
+    fn initialize_migrates_old_schema() {
+        let mut c = Calendar::new(Box::new(Vec::<u8>::new()));
+        let yaml = "version: \"0.1\"\nvalves: []\nschedules:\n  - name: beds\n    \
+                    start_offset_min: 420\n    duration_min: 30\n    repeat_period_days: 1\n    \
+                    valves: []\n";
+
+        c.initialize(&mut yaml.as_bytes()).expect("initialize succeeds");
+        assert_eq!(c.migrated_from(), Some("0.1"));
+        assert_eq!(c.get("beds").expect("schedule exists").start_offsets_min, vec![420]);
+
+        c.save().expect("save succeeds");
+        let saved = String::from_utf8(peek_storage(&mut c).clone()).expect("utf-8");
+        assert!(saved.contains(&format!("version: \"{}\"", SCHEMA_VERSION)));
+    }
+
+    #[test]
+    fn initialize_refuses_newer_schema() {
+        let mut c = Calendar::new(Box::new(Vec::<u8>::new()));
+        let yaml = "version: \"99.0\"\nvalves: []\nschedules: []\n";
+
+        assert!(c.initialize(&mut yaml.as_bytes()).is_err());
+    }
+
+    #[test]
+    fn several_starts_a_day_each_run() {
+        let mut schedule = daily_at("beds", 6 * 60, 15);
+        schedule.start_offsets_min = vec![18 * 60, 6 * 60];
+        let from = Los_Angeles.ymd(2020, 7, 1).and_hms(0, 0, 0).with_timezone(&Utc);
+
+        let runs = schedule.occurrences(&Los_Angeles, from, from + Duration::days(1));
+
+        let starts: Vec<_> = runs.iter().map(|o| o.start).collect();
+        assert_eq!(
+            starts,
+            vec![
+                Los_Angeles.ymd(2020, 7, 1).and_hms(6, 0, 0),
+                Los_Angeles.ymd(2020, 7, 1).and_hms(18, 0, 0)
+            ]
+        );
+    }
+
+    #[test]
+    fn upcoming_is_sorted_and_excludes_runs_in_progress() {
+        let mut c = calendar_in("America/Los_Angeles");
+        let mut morning = any_schedule(&String::from("morning"));
+        morning.start_offsets_min = vec![6 * 60];
+        morning.repeat_period_days = 1;
+        morning.valves = vec![String::from("beds")];
+        let mut evening = morning.clone();
+        evening.name = String::from("evening");
+        evening.start_offsets_min = vec![18 * 60];
+        evening.valves = vec![String::from("pots")];
+        add_valves(&mut c, &["beds", "pots"]);
+        c.create_or_replace_schedule(morning).expect("create succeeds");
+        c.create_or_replace_schedule(evening).expect("create succeeds");
+
+        let from = Los_Angeles.ymd(2020, 7, 1).and_hms(6, 30, 0).with_timezone(&Utc);
+        let runs = c.upcoming(from, 1, None);
+
+        let names: Vec<&str> = runs.iter().map(|o| o.schedule.as_str()).collect();
+        assert_eq!(names, vec!["evening", "morning"]);
+        assert_eq!(runs[1].start, Los_Angeles.ymd(2020, 7, 2).and_hms(6, 0, 0));
+    }
+
+    #[test]
+    fn upcoming_filters_by_valve() {
+        let mut c = calendar_in("America/Los_Angeles");
+        let mut beds = any_schedule(&String::from("beds"));
+        beds.valves = vec![String::from("beds valve")];
+        let mut pots = any_schedule(&String::from("pots"));
+        pots.valves = vec![String::from("pots valve")];
+        add_valves(&mut c, &["beds valve", "pots valve"]);
+        c.create_or_replace_schedule(beds).expect("create succeeds");
+        c.create_or_replace_schedule(pots).expect("create succeeds");
+
+        let runs = c.upcoming(Utc.ymd(2020, 7, 1).and_hms(0, 0, 0), 7, Some("pots valve"));
+
+        assert!(!runs.is_empty());
+        assert!(runs.iter().all(|o| o.schedule == "pots"));
+    }
+
+    #[test]
+    fn create_reports_conflicts_with_existing_schedules() {
+        let mut c = Calendar::new(Box::new(Vec::<u8>::new()));
+        let mut first = any_schedule(&String::from("first"));
+        first.repeat_period_days = 1;
+        first.valves = vec![String::from("shared valve")];
+        let mut second = first.clone();
+        second.name = String::from("second");
+        add_valves(&mut c, &["shared valve"]);
+
+        let warnings = c.create_or_replace_schedule(first).expect("create succeeds");
+        assert!(warnings.is_empty());
+
+        let warnings = c.create_or_replace_schedule(second).expect("create succeeds");
+        assert!(!warnings.is_empty());
+        assert!(warnings.iter().all(|w| w.kind == ConflictKind::ValveOverlap));
+        assert!(c.get("second").is_some());
+    }
+
+    #[test]
+    fn schedule_with_unknown_valve_is_refused() {
+        let mut c = Calendar::new(Box::new(Vec::<u8>::new()));
+        let mut schedule = any_schedule(&String::from("beds"));
+        schedule.valves = vec![String::from("missing")];
+
+        assert!(c.create_or_replace_schedule(schedule).is_err());
+        assert!(c.get("beds").is_none());
+    }
+
+    #[test]
+    fn deleting_used_valve_fails_or_cascades() {
+        let mut c = Calendar::new(Box::new(Vec::<u8>::new()));
+        add_valves(&mut c, &["beds valve"]);
+        let mut schedule = any_schedule(&String::from("beds"));
+        schedule.valves = vec![String::from("beds valve")];
+        c.create_or_replace_schedule(schedule).expect("create succeeds");
+
+        assert!(c.delete_valve("beds valve", DeletePolicy::Fail).is_err());
+        assert_eq!(c.valves().count(), 1);
+
+        c.delete_valve("beds valve", DeletePolicy::Cascade)
+            .expect("delete succeeds");
+        assert_eq!(c.valves().count(), 0);
+        assert!(c.get("beds").expect("schedule kept").valves().is_empty());
+    }
+
+    #[test]
+    fn subscribers_are_told_what_changed() {
+        let mut c = Calendar::new(Box::new(Vec::<u8>::new()));
+        let changes = c.subscribe();
+        add_valves(&mut c, &["beds valve"]);
+        let mut schedule = any_schedule(&String::from("beds"));
+        schedule.valves = vec![String::from("beds valve")];
+        c.create_or_replace_schedule(schedule).expect("create succeeds");
+        c.delete_valve("beds valve", DeletePolicy::Cascade)
+            .expect("delete succeeds");
+
+        let received: Vec<ConfigChange> = changes.try_iter().collect();
+        assert_eq!(
+            received,
+            vec![
+                ConfigChange::ValveSaved(String::from("beds valve")),
+                ConfigChange::ScheduleSaved(String::from("beds")),
+                ConfigChange::ValveDeleted(String::from("beds valve")),
+                ConfigChange::ScheduleSaved(String::from("beds")),
+            ]
+        );
+    }
+
     #[test]
     fn delete_schedule() {
         let mut c = Calendar::new(Box::new(Vec::<u8>::new()));
@@ -144,6 +884,10 @@ mod tests {
 
         assert!(c.delete_schedule(&schedule_name).is_ok());
         assert!(c.list().find(|s| schedule_name == s.name).is_none());
+        assert!(matches!(
+            c.delete_schedule(&schedule_name),
+            Err(Error::ScheduleNotFound(name)) if name == schedule_name
+        ));
     }
 
     #[test]
@@ -179,13 +923,46 @@ mod tests {
     fn any_schedule(name: &String) -> Schedule {
         Schedule {
             name: name.clone(),
-            start_offset_min: 1440,
+            start_offsets_min: vec![1440],
             duration_min: 60,
             repeat_period_days: 3,
             valves: Vec::new(),
         }
     }
 
+    fn daily_at(name: &str, start_offset_min: u64, duration_min: u64) -> Schedule {
+        Schedule {
+            name: String::from(name),
+            start_offsets_min: vec![start_offset_min],
+            duration_min,
+            repeat_period_days: 1,
+            valves: Vec::new(),
+        }
+    }
+
+    fn add_valves(calendar: &mut Calendar, names: &[&str]) {
+        for (pin, name) in names.iter().enumerate() {
+            calendar
+                .create_or_replace_valve(ValvePersist {
+                    name: String::from(*name),
+                    pin: pin as u64,
+                    max_flow_lpm: None,
+                    nominal_flow_lpm: None,
+                })
+                .expect("create valve succeeds");
+        }
+    }
+
+    fn calendar_in(timezone: &str) -> Calendar {
+        let mut c = Calendar::new(Box::new(Vec::<u8>::new()));
+        let yaml = format!(
+            "version: \"0.2\"\ntimezone: {}\nvalves: []\nschedules: []\n",
+            timezone
+        );
+        c.initialize(&mut yaml.as_bytes()).expect("initialize succeeds");
+        c
+    }
+
     fn clear_storage(calendar: &mut Calendar) {
         // Swap in an empty Box, let the original drop here
         let original = mem::replace(&mut calendar.persistent_store, Box::new(Vec::new()));
@@ -0,0 +1,353 @@
+use super::calendar::{Calendar, Occurrence};
+use super::constants::{RAIN_SKIP_MM, SIMULATED_LOGBOOK_PATH, UPCOMING_DAYS};
+use super::file_store::MemoryStore;
+use super::garden;
+use super::history::{self, Format};
+use super::lint::{self, Diagnostic, Severity};
+use super::settings::Settings;
+use super::simulation::{Report, Simulation, Weather};
+use super::clock::Clock;
+#[cfg(feature = "sqlite")]
+use super::logbook::Logbook;
+#[cfg(feature = "sqlite")]
+use super::sqlite_store::Database;
+use super::table::Table;
+use chrono::{DateTime, NaiveDate, Utc};
+use std::error::Error;
+use std::fs::{self, File};
+use std::io;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+type CliResult = Result<(), Box<dyn Error>>;
+
+/// Runs a one-shot subcommand instead of the daemon.
+///
+/// Files are looked up in the data directory of `settings` unless given on the command line, and
+/// time is told with `clock`. Returns `None` if the arguments do not name a subcommand.
+pub fn run(settings: &Settings, clock: &Arc<dyn Clock>, args: &[String]) -> Option<CliResult> {
+    let (command, rest) = args.split_first()?;
+    match command.as_str() {
+        "upcoming" => Some(upcoming(settings, clock.now(), rest)),
+        "simulate" => Some(simulate(settings, rest)),
+        "check" => Some(check(settings, rest)),
+        "export" => Some(export(settings, rest)),
+        "import" => Some(import(settings, Arc::clone(clock), rest)),
+        #[cfg(feature = "sqlite")]
+        "import-sqlite" => Some(import_sqlite(settings, Arc::clone(clock), rest)),
+        _ => None,
+    }
+}
+
+/// Prints the runs starting over the next days as a table.
+///
+/// Usage: `upcoming [--days N] [--valve NAME]`
+fn upcoming(settings: &Settings, now: DateTime<Utc>, args: &[String]) -> CliResult {
+    let mut days = UPCOMING_DAYS;
+    let mut valve: Option<String> = None;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--days" => days = next_value(&mut args, arg)?.parse()?,
+            "--valve" => valve = Some(next_value(&mut args, arg)?),
+            other => return Err(format!("unexpected argument {}", other).into()),
+        }
+    }
+
+    let calendar = load_calendar(&settings.schedule_path())?;
+    let occurrences = calendar.upcoming(now, days, valve.as_deref());
+    if occurrences.is_empty() {
+        println!("Nothing scheduled in the next {} days", days);
+    } else {
+        print!("{}", upcoming_table(&occurrences));
+    }
+    Ok(())
+}
+
+/// Replays the schedules over a range of days and prints how much each zone was watered.
+///
+/// Usage: `simulate --from YYYY-MM-DD --until YYYY-MM-DD [--schedule PATH] [--weather PATH]
+/// [--rain-skip-mm N] [--logbook PATH]`
+fn simulate(settings: &Settings, args: &[String]) -> CliResult {
+    let mut from: Option<NaiveDate> = None;
+    let mut until: Option<NaiveDate> = None;
+    let mut schedule_path = settings.schedule_path();
+    let mut weather_path: Option<String> = None;
+    let mut rain_skip_mm = RAIN_SKIP_MM;
+    let mut logbook_path = String::from(SIMULATED_LOGBOOK_PATH);
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--from" => from = Some(next_value(&mut args, arg)?.parse()?),
+            "--until" => until = Some(next_value(&mut args, arg)?.parse()?),
+            "--schedule" => schedule_path = PathBuf::from(next_value(&mut args, arg)?),
+            "--weather" => weather_path = Some(next_value(&mut args, arg)?),
+            "--rain-skip-mm" => rain_skip_mm = next_value(&mut args, arg)?.parse()?,
+            "--logbook" => logbook_path = next_value(&mut args, arg)?,
+            other => return Err(format!("unexpected argument {}", other).into()),
+        }
+    }
+    let from = from.ok_or("--from is required")?;
+    let until = until.ok_or("--until is required")?;
+    if until < from {
+        return Err("--until is before --from".into());
+    }
+
+    let weather = match weather_path {
+        Some(path) => Some(Weather::parse(&mut File::open(path)?)?),
+        None => None,
+    };
+    let simulation = Simulation {
+        from,
+        until,
+        weather,
+        rain_skip_mm,
+    };
+
+    // The logbook is rewritten on every change, only the final version is worth saving
+    let logbook = MemoryStore::new();
+    let report = simulation.run(&mut File::open(&schedule_path)?, Box::new(logbook.clone()))?;
+    fs::write(&logbook_path, logbook.contents())?;
+
+    println!("Simulated {} to {}, logbook written to {}", from, until, logbook_path);
+    println!(
+        "{} runs, {} skipped for rain, {} alarms",
+        report.runs, report.skipped, report.alarms
+    );
+    print!("{}", usage_table(&report));
+    Ok(())
+}
+
+/// Checks the schedule and logbook files without touching the valves, printing every problem.
+///
+/// The logbook is skipped if it does not exist. Fails if any errors were found.
+///
+/// Usage: `check [--schedule PATH] [--logbook PATH]`
+fn check(settings: &Settings, args: &[String]) -> CliResult {
+    let mut schedule_path = settings.schedule_path();
+    let mut logbook_path = settings.logbook_path();
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--schedule" => schedule_path = PathBuf::from(next_value(&mut args, arg)?),
+            "--logbook" => logbook_path = PathBuf::from(next_value(&mut args, arg)?),
+            other => return Err(format!("unexpected argument {}", other).into()),
+        }
+    }
+
+    let (diagnostics, calendar) = lint::check_schedules(&fs::read_to_string(&schedule_path)?);
+    let mut errors = report(&schedule_path, &diagnostics);
+    let mut warnings = diagnostics.len() - errors;
+
+    match fs::read_to_string(&logbook_path) {
+        Ok(source) => {
+            let diagnostics = lint::check_logbook(&source, calendar.as_ref());
+            let logbook_errors = report(&logbook_path, &diagnostics);
+            errors += logbook_errors;
+            warnings += diagnostics.len() - logbook_errors;
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e.into()),
+    }
+
+    println!("{} errors, {} warnings", errors, warnings);
+    if errors > 0 {
+        return Err(format!("{} errors found", errors).into());
+    }
+    Ok(())
+}
+
+/// Writes the finished runs in the logbook as CSV or JSON Lines, to stdout unless `--output` is
+/// given. The format defaults to that of the output file's extension, or JSON Lines.
+///
+/// Usage: `export [--format csv|jsonl] [--output PATH]`
+fn export(settings: &Settings, args: &[String]) -> CliResult {
+    let mut format: Option<Format> = None;
+    let mut output: Option<PathBuf> = None;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--format" => format = Some(next_value(&mut args, arg)?.parse()?),
+            "--output" => output = Some(PathBuf::from(next_value(&mut args, arg)?)),
+            other => return Err(format!("unexpected argument {}", other).into()),
+        }
+    }
+    let format = format
+        .or_else(|| output.as_deref().and_then(Format::of_path))
+        .unwrap_or(Format::JsonLines);
+
+    let logbook = garden::read_logbook(settings)?;
+    match &output {
+        Some(path) => {
+            let mut out = io::BufWriter::new(File::create(path)?);
+            let written = history::export(logbook.iter(), format, &mut out)?;
+            out.flush()?;
+            println!("exported {} records to {}", written, path.display());
+        }
+        None => {
+            let stdout = io::stdout();
+            let mut out = stdout.lock();
+            history::export(logbook.iter(), format, &mut out)?;
+            out.flush()?;
+        }
+    }
+    Ok(())
+}
+
+/// Adds the runs in a CSV or JSON Lines file to the logbook, skipping those already logged with
+/// the same schedule and start time. The format defaults to that of the file's extension.
+///
+/// The daemon must be stopped first, it would overwrite the imported runs with its own logbook.
+///
+/// Usage: `import PATH [--format csv|jsonl]`
+fn import(settings: &Settings, clock: Arc<dyn Clock>, args: &[String]) -> CliResult {
+    let mut path: Option<PathBuf> = None;
+    let mut format: Option<Format> = None;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--format" => format = Some(next_value(&mut args, arg)?.parse()?),
+            other if path.is_none() && !other.starts_with("--") => {
+                path = Some(PathBuf::from(other))
+            }
+            other => return Err(format!("unexpected argument {}", other).into()),
+        }
+    }
+    let path = path.ok_or("import requires a file to read")?;
+    let format = match format.or_else(|| Format::of_path(&path)) {
+        Some(format) => format,
+        None => return Err("unknown file type, pass --format csv or --format jsonl".into()),
+    };
+
+    let records = history::import(&mut File::open(&path)?, format)?;
+    let total = records.len();
+    let mut logbook = garden::open_logbook(clock, settings)?;
+    let imported = logbook.import_records(records)?;
+    logbook.save()?;
+
+    println!(
+        "imported {} records, {} already in the logbook",
+        imported,
+        total - imported
+    );
+    Ok(())
+}
+
+/// Copies the schedules and logbook from the YAML files into the SQLite database, replacing
+/// whatever it held. Changes still in the logbook journal are included, the files are left as
+/// they were.
+///
+/// Usage: `import-sqlite [--schedule PATH] [--logbook PATH] [--journal PATH] [--database PATH]`
+#[cfg(feature = "sqlite")]
+fn import_sqlite(settings: &Settings, clock: Arc<dyn Clock>, args: &[String]) -> CliResult {
+    let mut schedule_path = settings.schedule_path();
+    let mut logbook_path = settings.logbook_path();
+    let mut journal_path = settings.journal_path();
+    let mut database_path = settings.database_path();
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--schedule" => schedule_path = PathBuf::from(next_value(&mut args, arg)?),
+            "--logbook" => logbook_path = PathBuf::from(next_value(&mut args, arg)?),
+            "--journal" => journal_path = PathBuf::from(next_value(&mut args, arg)?),
+            "--database" => database_path = PathBuf::from(next_value(&mut args, arg)?),
+            other => return Err(format!("unexpected argument {}", other).into()),
+        }
+    }
+
+    let database = Database::open(&database_path)?;
+    let mut calendar = Calendar::new(Box::new(database.calendar_store()));
+    calendar.initialize(&mut File::open(&schedule_path)?)?;
+    calendar.save()?;
+
+    // Replay a copy of the journal, so the import never truncates the original
+    let journal = match fs::read(&journal_path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+        Err(e) => return Err(e.into()),
+    };
+    let mut logbook = Logbook::with_journal(
+        Box::new(database.logbook_store()),
+        Box::new(journal),
+        clock,
+    );
+    match File::open(&logbook_path) {
+        Ok(mut source) => logbook.initialize(&mut source)?,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            logbook.replay_journal()?;
+        }
+        Err(e) => return Err(e.into()),
+    }
+    logbook.save()?;
+
+    println!(
+        "imported {} schedules and {} records into {}",
+        calendar.list().count(),
+        logbook.iter().count(),
+        database_path.display()
+    );
+    Ok(())
+}
+
+/// Prints diagnostics prefixed with the file they are in, returning the number of errors.
+fn report(path: &Path, diagnostics: &[Diagnostic]) -> usize {
+    for diagnostic in diagnostics {
+        println!("{}:{}", path.display(), diagnostic);
+    }
+    diagnostics
+        .iter()
+        .filter(|d| d.severity == Severity::Error)
+        .count()
+}
+
+/// Renders the water used by each zone in a simulation as a table.
+pub fn usage_table(report: &Report) -> Table {
+    let mut table = Table::new(&["Zone", "Runs", "Minutes", "Liters"]);
+    for (zone, usage) in &report.zones {
+        table.push(vec![
+            zone.clone(),
+            usage.runs.to_string(),
+            usage.minutes.to_string(),
+            format!("{:.1}", usage.liters),
+        ]);
+    }
+    table
+}
+
+/// Renders runs as a table of start and end times, schedules and valves.
+pub fn upcoming_table(occurrences: &[Occurrence]) -> Table {
+    let mut table = Table::new(&["Start", "End", "Schedule", "Valves"]);
+    for occurrence in occurrences {
+        table.push(vec![
+            occurrence.start.format("%a %Y-%m-%d %H:%M").to_string(),
+            occurrence.end.format("%H:%M").to_string(),
+            occurrence.schedule.clone(),
+            occurrence.valves.join(", "),
+        ]);
+    }
+    table
+}
+
+/// Loads a calendar for reading only, changes are discarded.
+fn load_calendar(path: &Path) -> io::Result<Calendar> {
+    let mut calendar = Calendar::new(Box::new(io::sink()));
+    calendar.initialize(&mut File::open(path)?)?;
+    Ok(calendar)
+}
+
+fn next_value<'a, I>(args: &mut I, flag: &str) -> Result<String, Box<dyn Error>>
+where
+    I: Iterator<Item = &'a String>,
+{
+    match args.next() {
+        Some(value) => Ok(value.clone()),
+        None => Err(format!("{} requires a value", flag).into()),
+    }
+}
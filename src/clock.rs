@@ -0,0 +1,64 @@
+use chrono::{DateTime, Duration, Utc};
+use std::sync::Mutex;
+
+/// A source of the current time.
+pub trait Clock: Send + Sync {
+    /// Returns the current time.
+    fn now(&self) -> DateTime<Utc>;
+}
+
+/// The system's wall clock.
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+}
+
+/// A clock that only moves when told to, for simulating time.
+pub struct VirtualClock {
+    now: Mutex<DateTime<Utc>>,
+}
+
+impl VirtualClock {
+    /// Creates a new clock stopped at the given time.
+    pub fn new(start: DateTime<Utc>) -> VirtualClock {
+        VirtualClock {
+            now: Mutex::new(start),
+        }
+    }
+
+    /// Moves the clock forward.
+    pub fn advance(&self, by: Duration) {
+        let mut now = self.now.lock().expect("clock lock is not poisoned");
+        *now = *now + by;
+    }
+
+    /// Moves the clock to the given time.
+    pub fn set(&self, to: DateTime<Utc>) {
+        *self.now.lock().expect("clock lock is not poisoned") = to;
+    }
+}
+
+impl Clock for VirtualClock {
+    fn now(&self) -> DateTime<Utc> {
+        *self.now.lock().expect("clock lock is not poisoned")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn virtual_clock_only_moves_when_advanced() {
+        let start = Utc.ymd(2020, 7, 1).and_hms(0, 0, 0);
+        let clock = VirtualClock::new(start);
+
+        assert_eq!(clock.now(), start);
+        clock.advance(Duration::minutes(90));
+        assert_eq!(clock.now(), Utc.ymd(2020, 7, 1).and_hms(1, 30, 0));
+    }
+}
@@ -1,11 +1,32 @@
 use serde::{Deserialize, Serialize};
 use std::cmp::Ordering;
+use std::collections::BTreeSet;
 
-#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct ConfigPersist {
     version: String,
     valves: Vec<ValvePersist>,
 
+    /// The IANA timezone schedules are written in, the system's local time if unset
+    #[serde(default)]
+    timezone: Option<String>,
+
+    /// The valve upstream of all zones, closed first when a leak is detected
+    #[serde(default)]
+    master_valve: Option<String>,
+
+    /// The flow meter on the mains line, if one is installed
+    #[serde(default)]
+    flow_meter: Option<FlowMeterPersist>,
+
+    /// The most zone valves the water supply can run at once
+    #[serde(default)]
+    max_open_valves: Option<usize>,
+
+    /// Thresholds for raising leak alarms from flow meter readings
+    #[serde(default)]
+    leak_detection: LeakDetectionPersist,
+
     /// All configured schedules, sorted by name
     schedules: Vec<SchedulePersist>,
 }
@@ -16,6 +37,11 @@ impl ConfigPersist {
         ConfigPersist {
             version,
             valves: Vec::new(),
+            timezone: None,
+            master_valve: None,
+            flow_meter: None,
+            max_open_valves: None,
+            leak_detection: LeakDetectionPersist::default(),
             schedules: Vec::new(),
         }
     }
@@ -41,31 +67,150 @@ impl ConfigPersist {
     }
 
     /// Remove the schedule by name if it exists.
-    pub fn delete_schedule(&mut self, name: &str) {
+    pub fn delete_schedule(&mut self, name: &str) -> bool {
         let r = self.schedules.binary_search_by(|s| name.cmp(&s.name));
         match r {
             Ok(idx) => {
                 self.schedules.remove(idx);
+                true
             }
-            Err(_) => (),
+            Err(_) => false,
+        }
+    }
+
+    /// Adds a valve or replaces the one with the same name, keeping its position.
+    pub fn create_or_replace_valve(&mut self, valve: ValvePersist) {
+        match self.valves.iter_mut().find(|v| v.name == valve.name) {
+            Some(existing) => *existing = valve,
+            None => self.valves.push(valve),
+        }
+    }
+
+    /// Removes the valve by name, returning `false` if it did not exist.
+    pub fn delete_valve(&mut self, name: &str) -> bool {
+        let before = self.valves.len();
+        self.valves.retain(|v| v.name != name);
+        self.valves.len() != before
+    }
+
+    /// Stops every schedule from using the valve, and unsets it as the master valve.
+    pub fn remove_valve_references(&mut self, name: &str) {
+        for schedule in &mut self.schedules {
+            schedule.valves.retain(|v| v != name);
         }
+        if self.master_valve.as_deref() == Some(name) {
+            self.master_valve = None;
+        }
+    }
+
+    /// Returns the schedules, or `master_valve`, paired with each valve they name that is not
+    /// configured.
+    pub fn dangling_references(&self) -> BTreeSet<(String, String)> {
+        let known = |name: &String| self.valves.iter().any(|v| v.name == *name);
+        let mut dangling = BTreeSet::new();
+        for schedule in &self.schedules {
+            for valve in schedule.valves.iter().filter(|v| !known(v)) {
+                dangling.insert((schedule.name.clone(), valve.clone()));
+            }
+        }
+        if let Some(master) = self.master_valve.as_ref().filter(|v| !known(v)) {
+            dangling.insert((String::from("master_valve"), master.clone()));
+        }
+        dangling
+    }
+
+    /// Returns `true` if everything but the valves and schedules is the same.
+    pub fn same_settings(&self, other: &ConfigPersist) -> bool {
+        self.timezone == other.timezone
+            && self.master_valve == other.master_valve
+            && self.flow_meter == other.flow_meter
+            && self.max_open_valves == other.max_open_valves
+            && self.leak_detection == other.leak_detection
+    }
+
+    /// Finds a valve by name.
+    pub fn get_valve(&self, name: &str) -> Option<&ValvePersist> {
+        self.valves.iter().find(|v| v.name == name)
+    }
+
+    /// Finds a schedule by name.
+    pub fn get_schedule(&self, name: &str) -> Option<&SchedulePersist> {
+        self.schedules.iter().find(|s| s.name == name)
     }
 
     pub fn iter_schedules(&self) -> impl Iterator<Item = &SchedulePersist> {
         self.schedules.iter()
     }
+
+    pub fn iter_valves(&self) -> impl Iterator<Item = &ValvePersist> {
+        self.valves.iter()
+    }
+
+    pub fn timezone(&self) -> Option<&str> {
+        self.timezone.as_deref()
+    }
+
+    pub fn master_valve(&self) -> Option<&str> {
+        self.master_valve.as_deref()
+    }
+
+    pub fn flow_meter(&self) -> Option<&FlowMeterPersist> {
+        self.flow_meter.as_ref()
+    }
+
+    pub fn max_open_valves(&self) -> Option<usize> {
+        self.max_open_valves
+    }
+
+    pub fn leak_detection(&self) -> &LeakDetectionPersist {
+        &self.leak_detection
+    }
 }
 
-#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct ValvePersist {
     pub name: String,
     pub pin: u64,
+
+    /// Flow above this rate while the valve is open is treated as a broken pipe or head
+    #[serde(default)]
+    pub max_flow_lpm: Option<f64>,
+
+    /// The usual flow rate while the valve is open, used to estimate water use in simulations
+    #[serde(default)]
+    pub nominal_flow_lpm: Option<f64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FlowMeterPersist {
+    pub pin: u64,
+    pub pulses_per_liter: f64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LeakDetectionPersist {
+    /// Flow tolerated with every zone closed, in liters per minute
+    pub idle_max_lpm: f64,
+
+    /// Consecutive abnormal samples required before raising an alarm
+    pub confirm_samples: u32,
+}
+
+impl Default for LeakDetectionPersist {
+    fn default() -> LeakDetectionPersist {
+        LeakDetectionPersist {
+            idle_max_lpm: 0.5,
+            confirm_samples: 3,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Eq, Serialize, Deserialize)]
 pub struct SchedulePersist {
     pub name: String,
-    pub start_offset_min: u64,
+
+    /// Minutes after midnight of each start on a day the schedule runs
+    pub start_offsets_min: Vec<u64>,
     pub duration_min: u64,
     pub repeat_period_days: u64,
     pub valves: Vec<String>,
@@ -0,0 +1,233 @@
+extern crate log;
+
+use super::calendar::ConfigChange;
+use super::constants::RELOAD_DEBOUNCE_MS;
+use super::environment::{
+    Any, AsAny, Environment, EnvironmentBuilder, Lifecycle, Service, ServiceKit,
+};
+use super::garden::{Garden, GardenState};
+use super::settings::Storage;
+use log::{error, info, warn};
+use notify::{watcher, DebouncedEvent, RecursiveMode, Watcher};
+use std::fs::File;
+use std::io;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Reloads the schedules when the schedule file is edited on disk.
+pub struct ConfigWatcher {
+    /// Set when the service stops, ending the watch.
+    stopping: Arc<AtomicBool>,
+}
+
+impl ConfigWatcher {
+    /// Reloads the calendar from `path` and applies any valve changes.
+    ///
+    /// An invalid file is rejected and the current configuration kept. Runs in progress are not
+    /// interrupted, they finish as they were scheduled when they started.
+    pub fn reload(state: &mut GardenState, path: &Path) -> io::Result<Vec<ConfigChange>> {
+        let changes = state.calendar.reload(&mut File::open(path)?)?;
+        for change in &changes {
+            info!("reloaded {}: {}", path.display(), change);
+        }
+        state.apply_config_changes();
+        Ok(changes)
+    }
+
+    /// Watches the schedule file until `stopping` is set.
+    fn watch(env: Arc<Environment>, stopping: Arc<AtomicBool>) -> notify::Result<()> {
+        let path = env.settings().schedule_path();
+        let path = path.as_path();
+        let file_name = path
+            .file_name()
+            .ok_or_else(|| notify::Error::Generic(String::from("the path names no file")))?;
+        let directory = match path.parent() {
+            Some(p) if !p.as_os_str().is_empty() => p,
+            _ => Path::new("."),
+        };
+
+        // Editors often replace the file rather than write it, so watch the directory instead
+        let (sender, receiver) = mpsc::channel();
+        let mut watcher = watcher(sender, Duration::from_millis(RELOAD_DEBOUNCE_MS))?;
+        watcher.watch(directory, RecursiveMode::NonRecursive)?;
+
+        let poll = Duration::from_millis(RELOAD_DEBOUNCE_MS);
+        while !stopping.load(Ordering::Relaxed) {
+            let event = match receiver.recv_timeout(poll) {
+                Ok(event) => event,
+                Err(RecvTimeoutError::Timeout) => continue,
+                Err(RecvTimeoutError::Disconnected) => break,
+            };
+            let changed = match event {
+                DebouncedEvent::Create(p)
+                | DebouncedEvent::Write(p)
+                | DebouncedEvent::Rename(_, p) => p,
+                DebouncedEvent::Error(e, _) => {
+                    warn!("watching {} failed: {}", path.display(), e);
+                    continue;
+                }
+                _ => continue,
+            };
+            if changed.file_name() != Some(file_name) {
+                continue;
+            }
+
+            let garden = env.get::<Garden>();
+            let mut state = garden.lock();
+            if let Err(e) = ConfigWatcher::reload(&mut state, path) {
+                error!(
+                    "rejected edit to {}, keeping the last good config: {}",
+                    path.display(),
+                    e
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Service for ConfigWatcher {
+    fn create(builder: &mut EnvironmentBuilder) -> io::Result<ConfigWatcher> {
+        ServiceKit::with_builder(builder)
+            .with_dep::<Garden>()?
+            .new();
+        Ok(ConfigWatcher {
+            stopping: Arc::new(AtomicBool::new(false)),
+        })
+    }
+
+    fn start<'a>(&'a self, env: &'a Arc<Environment>) -> Lifecycle<'a> {
+        if env.settings().storage != Storage::Yaml {
+            info!("schedules are kept in the database, not watching for edits");
+            return Box::pin(async { Ok(()) });
+        }
+
+        let env_owned = Arc::clone(env);
+        let stopping = Arc::clone(&self.stopping);
+        env.spawn(ConfigWatcher::name(), async move {
+            let path = env_owned.settings().schedule_path();
+            // notify blocks its thread, so keep it off the runtime
+            tokio::task::spawn_blocking(move || ConfigWatcher::watch(env_owned, stopping))
+                .await
+                .map_err(|e| io::Error::other(e.to_string()))?
+                .map_err(|e| {
+                    io::Error::other(format!("cannot watch {} for edits: {}", path.display(), e))
+                })
+        });
+        Box::pin(async { Ok(()) })
+    }
+
+    fn stop<'a>(&'a self, _env: &'a Arc<Environment>) -> Lifecycle<'a> {
+        self.stopping.store(true, Ordering::Relaxed);
+        Box::pin(async { Ok(()) })
+    }
+
+    fn name() -> &'static str {
+        "ConfigWatcher"
+    }
+}
+
+impl AsAny for ConfigWatcher {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::calendar::Calendar;
+    use crate::clock::{Clock, VirtualClock};
+    use crate::logbook::Logbook;
+    use crate::taskmaster::Taskmaster;
+    use crate::valve::{Backend, ValveState, Valves};
+    use chrono::{Duration, TimeZone, Utc};
+    use chrono_tz::America::Los_Angeles;
+    use std::{env, fs};
+
+    const SCHEDULE: &str = r#"
+version: "0.2"
+timezone: America/Los_Angeles
+valves:
+  - name: beds
+    pin: 22
+schedules:
+  - name: beds daily
+    start_offsets_min: [420]
+    duration_min: 30
+    repeat_period_days: 1
+    valves:
+      - beds
+"#;
+
+    #[test]
+    fn edit_does_not_interrupt_running_zone() {
+        let path = env::temp_dir().join("rustic-garden-reload-running.yaml");
+        let start = Los_Angeles.ymd(2020, 7, 1).and_hms(7, 0, 0).with_timezone(&Utc);
+        let clock = Arc::new(VirtualClock::new(start));
+        let mut state = garden(&clock);
+        Taskmaster::evaluate_schedules(&mut state, clock.now()).expect("evaluate succeeds");
+
+        // Moved to the evening while the morning run is going
+        fs::write(&path, SCHEDULE.replace("[420]", "[1080]")).expect("write succeeds");
+        let changes = ConfigWatcher::reload(&mut state, &path).expect("reload succeeds");
+        assert_eq!(
+            changes,
+            vec![ConfigChange::ScheduleSaved(String::from("beds daily"))]
+        );
+
+        clock.advance(Duration::minutes(10));
+        Taskmaster::evaluate_schedules(&mut state, clock.now()).expect("evaluate succeeds");
+        assert!(is_open(&state, "beds"));
+
+        clock.advance(Duration::minutes(20));
+        Taskmaster::evaluate_schedules(&mut state, clock.now()).expect("evaluate succeeds");
+        assert!(!is_open(&state, "beds"));
+        fs::remove_file(&path).expect("cleanup succeeds");
+    }
+
+    #[test]
+    fn invalid_edit_keeps_last_good_config() {
+        let path = env::temp_dir().join("rustic-garden-reload-invalid.yaml");
+        let clock = Arc::new(VirtualClock::new(Utc.ymd(2020, 7, 1).and_hms(0, 0, 0)));
+        let mut state = garden(&clock);
+
+        fs::write(&path, SCHEDULE.replace("- beds\n", "- lawn\n")).expect("write succeeds");
+        assert!(ConfigWatcher::reload(&mut state, &path).is_err());
+
+        fs::write(&path, "schedules: [").expect("write succeeds");
+        assert!(ConfigWatcher::reload(&mut state, &path).is_err());
+
+        let schedule = state.calendar.get("beds daily").expect("schedule kept");
+        assert_eq!(schedule.valves(), &[String::from("beds")]);
+        fs::remove_file(&path).expect("cleanup succeeds");
+    }
+
+    fn is_open(state: &GardenState, valve: &str) -> bool {
+        matches!(
+            state.valves.get(valve).expect("valve exists").get_state(),
+            Ok(ValveState::Open)
+        )
+    }
+
+    fn garden(clock: &Arc<VirtualClock>) -> GardenState {
+        let clock = Arc::clone(clock) as Arc<dyn Clock>;
+        let mut calendar = Calendar::with_clock(Box::new(Vec::<u8>::new()), Arc::clone(&clock));
+        calendar
+            .initialize(&mut SCHEDULE.as_bytes())
+            .expect("initialize succeeds");
+        let logbook = Logbook::with_clock(Box::new(Vec::<u8>::new()), clock);
+
+        let mut valves = Valves::with_backend(Backend::Mock);
+        for valve in calendar.valves() {
+            valves
+                .register_new_valve(valve.name.clone(), valve.pin)
+                .expect("mock valves always register");
+        }
+
+        GardenState::new(calendar, logbook, valves)
+    }
+}
@@ -0,0 +1,167 @@
+use super::calendar::Occurrence;
+use chrono::{DateTime, FixedOffset};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+
+/// The ways two or more schedule runs can collide.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConflictKind {
+    /// The same valve is used by two runs at the same time.
+    ValveOverlap,
+
+    /// More valves are open at once than the water supply allows.
+    ConcurrencyExceeded,
+}
+
+/// A collision between schedule runs found by `analyze`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Conflict {
+    /// What kind of collision this is.
+    pub kind: ConflictKind,
+
+    /// The time when the collision starts.
+    pub start: DateTime<FixedOffset>,
+
+    /// The time when the collision ends.
+    pub end: DateTime<FixedOffset>,
+
+    /// The schedules involved, sorted by name.
+    pub schedules: Vec<String>,
+
+    /// The valves involved, sorted by name.
+    pub valves: Vec<String>,
+}
+
+/// Finds collisions between runs, which must be sorted by start time.
+///
+/// Every pair of overlapping runs sharing a valve is reported as a `ValveOverlap`. If
+/// `max_open_valves` is set, every run start that brings the number of distinct open valves over
+/// the limit is reported as `ConcurrencyExceeded`.
+pub fn analyze(occurrences: &[Occurrence], max_open_valves: Option<usize>) -> Vec<Conflict> {
+    let mut conflicts = Vec::new();
+
+    for (i, first) in occurrences.iter().enumerate() {
+        for second in &occurrences[i + 1..] {
+            // Sorted by start, nothing later can overlap `first` either
+            if second.start >= first.end {
+                break;
+            }
+
+            let shared: BTreeSet<&String> = first
+                .valves
+                .iter()
+                .filter(|valve| second.valves.contains(valve))
+                .collect();
+            if shared.is_empty() {
+                continue;
+            }
+
+            conflicts.push(Conflict {
+                kind: ConflictKind::ValveOverlap,
+                start: second.start,
+                end: first.end.min(second.end),
+                schedules: sorted_names(&[first, second], |o| &o.schedule),
+                valves: shared.into_iter().cloned().collect(),
+            });
+        }
+    }
+
+    if let Some(limit) = max_open_valves {
+        // The open valve count only rises when a run starts, so checking each start is enough
+        for occurrence in occurrences {
+            let at = occurrence.start;
+            let active: Vec<&Occurrence> = occurrences
+                .iter()
+                .filter(|o| o.start <= at && at < o.end)
+                .collect();
+            let valves: BTreeSet<&String> = active.iter().flat_map(|o| &o.valves).collect();
+            if valves.len() <= limit {
+                continue;
+            }
+
+            conflicts.push(Conflict {
+                kind: ConflictKind::ConcurrencyExceeded,
+                start: at,
+                end: active.iter().map(|o| o.end).min().expect("run is active"),
+                schedules: sorted_names(&active, |o| &o.schedule),
+                valves: valves.into_iter().cloned().collect(),
+            });
+        }
+    }
+
+    conflicts
+}
+
+fn sorted_names<F>(occurrences: &[&Occurrence], name: F) -> Vec<String>
+where
+    F: Fn(&Occurrence) -> &String,
+{
+    let names: BTreeSet<&String> = occurrences.iter().map(|o| name(o)).collect();
+    names.into_iter().cloned().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{Duration, TimeZone};
+
+    #[test]
+    fn shared_valve_at_same_time_overlaps() {
+        let runs = vec![run("a", 0, 30, &["v1", "v2"]), run("b", 15, 30, &["v2"])];
+
+        let conflicts = analyze(&runs, None);
+
+        assert_eq!(conflicts.len(), 1);
+        assert_eq!(conflicts[0].kind, ConflictKind::ValveOverlap);
+        assert_eq!(conflicts[0].valves, vec![String::from("v2")]);
+        assert_eq!(conflicts[0].start, runs[1].start);
+        assert_eq!(conflicts[0].end, runs[0].end);
+    }
+
+    #[test]
+    fn back_to_back_runs_do_not_overlap() {
+        let runs = vec![run("a", 0, 30, &["v1"]), run("b", 30, 30, &["v1"])];
+
+        assert!(analyze(&runs, None).is_empty());
+    }
+
+    #[test]
+    fn different_valves_at_same_time_do_not_overlap() {
+        let runs = vec![run("a", 0, 30, &["v1"]), run("b", 0, 30, &["v2"])];
+
+        assert!(analyze(&runs, None).is_empty());
+    }
+
+    #[test]
+    fn too_many_open_valves_exceeds_concurrency() {
+        let runs = vec![run("a", 0, 30, &["v1"]), run("b", 10, 30, &["v2"])];
+
+        let conflicts = analyze(&runs, Some(1));
+
+        assert_eq!(conflicts.len(), 1);
+        assert_eq!(conflicts[0].kind, ConflictKind::ConcurrencyExceeded);
+        assert_eq!(
+            conflicts[0].schedules,
+            vec![String::from("a"), String::from("b")]
+        );
+    }
+
+    #[test]
+    fn open_valves_within_limit_is_fine() {
+        let runs = vec![run("a", 0, 30, &["v1"]), run("b", 10, 30, &["v2"])];
+
+        assert!(analyze(&runs, Some(2)).is_empty());
+    }
+
+    fn run(schedule: &str, start_min: i64, duration_min: i64, valves: &[&str]) -> Occurrence {
+        let start = FixedOffset::west(7 * 3600).ymd(2020, 7, 1).and_hms(6, 0, 0)
+            + Duration::minutes(start_min);
+        Occurrence {
+            schedule: String::from(schedule),
+            start,
+            end: start + Duration::minutes(duration_min),
+            valves: valves.iter().map(|v| String::from(*v)).collect(),
+        }
+    }
+}
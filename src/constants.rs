@@ -3,6 +3,33 @@ pub const MINUTES_PER_HOUR: u32 = 60;
 pub const HOURS_PER_DAY: u32 = 24;
 pub const MINUTES_PER_DAY: u32 = MINUTES_PER_HOUR * HOURS_PER_DAY;
 
+// Scheduling
+pub const CONFLICT_CHECK_DAYS: u32 = 28;
+pub const UPCOMING_DAYS: u32 = 7;
+pub const LOGBOOK_TAIL: usize = 10;
+pub const TASKMASTER_PERIOD_SEC: u64 = 5;
+pub const RELOAD_DEBOUNCE_MS: u64 = 500;
+
+// Logbook retention
+pub const RETENTION_DAYS: u32 = 90;
+pub const RETENTION_RECORDS_PER_SCHEDULE: usize = 100;
+pub const JOURNAL_SNAPSHOT_EVENTS: usize = 500;
+
+// Simulation
+pub const RAIN_SKIP_MM: f64 = 5.0;
+
 // Files
-pub static SCHEDULE_PATH: &str = "./schedule.yaml";
-pub static LOGBOOK_PATH: &str = "./logbook.yaml";
+pub static DATA_DIR: &str = ".";
+pub static SCHEDULE_FILE: &str = "schedule.yaml";
+pub static LOGBOOK_FILE: &str = "logbook.yaml";
+pub static JOURNAL_FILE: &str = "logbook.journal";
+pub static ARCHIVE_DIR: &str = "archive";
+pub static DATABASE_FILE: &str = "garden.db";
+pub static SETTINGS_PATH: &str = "./rustic-garden.yaml";
+pub static SIMULATED_LOGBOOK_PATH: &str = "./simulated-logbook.yaml";
+
+// Control
+pub static CONTROL_ADDRESS: &str = "127.0.0.1:7292";
+
+// Logging
+pub static LOG_LEVEL: &str = "info";
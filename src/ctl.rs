@@ -0,0 +1,696 @@
+use super::calendar::{resolve_local, Schedule};
+use super::cli::upcoming_table;
+use super::constants::{CONTROL_ADDRESS, MINUTES_PER_HOUR};
+use super::environment::Health;
+use super::logbook::Trigger;
+use super::logbook_query::{LogEntry, Query};
+use super::protocol::{Request, Response};
+use super::statistics::Period;
+use super::table::Table;
+use chrono::{DateTime, FixedOffset, Local, NaiveDate, Offset, TimeZone, Utc};
+use chrono_tz::Tz;
+use std::io;
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpStream;
+
+pub const USAGE: &str = "\
+usage: garden-ctl [--json] [--address HOST:PORT] <command>
+
+commands:
+  schedules list
+  schedules show NAME
+  schedules add NAME --start HH:MM [--start HH:MM]... --duration MIN [--every DAYS]
+                     --valve NAME [--valve NAME]...
+  schedules rm NAME
+  valves list
+  valves open NAME
+  valves close NAME
+  run SCHEDULE
+  logbook tail [-n COUNT] [--page N] [--schedule NAME] [--valve NAME]
+               [--since TIME] [--until TIME]
+               [--outcome completed|interrupted|aborted|failed|skipped|manual]
+  report --from DATE [--until DATE] [--period day|week|month] [--csv]
+  status";
+
+/// What the user asked garden-ctl to do.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Command {
+    ListSchedules,
+    ShowSchedule(String),
+    SaveSchedule(Schedule),
+    DeleteSchedule(String),
+    ListValves,
+    OpenValve(String),
+    CloseValve(String),
+    Run(String),
+    TailLogbook(Query),
+    Report {
+        from: NaiveDate,
+        until: NaiveDate,
+        period: Period,
+        csv: bool,
+    },
+    Status,
+}
+
+/// A parsed garden-ctl command line.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Invocation {
+    pub command: Command,
+    pub json: bool,
+    pub address: String,
+}
+
+impl Invocation {
+    /// Parses the arguments after the program name.
+    ///
+    /// Dates are resolved in the garden's timezone, which `timezone` looks up for the daemon at
+    /// the given address only if the command has dates to resolve. `None` means local time.
+    pub fn parse<F>(args: &[String], timezone: F) -> Result<Invocation, String>
+    where
+        F: FnOnce(&str) -> Result<Option<Tz>, String>,
+    {
+        let mut json = false;
+        let mut address = String::from(CONTROL_ADDRESS);
+        let mut words: Vec<&str> = Vec::new();
+
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--json" => json = true,
+                "--address" => address = next_value(&mut args, arg)?,
+                _ => words.push(arg),
+            }
+        }
+
+        let daemon = address.clone();
+        let mut zone = Zone {
+            lookup: Some(Box::new(move || timezone(&daemon))),
+            tz: None,
+        };
+
+        let command = match words.as_slice() {
+            ["schedules", "list"] => Command::ListSchedules,
+            ["schedules", "show", name] => Command::ShowSchedule(String::from(*name)),
+            ["schedules", "add", name, rest @ ..] => Command::SaveSchedule(schedule(name, rest)?),
+            ["schedules", "rm", name] => Command::DeleteSchedule(String::from(*name)),
+            ["valves", "list"] => Command::ListValves,
+            ["valves", "open", name] => Command::OpenValve(String::from(*name)),
+            ["valves", "close", name] => Command::CloseValve(String::from(*name)),
+            ["run", name] => Command::Run(String::from(*name)),
+            ["logbook", "tail", rest @ ..] => {
+                Command::TailLogbook(logbook_query(rest, &mut zone)?)
+            }
+            ["report", rest @ ..] => report(rest, &mut zone)?,
+            ["status"] => Command::Status,
+            _ => return Err(String::from(USAGE)),
+        };
+
+        Ok(Invocation {
+            command,
+            json,
+            address,
+        })
+    }
+
+    /// The request to send the daemon.
+    pub fn request(&self) -> Request {
+        match &self.command {
+            Command::ListSchedules | Command::ShowSchedule(_) => Request::Schedules,
+            Command::SaveSchedule(schedule) => Request::SaveSchedule {
+                schedule: schedule.clone(),
+            },
+            Command::DeleteSchedule(name) => Request::DeleteSchedule { name: name.clone() },
+            Command::ListValves => Request::Valves,
+            Command::OpenValve(name) => Request::OpenValve { name: name.clone() },
+            Command::CloseValve(name) => Request::CloseValve { name: name.clone() },
+            Command::Run(name) => Request::RunSchedule { name: name.clone() },
+            Command::TailLogbook(query) => Request::Logbook {
+                query: query.clone(),
+            },
+            Command::Report {
+                from,
+                until,
+                period,
+                ..
+            } => Request::Report {
+                from: *from,
+                until: *until,
+                period: Some(*period),
+            },
+            Command::Status => Request::Status,
+        }
+    }
+
+    /// Formats the daemon's response for the terminal, or as JSON with `--json`.
+    ///
+    /// Returns an error message if the request failed.
+    pub fn render(&self, response: Response) -> Result<String, String> {
+        let response = match (&self.command, response) {
+            (_, Response::Error { message, .. }) => return Err(message),
+            (Command::ShowSchedule(name), Response::Schedules { schedules }) => {
+                let schedule = schedules
+                    .into_iter()
+                    .find(|schedule| schedule.name() == name)
+                    .ok_or_else(|| format!("no schedule named {}", name))?;
+                Response::Schedules {
+                    schedules: vec![schedule],
+                }
+            }
+            (Command::Report { csv: true, .. }, Response::Report { report }) => {
+                let mut out = Vec::new();
+                report.write_zones_csv(&mut out).map_err(|e| e.to_string())?;
+                return String::from_utf8(out).map_err(|e| e.to_string());
+            }
+            (_, response) => response,
+        };
+
+        if self.json {
+            return serde_json::to_string_pretty(&response).map_err(|e| e.to_string());
+        }
+        Ok(human(response))
+    }
+}
+
+/// Sends a request to the daemon at `address` and waits for its response.
+pub fn send(address: &str, request: &Request) -> io::Result<Response> {
+    let mut stream = TcpStream::connect(address)?;
+    let mut line = serde_json::to_string(request)?;
+    line.push('\n');
+    stream.write_all(line.as_bytes())?;
+
+    let mut reply = String::new();
+    BufReader::new(stream).read_line(&mut reply)?;
+    Ok(serde_json::from_str(&reply)?)
+}
+
+fn human(response: Response) -> String {
+    match response {
+        Response::Schedules { schedules } => {
+            let mut table = Table::new(&["Name", "Starts", "Duration", "Every", "Valves"]);
+            for schedule in schedules {
+                let starts: Vec<String> = schedule
+                    .start_offsets_min()
+                    .iter()
+                    .map(|offset| format_time(*offset))
+                    .collect();
+                table.push(vec![
+                    String::from(schedule.name()),
+                    starts.join(", "),
+                    format!("{} min", schedule.duration_min()),
+                    format!("{} days", schedule.repeat_period_days()),
+                    schedule.valves().join(", "),
+                ]);
+            }
+            table.to_string()
+        }
+        Response::Valves { valves } => {
+            let mut table = Table::new(&["Name", "Pin", "State"]);
+            for status in valves {
+                table.push(vec![
+                    status.valve.name,
+                    status.valve.pin.to_string(),
+                    String::from(if status.open { "open" } else { "closed" }),
+                ]);
+            }
+            table.to_string()
+        }
+        Response::Entries { entries } => {
+            let mut table = Table::new(&["Schedule", "Started", "Completed", "Outcome", "Liters"]);
+            for entry in entries {
+                match entry {
+                    LogEntry::Run(record) => {
+                        let mut name = record.name.clone();
+                        match record.trigger() {
+                            Trigger::Schedule => {}
+                            Trigger::Manual => name.push_str(" (by hand)"),
+                            trigger => name.push_str(&format!(" ({})", trigger)),
+                        }
+                        if record.dry_run {
+                            name.push_str(" (dry run)");
+                        }
+                        let details = record.error.as_ref();
+                        let outcome = match (record.outcome(), details) {
+                            (Some(outcome), Some(details)) => format!("{}: {}", outcome, details),
+                            (Some(outcome), None) => outcome.to_string(),
+                            (None, _) => String::from("running"),
+                        };
+                        table.push(vec![
+                            name,
+                            record.started.unwrap_or_default(),
+                            record.completed.unwrap_or_default(),
+                            outcome,
+                            record.liters.map_or(String::new(), |l| format!("{:.1}", l)),
+                        ]);
+                    }
+                    LogEntry::Skipped(skip) => table.push(vec![
+                        skip.name,
+                        skip.due,
+                        String::new(),
+                        format!("skipped: {}", skip.reason),
+                        String::new(),
+                    ]),
+                }
+            }
+            table.to_string()
+        }
+        Response::RunStarted { run } => format!("{} running until {}\n", run.schedule, run.end),
+        Response::ScheduleSaved { warnings } => {
+            let mut out = String::from("saved\n");
+            for conflict in warnings {
+                out.push_str(&format!(
+                    "warning: {:?} {} to {} between {} on {}\n",
+                    conflict.kind,
+                    conflict.start,
+                    conflict.end,
+                    conflict.schedules.join(", "),
+                    conflict.valves.join(", ")
+                ));
+            }
+            out
+        }
+        Response::Status {
+            open_valves,
+            running,
+            next,
+            active_alarms,
+            dry_run,
+            services,
+            ..
+        } => {
+            let mut out = String::new();
+            if dry_run {
+                out.push_str("DRY RUN: valves are not driven\n");
+            }
+            for service in services.iter().filter(|s| s.health != Health::Running) {
+                out.push_str(&format!("Service {} is {}\n", service.name, service.health));
+            }
+            if open_valves.is_empty() {
+                out.push_str("Open valves: none\n");
+            } else {
+                out.push_str(&format!("Open valves: {}\n", open_valves.join(", ")));
+            }
+            out.push_str(&format!("Active alarms: {}\n", active_alarms));
+            if !running.is_empty() {
+                out.push_str(&format!("Running:\n{}", upcoming_table(&running)));
+            }
+            match next {
+                Some(run) => out.push_str(&format!("Next:\n{}", upcoming_table(&[run]))),
+                None => out.push_str("Next: nothing in the coming week\n"),
+            }
+            out
+        }
+        Response::Report { report } => {
+            let mut zones = Table::new(&["Period", "Zone", "Runs", "Minutes", "Liters"]);
+            for total in report.zones {
+                zones.push(vec![
+                    total.period.to_string(),
+                    total.zone,
+                    total.runs.to_string(),
+                    format!("{:.0}", total.minutes),
+                    total.liters.map_or(String::new(), |l| format!("{:.1}", l)),
+                ]);
+            }
+            let mut schedules =
+                Table::new(&["Schedule", "Runs", "Average min", "Skipped", "Missed"]);
+            for (name, summary) in report.schedules {
+                schedules.push(vec![
+                    name,
+                    summary.runs.to_string(),
+                    format!("{:.1}", summary.average_minutes),
+                    summary.skipped.to_string(),
+                    summary.missed.to_string(),
+                ]);
+            }
+            let mut out = format!("{}\n{}", zones, schedules);
+            if !report.skipped_by_reason.is_empty() {
+                let mut reasons = Table::new(&["Skipped because", "Runs"]);
+                for (reason, count) in report.skipped_by_reason {
+                    reasons.push(vec![reason, count.to_string()]);
+                }
+                out.push_str(&format!("\n{}", reasons));
+            }
+            out
+        }
+        Response::Ok => String::from("ok\n"),
+        other => format!("{:?}\n", other),
+    }
+}
+
+/// Builds a schedule from `schedules add` options.
+fn schedule(name: &str, args: &[&str]) -> Result<Schedule, String> {
+    let mut starts = Vec::new();
+    let mut duration: Option<u64> = None;
+    let mut every = 1;
+    let mut valves = Vec::new();
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .map(|v| String::from(*v))
+                .ok_or_else(|| format!("{} requires a value", arg))
+        };
+        match *arg {
+            "--start" => starts.push(parse_time(&value()?)?),
+            "--duration" => {
+                let v = value()?;
+                duration = Some(v.parse().map_err(|_| format!("invalid duration {}", v))?);
+            }
+            "--every" => {
+                let v = value()?;
+                every = v.parse().map_err(|_| format!("invalid period {}", v))?;
+            }
+            "--valve" => valves.push(value()?),
+            other => return Err(format!("unexpected argument {}", other)),
+        }
+    }
+
+    if starts.is_empty() {
+        return Err(String::from("at least one --start is required"));
+    }
+    let duration = duration.ok_or("--duration is required")?;
+    Ok(Schedule::new(String::from(name), starts, duration, every, valves))
+}
+
+/// Builds a logbook query from `logbook tail` options.
+fn logbook_query(args: &[&str], zone: &mut Zone) -> Result<Query, String> {
+    let mut query = Query::new();
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .map(|v| String::from(*v))
+                .ok_or_else(|| format!("{} requires a value", arg))
+        };
+        match *arg {
+            "-n" => {
+                let v = value()?;
+                query = query.limit(v.parse().map_err(|_| format!("invalid count {}", v))?);
+            }
+            "--page" => {
+                let v = value()?;
+                query = query.page(v.parse().map_err(|_| format!("invalid page {}", v))?);
+            }
+            "--schedule" => query = query.schedule(&value()?),
+            "--valve" => query = query.valve(&value()?),
+            "--since" => query = query.since(parse_timestamp(&value()?, zone)?),
+            "--until" => query = query.until(parse_timestamp(&value()?, zone)?),
+            "--outcome" => query = query.outcome(value()?.parse()?),
+            other => return Err(format!("unexpected argument {}", other)),
+        }
+    }
+    Ok(query)
+}
+
+/// Builds a report command from `report` options.
+fn report(args: &[&str], zone: &mut Zone) -> Result<Command, String> {
+    let mut from = None;
+    let mut until = None;
+    let mut period = Period::Day;
+    let mut csv = false;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .map(|v| String::from(*v))
+                .ok_or_else(|| format!("{} requires a value", arg))
+        };
+        match *arg {
+            "--from" => from = Some(parse_date(&value()?)?),
+            "--until" => until = Some(parse_date(&value()?)?),
+            "--period" => period = value()?.parse()?,
+            "--csv" => csv = true,
+            other => return Err(format!("unexpected argument {}", other)),
+        }
+    }
+
+    let from = from.ok_or("--from is required")?;
+    let until = match until {
+        Some(until) => until,
+        None => zone.today(Utc::now())?,
+    };
+    if until < from {
+        return Err(String::from("--until is before --from"));
+    }
+    Ok(Command::Report {
+        from,
+        until,
+        period,
+        csv,
+    })
+}
+
+fn parse_date(date: &str) -> Result<NaiveDate, String> {
+    NaiveDate::parse_from_str(date, "%Y-%m-%d")
+        .map_err(|_| format!("invalid date {}, expected YYYY-MM-DD", date))
+}
+
+/// Parses an RFC 3339 time, or a date `YYYY-MM-DD` meaning midnight in the garden's timezone.
+fn parse_timestamp(time: &str, zone: &mut Zone) -> Result<DateTime<FixedOffset>, String> {
+    if let Ok(time) = DateTime::parse_from_rfc3339(time) {
+        return Ok(time);
+    }
+    let date = parse_date(time)
+        .map_err(|_| format!("invalid time {}, expected YYYY-MM-DD or RFC 3339", time))?;
+    zone.start_of_day(date)
+}
+
+type TimezoneLookup<'a> = Box<dyn FnOnce() -> Result<Option<Tz>, String> + 'a>;
+
+/// The garden's timezone, looked up the first time a date needs it.
+struct Zone<'a> {
+    lookup: Option<TimezoneLookup<'a>>,
+    tz: Option<Option<Tz>>,
+}
+
+impl Zone<'_> {
+    fn tz(&mut self) -> Result<Option<Tz>, String> {
+        if let Some(tz) = self.tz {
+            return Ok(tz);
+        }
+        let lookup = self.lookup.take().expect("lookup runs once");
+        let tz = lookup()?;
+        self.tz = Some(tz);
+        Ok(tz)
+    }
+
+    /// The garden's date at `now`.
+    fn today(&mut self, now: DateTime<Utc>) -> Result<NaiveDate, String> {
+        Ok(match self.tz()? {
+            Some(tz) => now.with_timezone(&tz).date().naive_local(),
+            None => now.with_timezone(&Local).date().naive_local(),
+        })
+    }
+
+    /// The instant `date` begins in the garden, with the garden's UTC offset at the time.
+    fn start_of_day(&mut self, date: NaiveDate) -> Result<DateTime<FixedOffset>, String> {
+        let midnight = date.and_hms(0, 0, 0);
+        Ok(match self.tz()? {
+            Some(tz) => with_offset(resolve_local(&tz, midnight).with_timezone(&tz)),
+            None => with_offset(resolve_local(&Local, midnight).with_timezone(&Local)),
+        })
+    }
+}
+
+fn with_offset<Z: TimeZone>(time: DateTime<Z>) -> DateTime<FixedOffset> {
+    let offset = time.offset().fix();
+    time.with_timezone(&offset)
+}
+
+/// Asks the daemon at `address` for the garden's timezone, `None` if it runs on local time.
+pub fn garden_timezone(address: &str) -> Result<Option<Tz>, String> {
+    let response = send(address, &Request::Status)
+        .map_err(|e| format!("cannot reach the daemon at {}: {}", address, e))?;
+    match response {
+        Response::Status { timezone, .. } => timezone
+            .map(|name| name.parse().map_err(|e| format!("unknown timezone {}: {}", name, e)))
+            .transpose(),
+        Response::Error { message, .. } => Err(message),
+        other => Err(format!("unexpected response {:?}", other)),
+    }
+}
+
+/// Parses a wall clock time `HH:MM` into minutes after midnight.
+fn parse_time(time: &str) -> Result<u64, String> {
+    let invalid = || format!("invalid time {}, expected HH:MM", time);
+    let (hours, minutes) = time.split_once(':').ok_or_else(invalid)?;
+    let hours: u64 = hours.parse().map_err(|_| invalid())?;
+    let minutes: u64 = minutes.parse().map_err(|_| invalid())?;
+    if hours >= 24 || minutes >= MINUTES_PER_HOUR as u64 {
+        return Err(invalid());
+    }
+    Ok(hours * MINUTES_PER_HOUR as u64 + minutes)
+}
+
+fn format_time(offset_min: u64) -> String {
+    let per_hour = MINUTES_PER_HOUR as u64;
+    format!("{:02}:{:02}", offset_min / per_hour, offset_min % per_hour)
+}
+
+fn next_value<'a, I>(args: &mut I, flag: &str) -> Result<String, String>
+where
+    I: Iterator<Item = &'a String>,
+{
+    args.next()
+        .cloned()
+        .ok_or_else(|| format!("{} requires a value", flag))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::logbook_query::Outcome;
+    use crate::statistics::{WateringReport, ZoneTotal};
+    use chrono_tz::America::Los_Angeles;
+
+    #[test]
+    fn parses_schedule_add() {
+        let invocation = parse(&[
+            "--json", "schedules", "add", "beds", "--start", "06:30", "--start", "18:00",
+            "--duration", "20", "--every", "2", "--valve", "beds valve",
+        ])
+        .expect("parse succeeds");
+
+        assert!(invocation.json);
+        assert_eq!(
+            invocation.command,
+            Command::SaveSchedule(Schedule::new(
+                String::from("beds"),
+                vec![390, 1080],
+                20,
+                2,
+                vec![String::from("beds valve")]
+            ))
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_command() {
+        assert!(parse(&["water", "everything"]).is_err());
+        assert!(parse(&["schedules", "add", "beds", "--start", "25:00", "--duration", "5"]).is_err());
+    }
+
+    #[test]
+    fn show_picks_the_named_schedule() {
+        let invocation = parse(&["schedules", "show", "pots"]).expect("parse succeeds");
+        let schedules = vec![
+            Schedule::new(String::from("beds"), vec![60], 5, 1, vec![]),
+            Schedule::new(String::from("pots"), vec![420], 15, 1, vec![]),
+        ];
+
+        let out = invocation
+            .render(Response::Schedules { schedules })
+            .expect("render succeeds");
+
+        assert!(out.contains("pots"));
+        assert!(out.contains("07:00"));
+        assert!(!out.contains("beds"));
+    }
+
+    #[test]
+    fn parses_logbook_filters() {
+        let invocation = parse(&[
+            "logbook", "tail", "-n", "5", "--page", "2", "--valve", "beds valve", "--since",
+            "2020-06-01T00:00:00+02:00", "--outcome", "skipped",
+        ])
+        .expect("parse succeeds");
+
+        assert_eq!(
+            invocation.command,
+            Command::TailLogbook(
+                Query::new()
+                    .limit(5)
+                    .page(2)
+                    .valve("beds valve")
+                    .since(
+                        DateTime::parse_from_rfc3339("2020-06-01T00:00:00+02:00")
+                            .expect("valid time"),
+                    )
+                    .outcome(Outcome::Skipped)
+            )
+        );
+        assert!(parse(&["logbook", "tail", "--outcome", "flooded"]).is_err());
+        assert!(parse(&["logbook", "tail", "--since", "June"]).is_err());
+    }
+
+    #[test]
+    fn dates_are_midnight_in_the_garden() {
+        let invocation =
+            parse(&["logbook", "tail", "--since", "2020-06-01"]).expect("parse succeeds");
+
+        assert_eq!(
+            invocation.command,
+            Command::TailLogbook(Query::new().since(
+                DateTime::parse_from_rfc3339("2020-06-01T00:00:00-07:00").expect("valid time")
+            ))
+        );
+    }
+
+    #[test]
+    fn timezone_is_only_looked_up_for_dates() {
+        let unreachable = |_: &str| Err(String::from("unreachable"));
+        let args = vec![String::from("status")];
+        let invocation = Invocation::parse(&args, unreachable).expect("parse succeeds");
+        assert_eq!(invocation.command, Command::Status);
+
+        let args: Vec<String> = ["report", "--from", "2020-06-01"]
+            .iter()
+            .map(|arg| String::from(*arg))
+            .collect();
+        assert_eq!(
+            Invocation::parse(&args, unreachable),
+            Err(String::from("unreachable"))
+        );
+    }
+
+    #[test]
+    fn report_renders_csv() {
+        let invocation = parse(&["report", "--from", "2020-06-01", "--until", "2020-06-30", "--csv"])
+            .expect("parse succeeds");
+        let report = WateringReport {
+            zones: vec![ZoneTotal {
+                zone: String::from("beds valve"),
+                period: NaiveDate::from_ymd(2020, 6, 1),
+                runs: 3,
+                minutes: 90.0,
+                liters: Some(240.0),
+            }],
+            ..WateringReport::default()
+        };
+
+        assert_eq!(
+            invocation.request(),
+            Request::Report {
+                from: NaiveDate::from_ymd(2020, 6, 1),
+                until: NaiveDate::from_ymd(2020, 6, 30),
+                period: Some(Period::Day),
+            }
+        );
+        assert_eq!(
+            invocation.render(Response::Report { report }),
+            Ok(String::from(
+                "period,zone,runs,minutes,liters\n2020-06-01,beds valve,3,90.0,240.0\n"
+            ))
+        );
+        assert!(parse(&["report", "--until", "2020-06-30"]).is_err());
+    }
+
+    #[test]
+    fn error_response_is_an_error() {
+        let invocation = parse(&["status"]).expect("parse succeeds");
+        let response = Response::Error {
+            message: String::from("any message"),
+            code: None,
+        };
+
+        assert_eq!(invocation.render(response), Err(String::from("any message")));
+    }
+
+    fn parse(args: &[&str]) -> Result<Invocation, String> {
+        let args: Vec<String> = args.iter().map(|arg| String::from(*arg)).collect();
+        Invocation::parse(&args, |_| Ok(Some(Los_Angeles)))
+    }
+}
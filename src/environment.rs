@@ -1,125 +1,466 @@
+extern crate log;
+
+use super::clock::{Clock, SystemClock};
+use super::settings::Settings;
+use log::{error, info};
+use serde::{Deserialize, Serialize};
 pub use std::any::Any;
+use std::any::TypeId;
 use std::collections::HashMap;
-use std::ptr;
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::mem;
+use std::pin::Pin;
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::{Arc, Weak};
+use std::sync::{Arc, Mutex};
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+
+type ServiceMap = HashMap<TypeId, Box<dyn Service>>;
 
-type ServiceAnonymous = dyn AsAny + Send + Sync;
-type ServiceMap = HashMap<&'static str, Box<ServiceAnonymous>>;
+/// What a service's `start` and `stop` return, completing once the service is ready or stopped.
+pub type Lifecycle<'a> = Pin<Box<dyn Future<Output = io::Result<()>> + Send + 'a>>;
 
 /// An environment containing various services.
+///
+/// Services are registered with an `EnvironmentBuilder`, which freezes them into an
+/// `Arc<Environment>` that can be shared with any thread or task. Nothing is registered or
+/// removed afterwards, so looking up a service only needs a shared reference.
 pub struct Environment {
     services: ServiceMap,
-    bootstrap_complete: AtomicBool,
+
+    /// Every service, each after the services it depends on.
+    supervisors: Vec<(TypeId, Arc<Supervisor>)>,
+
+    clock: Arc<dyn Clock>,
+    settings: Settings,
 }
 
 impl Environment {
-    /// Create a new, empty environment.
-    pub fn new() -> Environment {
-        Environment {
-            services: HashMap::new(),
-            bootstrap_complete: AtomicBool::new(false),
+    /// Starts every service, each once the services it depends on are ready.
+    ///
+    /// Stops at the first service that fails to start, leaving the rest unstarted. The services
+    /// already started should then be stopped with `stop`.
+    pub async fn start(env: &Arc<Environment>) -> io::Result<()> {
+        for (id, supervisor) in &env.supervisors {
+            info!("starting {}", supervisor.name);
+            supervisor.set_health(Health::Starting);
+            if let Err(e) = env.services[id].start(env).await {
+                supervisor.fail(e.to_string());
+                return Err(io::Error::new(
+                    e.kind(),
+                    format!("{} failed to start: {}", supervisor.name, e),
+                ));
+            }
+            // A task may already have failed
+            if supervisor.health() == Health::Starting {
+                supervisor.set_health(Health::Running);
+            }
         }
+        Ok(())
     }
 
-    pub fn bootstrap() -> (Arc<Environment>, *mut Environment) {
-        let env_owned = Arc::new(Environment::new());
-        let env_ptr: *mut Environment = Arc::into_raw(env_owned) as *mut Environment;
-        assert!(env_ptr != ptr::null_mut());
-        let env_owned = unsafe { Arc::from_raw(env_ptr) };
-        return (env_owned, env_ptr);
-    }
+    /// Stops every started service in the reverse of the order they were started in.
+    ///
+    /// Each service's `stop` hook runs, then its tasks are cancelled and waited for.
+    pub async fn stop(env: &Arc<Environment>) {
+        for (id, supervisor) in env.supervisors.iter().rev() {
+            if supervisor.health() == Health::NotStarted {
+                continue;
+            }
+            info!("stopping {}", supervisor.name);
+            supervisor.stop_requested.store(true, Ordering::Relaxed);
+            if let Err(e) = env.services[id].stop(env).await {
+                error!("stopping {} failed: {}", supervisor.name, e);
+            }
 
-    pub fn finish_bootstrap(&mut self) {
-        let already_finished =
-            self.bootstrap_complete
-                .compare_and_swap(false, true, Ordering::Relaxed);
-        if already_finished {
-            panic!("tried to finish bootstrap multiple times");
+            // Receivers outlive the tasks, so broadcasting cannot fail
+            let _ = supervisor.shutdown.broadcast(true);
+            let tasks = mem::take(&mut *supervisor.lock_tasks());
+            for task in tasks {
+                let _ = task.await;
+            }
+            if let Health::Failed { .. } = supervisor.health() {
+                continue;
+            }
+            supervisor.set_health(Health::Stopped);
         }
     }
 
-    /// Registers a new service with this environment.
-    pub fn register<T>(&mut self, env_owned: Arc<Environment>)
+    /// Runs a background task of the service named `service`, cancelling it when the service
+    /// stops.
+    ///
+    /// A task that fails or panics marks the service failed, so it shows in `health`. So does a
+    /// task that ends before the service is asked to stop, one that ends after marks it stopped.
+    pub fn spawn<F>(&self, service: &'static str, task: F)
     where
-        T: Service + 'static,
+        F: Future<Output = io::Result<()>> + Send + 'static,
     {
-        assert!(
-            !self.bootstrap_complete.load(Ordering::Relaxed),
-            "tried to register {} after bootstrap completed",
-            T::name()
-        );
+        let supervisor = match self.supervisors.iter().find(|(_, s)| s.name == service) {
+            Some((_, supervisor)) => Arc::clone(supervisor),
+            None => panic!("{} is not registered", service),
+        };
+
+        let mut shutdown = supervisor.shutdown_receiver.clone();
+        let task = tokio::spawn(async move {
+            tokio::select! {
+                result = task => result,
+                _ = stopping(&mut shutdown) => Ok(()),
+            }
+        });
+        let monitor = Arc::clone(&supervisor);
+        let handle = tokio::spawn(async move {
+            let failure = match task.await {
+                Ok(Ok(())) if monitor.stop_requested.load(Ordering::Relaxed) => {
+                    monitor.set_health(Health::Stopped);
+                    return;
+                }
+                Ok(Ok(())) => String::from("exited before it was stopped"),
+                Ok(Err(e)) => e.to_string(),
+                Err(e) if e.is_panic() => panic_message(e.into_panic()),
+                Err(_) => String::from("cancelled"),
+            };
+            monitor.fail(failure);
+        });
+        supervisor.lock_tasks().push(handle);
+    }
+
+    /// How each service is doing, in the order they start.
+    pub fn health(&self) -> Vec<ServiceHealth> {
+        self.supervisors
+            .iter()
+            .map(|(_, supervisor)| ServiceHealth {
+                name: String::from(supervisor.name),
+                health: supervisor.health(),
+            })
+            .collect()
+    }
+
+    /// The clock services should use to tell time.
+    pub fn clock(&self) -> Arc<dyn Clock> {
+        Arc::clone(&self.clock)
+    }
 
-        let new_service = T::start(env_owned, self);
+    /// How the daemon is set up on this machine.
+    pub fn settings(&self) -> &Settings {
+        &self.settings
+    }
 
-        self.services.insert(T::name(), Box::new(new_service));
+    /// Gets a registered service, or `None` if it was never registered.
+    pub fn try_get<T>(&self) -> Option<&T>
+    where
+        T: Service + 'static,
+    {
+        self.services
+            .get(&TypeId::of::<T>())
+            .and_then(|service| service.as_any().downcast_ref::<T>())
     }
 
+    /// Gets a registered service.
+    ///
+    /// Services only get what they declared as dependencies in their `ServiceKit`, which are
+    /// always registered, so a missing service is a bug.
     pub fn get<T>(&self) -> &T
     where
         T: Service + 'static,
     {
-        assert!(
-            self.bootstrap_complete.load(Ordering::Relaxed),
-            "tried to get {} during bootstrap",
-            T::name()
-        );
+        match self.try_get::<T>() {
+            Some(service) => service,
+            None => panic!("{} is not registered", T::name()),
+        }
+    }
+}
+
+/// How a service is doing.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum Health {
+    /// The service has not been started.
+    NotStarted,
+
+    /// The service is starting and not ready yet.
+    Starting,
+
+    /// The service is ready and none of its tasks failed.
+    Running,
+
+    /// The service was stopped.
+    Stopped,
+
+    /// The service failed to start, or one of its tasks failed or panicked.
+    Failed { error: String },
+}
 
-        let res: &T = Self::downcast::<T>(self.services.get(T::name()).expect("service exists"));
-        return res;
+impl fmt::Display for Health {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Health::NotStarted => f.write_str("not started"),
+            Health::Starting => f.write_str("starting"),
+            Health::Running => f.write_str("running"),
+            Health::Stopped => f.write_str("stopped"),
+            Health::Failed { error } => write!(f, "failed: {}", error),
+        }
     }
+}
+
+/// The health of a service by name.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ServiceHealth {
+    pub name: String,
+
+    #[serde(flatten)]
+    pub health: Health,
+}
+
+/// Tracks the health and tasks of one service.
+struct Supervisor {
+    name: &'static str,
+    health: Mutex<Health>,
+    tasks: Mutex<Vec<JoinHandle<()>>>,
 
-    fn downcast<T>(s: &Box<ServiceAnonymous>) -> &T
+    /// Set once `Environment::stop` reaches the service, before its `stop` hook runs.
+    stop_requested: AtomicBool,
+    shutdown: watch::Sender<bool>,
+    shutdown_receiver: watch::Receiver<bool>,
+}
+
+impl Supervisor {
+    fn new(name: &'static str) -> Supervisor {
+        let (shutdown, shutdown_receiver) = watch::channel(false);
+        Supervisor {
+            name,
+            health: Mutex::new(Health::NotStarted),
+            tasks: Mutex::new(Vec::new()),
+            stop_requested: AtomicBool::new(false),
+            shutdown,
+            shutdown_receiver,
+        }
+    }
+
+    fn health(&self) -> Health {
+        self.health
+            .lock()
+            .expect("service health lock is not poisoned")
+            .clone()
+    }
+
+    fn set_health(&self, health: Health) {
+        *self
+            .health
+            .lock()
+            .expect("service health lock is not poisoned") = health;
+    }
+
+    fn fail(&self, error: String) {
+        error!("{} failed: {}", self.name, error);
+        self.set_health(Health::Failed { error });
+    }
+
+    fn lock_tasks(&self) -> std::sync::MutexGuard<'_, Vec<JoinHandle<()>>> {
+        self.tasks
+            .lock()
+            .expect("service tasks lock is not poisoned")
+    }
+}
+
+/// Completes once `shutdown` says to stop, or its sender is gone.
+async fn stopping(shutdown: &mut watch::Receiver<bool>) {
+    while let Some(stop) = shutdown.recv().await {
+        if stop {
+            return;
+        }
+    }
+}
+
+fn panic_message(panic: Box<dyn Any + Send>) -> String {
+    match panic.downcast::<String>() {
+        Ok(message) => format!("panicked: {}", message),
+        Err(panic) => match panic.downcast_ref::<&str>() {
+            Some(message) => format!("panicked: {}", message),
+            None => String::from("panicked"),
+        },
+    }
+}
+
+/// Registers services, then freezes them into an `Environment`.
+pub struct EnvironmentBuilder {
+    services: ServiceMap,
+    registered: Vec<(TypeId, &'static str)>,
+    deps: HashMap<&'static str, Vec<&'static str>>,
+    creating: Vec<&'static str>,
+    clock: Arc<dyn Clock>,
+    settings: Settings,
+}
+
+impl EnvironmentBuilder {
+    /// Create a new, empty builder.
+    pub fn new() -> EnvironmentBuilder {
+        EnvironmentBuilder::with_clock(Arc::new(SystemClock))
+    }
+
+    /// Create a new, empty builder whose services tell time with the given clock.
+    pub fn with_clock(clock: Arc<dyn Clock>) -> EnvironmentBuilder {
+        EnvironmentBuilder::with_settings(clock, Settings::default())
+    }
+
+    /// Create a new, empty builder whose services are set up with the given settings.
+    pub fn with_settings(clock: Arc<dyn Clock>, settings: Settings) -> EnvironmentBuilder {
+        EnvironmentBuilder {
+            services: HashMap::new(),
+            registered: Vec::new(),
+            deps: HashMap::new(),
+            creating: Vec::new(),
+            clock,
+            settings,
+        }
+    }
+
+    /// Registers a new service, and before it the services it depends on.
+    ///
+    /// Services shared by several dependents are only created the first time they are registered.
+    /// Fails if the service or one of its dependencies cannot be created.
+    pub fn register<T>(&mut self) -> io::Result<&mut EnvironmentBuilder>
     where
         T: Service + 'static,
     {
-        s.as_any().downcast_ref::<T>().expect("right downcast")
+        let id = TypeId::of::<T>();
+        if self.services.contains_key(&id) {
+            return Ok(self);
+        }
+        assert!(
+            !self.creating.contains(&T::name()),
+            "{} depends on itself through {:?}",
+            T::name(),
+            self.creating
+        );
+
+        self.creating.push(T::name());
+        let new_service = T::create(self);
+        self.creating.pop();
+
+        self.services.insert(id, Box::new(new_service?));
+        self.registered.push((id, T::name()));
+        Ok(self)
+    }
+
+    /// The clock services should use to tell time.
+    pub fn clock(&self) -> Arc<dyn Clock> {
+        Arc::clone(&self.clock)
+    }
+
+    /// How the daemon is set up on this machine.
+    pub fn settings(&self) -> &Settings {
+        &self.settings
+    }
+
+    /// Freezes the registered services into an environment, without starting them.
+    ///
+    /// Services are ordered by the dependencies in their `ServiceKit`, otherwise in the order
+    /// they were registered.
+    pub fn build(self) -> Arc<Environment> {
+        let mut order = Vec::new();
+        for (_, name) in &self.registered {
+            self.visit(name, &mut order);
+        }
+        let supervisors = order
+            .into_iter()
+            .filter_map(|name| self.registered.iter().find(|(_, n)| *n == name))
+            .map(|(id, name)| (*id, Arc::new(Supervisor::new(name))))
+            .collect();
+
+        Arc::new(Environment {
+            services: self.services,
+            supervisors,
+            clock: self.clock,
+            settings: self.settings,
+        })
+    }
+
+    /// Appends `name` to `order` after its dependencies, unless it is already there.
+    fn visit(&self, name: &'static str, order: &mut Vec<&'static str>) {
+        if order.contains(&name) {
+            return;
+        }
+        for dep in self.deps.get(name).into_iter().flatten() {
+            self.visit(dep, order);
+        }
+        order.push(name);
+    }
+}
+
+impl Default for EnvironmentBuilder {
+    fn default() -> EnvironmentBuilder {
+        EnvironmentBuilder::new()
     }
 }
 
+/// The services a service depends on.
 pub struct ServiceKit {
-    env: Weak<Environment>,
     deps: Vec<&'static str>,
 }
 
 impl ServiceKit {
-    pub fn with_env<'a>(env_owned: Arc<Environment>, env: &'a mut Environment) -> ServiceKitProto<'a> {
+    pub fn with_builder(builder: &mut EnvironmentBuilder) -> ServiceKitProto<'_> {
         ServiceKitProto {
-            env_owned,
-            env,
+            builder,
             deps: Vec::<&'static str>::new(),
         }
     }
-}
 
-unsafe impl Send for ServiceKit {}
-unsafe impl Sync for ServiceKit {}
+    /// The names of the services depended on.
+    pub fn deps(&self) -> &[&'static str] {
+        &self.deps
+    }
+}
 
 pub struct ServiceKitProto<'a> {
-    env_owned: Arc<Environment>,
-    env: &'a mut Environment,
+    builder: &'a mut EnvironmentBuilder,
     deps: Vec<&'static str>,
 }
 
 impl<'a> ServiceKitProto<'a> {
-    pub fn with_dep<T: Service + 'static>(mut self) -> ServiceKitProto<'a> {
-        self.env.register::<T>(Arc::clone(&self.env_owned));
+    pub fn with_dep<T: Service + 'static>(mut self) -> io::Result<ServiceKitProto<'a>> {
+        self.builder.register::<T>()?;
         self.deps.push(T::name());
-        self
+        Ok(self)
     }
 
+    /// Finishes the kit, recording the dependencies of the service being created so it starts
+    /// after them.
     pub fn new(self) -> ServiceKit {
-        // Bootstrap all services requested in here? Or should the environment do it?
-        ServiceKit {
-            env: Arc::downgrade(&self.env_owned),
-            deps: self.deps,
+        if let Some(name) = self.builder.creating.last() {
+            self.builder
+                .deps
+                .entry(name)
+                .or_default()
+                .extend(self.deps.iter().copied());
         }
+        ServiceKit { deps: self.deps }
     }
 }
 
 pub trait Service: AsAny + Send + Sync {
-    fn name() -> &'static str;
-    fn start(env_owned: Arc<Environment>, env: &mut Environment) -> Self;
+    fn name() -> &'static str
+    where
+        Self: Sized;
+
+    /// Creates the service, registering the services it depends on with `builder`.
+    fn create(builder: &mut EnvironmentBuilder) -> io::Result<Self>
+    where
+        Self: Sized;
+
+    /// Starts any background work with `Environment::spawn`, completing once the service is
+    /// ready. By default there is nothing to start.
+    fn start<'a>(&'a self, _env: &'a Arc<Environment>) -> Lifecycle<'a> {
+        Box::pin(async { Ok(()) })
+    }
+
+    /// Prepares for shutdown, before the service's tasks are cancelled. By default there is
+    /// nothing to do.
+    fn stop<'a>(&'a self, _env: &'a Arc<Environment>) -> Lifecycle<'a> {
+        Box::pin(async { Ok(()) })
+    }
 }
 
 pub trait AsAny {
@@ -127,6 +468,7 @@ pub trait AsAny {
 }
 
 // TODO I assume this won't work for generic types, but a decent first pass
+#[allow(unused_macros)]
 macro_rules! make_service {
     ( $struct_name:ident ) => {
         impl AsAny for $struct_name {
@@ -140,13 +482,18 @@ macro_rules! make_service {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::clock::VirtualClock;
+    use chrono::{Duration, TimeZone, Utc};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+    use tokio::time;
 
     struct Dependency {}
     make_service!(Dependency);
 
     impl Service for Dependency {
-        fn start(env_owned: Arc<Environment>, env: &mut Environment) -> Dependency {
-            Dependency {}
+        fn create(_builder: &mut EnvironmentBuilder) -> io::Result<Dependency> {
+            Ok(Dependency {})
         }
 
         fn name() -> &'static str {
@@ -160,13 +507,13 @@ mod tests {
     }
 
     impl Service for TestService {
-        fn start(env_owned: Arc<Environment>, env: &mut Environment) -> TestService {
-            TestService {
-                kit: ServiceKit::with_env(env_owned, env)
-                    .with_dep::<Dependency>()
+        fn create(builder: &mut EnvironmentBuilder) -> io::Result<TestService> {
+            Ok(TestService {
+                kit: ServiceKit::with_builder(builder)
+                    .with_dep::<Dependency>()?
                     .new(),
                 field: 0,
-            }
+            })
         }
 
         fn name() -> &'static str {
@@ -181,7 +528,8 @@ mod tests {
     fn still_has_fields() {
         let e = create_environment();
 
-        e.get::<TestService>().field;
+        assert_eq!(e.get::<TestService>().field, 0);
+        assert_eq!(e.get::<TestService>().kit.deps(), ["Dependency"]);
     }
 
     #[test]
@@ -192,16 +540,309 @@ mod tests {
         let _b1 = e.get::<TestService>();
     }
 
+    #[test]
+    fn dependencies_are_registered() {
+        let e = create_environment();
+
+        assert!(e.try_get::<Dependency>().is_some());
+        assert!(e.try_get::<Shared>().is_none());
+    }
+
+    static SHARED_STARTS: AtomicUsize = AtomicUsize::new(0);
+
+    struct Shared {}
+    make_service!(Shared);
+
+    impl Service for Shared {
+        fn create(_builder: &mut EnvironmentBuilder) -> io::Result<Shared> {
+            Ok(Shared {})
+        }
+
+        fn start<'a>(&'a self, _env: &'a Arc<Environment>) -> Lifecycle<'a> {
+            SHARED_STARTS.fetch_add(1, Ordering::Relaxed);
+            Box::pin(async { Ok(()) })
+        }
+
+        fn name() -> &'static str {
+            "Shared"
+        }
+    }
+
+    #[tokio::test]
+    async fn shared_dependency_starts_once() {
+        let mut builder = EnvironmentBuilder::new();
+        builder
+            .register::<Shared>()
+            .and_then(|builder| builder.register::<Shared>())
+            .expect("register succeeds");
+        let env = builder.build();
+        Environment::start(&env).await.expect("start succeeds");
+
+        assert_eq!(SHARED_STARTS.load(Ordering::Relaxed), 1);
+    }
+
+    static LIFECYCLE: Mutex<Vec<&str>> = Mutex::new(Vec::new());
+
+    /// Logs its start and stop, and runs a task until it is stopped.
+    struct Base {}
+    make_service!(Base);
+
+    impl Service for Base {
+        fn create(_builder: &mut EnvironmentBuilder) -> io::Result<Base> {
+            Ok(Base {})
+        }
+
+        fn start<'a>(&'a self, env: &'a Arc<Environment>) -> Lifecycle<'a> {
+            Box::pin(async move {
+                LIFECYCLE.lock().unwrap().push("start Base");
+                env.spawn(Base::name(), async {
+                    loop {
+                        time::delay_for(std::time::Duration::from_millis(10)).await;
+                    }
+                });
+                Ok(())
+            })
+        }
+
+        fn stop<'a>(&'a self, _env: &'a Arc<Environment>) -> Lifecycle<'a> {
+            LIFECYCLE.lock().unwrap().push("stop Base");
+            Box::pin(async { Ok(()) })
+        }
+
+        fn name() -> &'static str {
+            "Base"
+        }
+    }
+
+    /// Depends on `Base`, logs its start and stop.
+    struct Dependent {
+        _kit: ServiceKit,
+    }
+    make_service!(Dependent);
+
+    impl Service for Dependent {
+        fn create(builder: &mut EnvironmentBuilder) -> io::Result<Dependent> {
+            Ok(Dependent {
+                _kit: ServiceKit::with_builder(builder).with_dep::<Base>()?.new(),
+            })
+        }
+
+        fn start<'a>(&'a self, _env: &'a Arc<Environment>) -> Lifecycle<'a> {
+            LIFECYCLE.lock().unwrap().push("start Dependent");
+            Box::pin(async { Ok(()) })
+        }
+
+        fn stop<'a>(&'a self, _env: &'a Arc<Environment>) -> Lifecycle<'a> {
+            LIFECYCLE.lock().unwrap().push("stop Dependent");
+            Box::pin(async { Ok(()) })
+        }
+
+        fn name() -> &'static str {
+            "Dependent"
+        }
+    }
+
+    #[tokio::test]
+    async fn services_start_after_and_stop_before_their_dependencies() {
+        let mut builder = EnvironmentBuilder::new();
+        builder.register::<Dependent>().expect("register succeeds");
+        let env = builder.build();
+
+        Environment::start(&env).await.expect("start succeeds");
+        assert!(env
+            .health()
+            .iter()
+            .all(|service| service.health == Health::Running));
+        Environment::stop(&env).await;
+
+        assert_eq!(
+            *LIFECYCLE.lock().unwrap(),
+            ["start Base", "start Dependent", "stop Dependent", "stop Base"]
+        );
+        assert_eq!(
+            env.health(),
+            vec![
+                ServiceHealth {
+                    name: String::from("Base"),
+                    health: Health::Stopped,
+                },
+                ServiceHealth {
+                    name: String::from("Dependent"),
+                    health: Health::Stopped,
+                },
+            ]
+        );
+    }
+
+    /// Runs a task that fails right away.
+    struct Crashing {}
+    make_service!(Crashing);
+
+    impl Service for Crashing {
+        fn create(_builder: &mut EnvironmentBuilder) -> io::Result<Crashing> {
+            Ok(Crashing {})
+        }
+
+        fn start<'a>(&'a self, env: &'a Arc<Environment>) -> Lifecycle<'a> {
+            env.spawn(Crashing::name(), async {
+                Err(io::Error::new(io::ErrorKind::AddrInUse, "address in use"))
+            });
+            Box::pin(async { Ok(()) })
+        }
+
+        fn name() -> &'static str {
+            "Crashing"
+        }
+    }
+
+    #[tokio::test]
+    async fn failed_task_marks_its_service_failed() {
+        let mut builder = EnvironmentBuilder::new();
+        builder.register::<Crashing>().expect("register succeeds");
+        let env = builder.build();
+        Environment::start(&env).await.expect("start succeeds");
+
+        time::delay_for(std::time::Duration::from_millis(50)).await;
+
+        assert_eq!(
+            env.health()[0].health,
+            Health::Failed {
+                error: String::from("address in use")
+            }
+        );
+        Environment::stop(&env).await;
+        assert!(matches!(env.health()[0].health, Health::Failed { .. }));
+    }
+
+    /// Runs a task that returns right away, without being stopped.
+    struct Quitting {}
+    make_service!(Quitting);
+
+    impl Service for Quitting {
+        fn create(_builder: &mut EnvironmentBuilder) -> io::Result<Quitting> {
+            Ok(Quitting {})
+        }
+
+        fn start<'a>(&'a self, env: &'a Arc<Environment>) -> Lifecycle<'a> {
+            env.spawn(Quitting::name(), async { Ok(()) });
+            Box::pin(async { Ok(()) })
+        }
+
+        fn name() -> &'static str {
+            "Quitting"
+        }
+    }
+
+    #[tokio::test]
+    async fn task_ending_early_marks_its_service_failed() {
+        let mut builder = EnvironmentBuilder::new();
+        builder.register::<Quitting>().expect("register succeeds");
+        let env = builder.build();
+        Environment::start(&env).await.expect("start succeeds");
+
+        time::delay_for(std::time::Duration::from_millis(50)).await;
+
+        assert_eq!(
+            env.health()[0].health,
+            Health::Failed {
+                error: String::from("exited before it was stopped")
+            }
+        );
+    }
+
+    struct Cyclic {}
+    make_service!(Cyclic);
+
+    impl Service for Cyclic {
+        fn create(builder: &mut EnvironmentBuilder) -> io::Result<Cyclic> {
+            ServiceKit::with_builder(builder).with_dep::<Cyclic>()?.new();
+            Ok(Cyclic {})
+        }
+
+        fn name() -> &'static str {
+            "Cyclic"
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "Cyclic depends on itself")]
+    fn dependency_cycles_are_refused() {
+        let _ = EnvironmentBuilder::new().register::<Cyclic>();
+    }
+
+    /// Cannot be created, like a garden whose schedule file is invalid.
+    struct Broken {}
+    make_service!(Broken);
+
+    impl Service for Broken {
+        fn create(_builder: &mut EnvironmentBuilder) -> io::Result<Broken> {
+            Err(io::Error::new(io::ErrorKind::InvalidData, "bad schedule"))
+        }
+
+        fn name() -> &'static str {
+            "Broken"
+        }
+    }
+
+    /// Depends on `Broken`.
+    struct NeedsBroken {}
+    make_service!(NeedsBroken);
+
+    impl Service for NeedsBroken {
+        fn create(builder: &mut EnvironmentBuilder) -> io::Result<NeedsBroken> {
+            ServiceKit::with_builder(builder).with_dep::<Broken>()?.new();
+            Ok(NeedsBroken {})
+        }
+
+        fn name() -> &'static str {
+            "NeedsBroken"
+        }
+    }
+
+    #[test]
+    fn failing_to_create_a_dependency_fails_registration() {
+        let mut builder = EnvironmentBuilder::new();
+
+        let e = builder.register::<NeedsBroken>().err().expect("register fails");
+
+        assert_eq!(e.to_string(), "bad schedule");
+        let env = builder.build();
+        assert!(env.try_get::<NeedsBroken>().is_none());
+        assert!(env.health().is_empty());
+    }
+
+    #[test]
+    fn services_share_injected_clock() {
+        let start = Utc.ymd(2020, 7, 1).and_hms(0, 0, 0);
+        let clock = Arc::new(VirtualClock::new(start));
+        let env = EnvironmentBuilder::with_clock(Arc::clone(&clock) as Arc<dyn Clock>).build();
+
+        clock.advance(Duration::days(1));
+
+        assert_eq!(env.clock().now(), start + Duration::days(1));
+    }
+
+    #[test]
+    fn services_are_reachable_from_other_threads() {
+        let env = create_environment();
+
+        let field = {
+            let env = Arc::clone(&env);
+            thread::spawn(move || env.get::<TestService>().field)
+        };
+
+        assert_eq!(field.join().expect("thread succeeds"), 0);
+    }
+
     #[test]
     fn implements_send_and_sync() {
         assert_impl_all!(Environment: Send, Sync);
+        assert_impl_all!(ServiceKit: Send, Sync);
     }
 
     fn create_environment() -> Arc<Environment> {
-        let (owned, env) = Environment::bootstrap();
-        let env = unsafe { &mut *env };
-        env.register::<TestService>(Arc::clone(&owned));
-        env.finish_bootstrap();
-        return owned;
+        let mut builder = EnvironmentBuilder::new();
+        builder.register::<TestService>().expect("register succeeds");
+        builder.build()
     }
 }
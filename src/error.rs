@@ -0,0 +1,224 @@
+use std::error;
+use std::fmt;
+use std::io;
+
+/// Why an operation on the garden failed.
+///
+/// Converts to and from `io::Error` without losing the variant, so code still returning
+/// `io::Result` can pass these errors through with `?`.
+#[derive(Debug)]
+pub enum Error {
+    /// No schedule has the given name.
+    ScheduleNotFound(String),
+
+    /// No valve has the given name.
+    ValveNotFound(String),
+
+    /// A schedule or the schedule file breaks a rule, such as using a valve that does not exist.
+    InvalidSchedule(String),
+
+    /// The logbook has no run of the given schedule.
+    RunNotFound(String),
+
+    /// The run of the given schedule has already finished.
+    NotRunning(String),
+
+    /// The schedule is already running and the overlap policy refuses another run.
+    AlreadyRunning(String),
+
+    /// There is no alarm with the given id.
+    AlarmNotFound(usize),
+
+    /// The alarm with the given id was already acknowledged.
+    AlarmAlreadyAcknowledged(usize),
+
+    /// Watering is stopped until the active alarms are acknowledged.
+    AlarmActive,
+
+    /// A GPIO pin could not be exported or driven, often for lack of permission.
+    Gpio { pin: u64, source: sysfs_gpio::Error },
+
+    /// Reading or writing files or the database failed.
+    Storage(io::Error),
+
+    /// A file or message could not be parsed. The line and column are 1-based, where known.
+    Parse {
+        message: String,
+        line: Option<usize>,
+        column: Option<usize>,
+    },
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    /// A parse error at `line`, the message saying where.
+    pub fn parse_at(line: usize, message: String) -> Error {
+        Error::Parse {
+            message: format!("line {}: {}", line, message),
+            line: Some(line),
+            column: None,
+        }
+    }
+
+    /// The closest `io::ErrorKind`, used when converting to an `io::Error`.
+    pub fn kind(&self) -> io::ErrorKind {
+        match self {
+            Error::ScheduleNotFound(_)
+            | Error::ValveNotFound(_)
+            | Error::RunNotFound(_)
+            | Error::AlarmNotFound(_) => io::ErrorKind::NotFound,
+            Error::InvalidSchedule(_) | Error::NotRunning(_) => io::ErrorKind::InvalidInput,
+            Error::AlreadyRunning(_) | Error::AlarmAlreadyAcknowledged(_) => {
+                io::ErrorKind::AlreadyExists
+            }
+            Error::AlarmActive => io::ErrorKind::Other,
+            Error::Gpio { source, .. } => match source {
+                sysfs_gpio::Error::Io(e) => e.kind(),
+                _ => io::ErrorKind::Other,
+            },
+            Error::Storage(e) => e.kind(),
+            Error::Parse { .. } => io::ErrorKind::InvalidData,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::ScheduleNotFound(name) => write!(f, "no schedule named {}", name),
+            Error::ValveNotFound(name) => write!(f, "no valve named {}", name),
+            Error::InvalidSchedule(message) => f.write_str(message),
+            Error::RunNotFound(name) => write!(f, "{} never started", name),
+            Error::NotRunning(name) => write!(f, "{} is not running", name),
+            Error::AlreadyRunning(name) => write!(f, "{} is already running", name),
+            Error::AlarmNotFound(id) => write!(f, "no alarm {}", id),
+            Error::AlarmAlreadyAcknowledged(id) => {
+                write!(f, "alarm {} is already acknowledged", id)
+            }
+            Error::AlarmActive => f.write_str("cannot water while an alarm is active"),
+            Error::Gpio { pin, source } => {
+                if self.kind() == io::ErrorKind::PermissionDenied {
+                    write!(f, "no permission to use GPIO pin {}: {}", pin, source)
+                } else {
+                    write!(f, "GPIO pin {}: {}", pin, source)
+                }
+            }
+            Error::Storage(e) => e.fmt(f),
+            Error::Parse { message, .. } => f.write_str(message),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Error::Gpio { source, .. } => Some(source),
+            Error::Storage(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    /// Unwraps an `Error` that was converted to an `io::Error`, anything else is a storage error.
+    fn from(e: io::Error) -> Error {
+        if !e.get_ref().is_some_and(|inner| inner.is::<Error>()) {
+            return Error::Storage(e);
+        }
+        match e.into_inner().map(|inner| inner.downcast::<Error>()) {
+            Some(Ok(error)) => *error,
+            _ => unreachable!("the inner error was checked to be an Error"),
+        }
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(e: Error) -> io::Error {
+        match e {
+            Error::Storage(e) => e,
+            e => io::Error::new(e.kind(), e),
+        }
+    }
+}
+
+impl From<serde_yaml::Error> for Error {
+    fn from(e: serde_yaml::Error) -> Error {
+        let location = e.location();
+        Error::Parse {
+            message: e.to_string(),
+            line: location.as_ref().map(|location| location.line()),
+            column: location.as_ref().map(|location| location.column()),
+        }
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Error {
+        // serde_json reports line 0 when the error has no location
+        let (line, column) = match e.line() {
+            0 => (None, None),
+            line => (Some(line), Some(e.column())),
+        };
+        Error::Parse {
+            message: e.to_string(),
+            line,
+            column,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_through_io_error() {
+        let e: io::Error = Error::ScheduleNotFound(String::from("pots")).into();
+
+        assert_eq!(e.kind(), io::ErrorKind::NotFound);
+        assert_eq!(e.to_string(), "no schedule named pots");
+        match Error::from(e) {
+            Error::ScheduleNotFound(name) => assert_eq!(name, "pots"),
+            other => panic!("unexpected error {:?}", other),
+        }
+    }
+
+    #[test]
+    fn other_io_errors_are_storage_errors() {
+        let e = Error::from(io::Error::new(io::ErrorKind::PermissionDenied, "read only"));
+
+        assert!(matches!(e, Error::Storage(_)));
+        assert_eq!(e.kind(), io::ErrorKind::PermissionDenied);
+        assert_eq!(io::Error::from(e).to_string(), "read only");
+    }
+
+    #[test]
+    fn parse_errors_keep_their_location() {
+        let e = Error::from(serde_yaml::from_str::<Vec<u64>>("- 1\n- two\n").unwrap_err());
+
+        match e {
+            Error::Parse { line, column, .. } => {
+                assert_eq!(line, Some(2));
+                assert_eq!(column, Some(3));
+            }
+            other => panic!("unexpected error {:?}", other),
+        }
+    }
+
+    #[test]
+    fn gpio_permission_errors_say_so() {
+        let e = Error::Gpio {
+            pin: 22,
+            source: sysfs_gpio::Error::Io(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "denied",
+            )),
+        };
+
+        assert_eq!(e.kind(), io::ErrorKind::PermissionDenied);
+        assert!(e
+            .to_string()
+            .starts_with("no permission to use GPIO pin 22"));
+    }
+}
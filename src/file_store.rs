@@ -0,0 +1,207 @@
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+/// A `Write` sink that replaces the contents of a file on each flush.
+///
+/// Writes are buffered in memory. Flushing writes the buffer to a temporary file next to the
+/// destination and renames it into place, so a crash mid-write never leaves a truncated file.
+pub struct FileStore {
+    path: PathBuf,
+    buffer: Vec<u8>,
+}
+
+impl FileStore {
+    /// Creates a new store for the file at the given path.
+    pub fn new<P: Into<PathBuf>>(path: P) -> FileStore {
+        FileStore {
+            path: path.into(),
+            buffer: Vec::new(),
+        }
+    }
+}
+
+impl Write for FileStore {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let mut staging = self.path.clone().into_os_string();
+        staging.push(".tmp");
+        let staging = PathBuf::from(staging);
+
+        let mut file = File::create(&staging)?;
+        file.write_all(&self.buffer)?;
+        file.sync_all()?;
+        fs::rename(&staging, &self.path)?;
+
+        self.buffer.clear();
+        Ok(())
+    }
+}
+
+/// A `Write` sink that keeps the contents of the last flush in memory.
+///
+/// Clones share the flushed contents, so one clone can be handed to a writer while another reads
+/// back what it wrote.
+#[derive(Clone, Default)]
+pub struct MemoryStore {
+    contents: Arc<Mutex<Vec<u8>>>,
+    buffer: Vec<u8>,
+}
+
+impl MemoryStore {
+    /// Creates a new, empty store.
+    pub fn new() -> MemoryStore {
+        MemoryStore::default()
+    }
+
+    /// Returns the contents of the last flush.
+    pub fn contents(&self) -> Vec<u8> {
+        self.contents.lock().expect("store lock is not poisoned").clone()
+    }
+}
+
+impl Write for MemoryStore {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let mut contents = self.contents.lock().expect("store lock is not poisoned");
+        *contents = std::mem::take(&mut self.buffer);
+        Ok(())
+    }
+}
+
+/// Storage that is only ever appended to and read back whole, for journals.
+pub trait Journal: Write + Send {
+    /// Reads everything written so far.
+    fn contents(&mut self) -> io::Result<Vec<u8>>;
+
+    /// Discards everything after the first `len` bytes.
+    fn truncate(&mut self, len: u64) -> io::Result<()>;
+}
+
+impl Journal for Vec<u8> {
+    fn contents(&mut self) -> io::Result<Vec<u8>> {
+        Ok(self.clone())
+    }
+
+    fn truncate(&mut self, len: u64) -> io::Result<()> {
+        Vec::truncate(self, len as usize);
+        Ok(())
+    }
+}
+
+/// A `Write` sink that appends to a file, syncing it to disk on each flush.
+///
+/// The file is created on the first write.
+pub struct AppendStore {
+    path: PathBuf,
+    file: Option<File>,
+}
+
+impl AppendStore {
+    /// Creates a new store for the file at the given path.
+    pub fn new<P: Into<PathBuf>>(path: P) -> AppendStore {
+        AppendStore {
+            path: path.into(),
+            file: None,
+        }
+    }
+}
+
+impl Write for AppendStore {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let file = match &mut self.file {
+            Some(file) => file,
+            None => self
+                .file
+                .insert(OpenOptions::new().create(true).append(true).open(&self.path)?),
+        };
+        file.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut self.file {
+            Some(file) => file.sync_data(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Journal for AppendStore {
+    fn contents(&mut self) -> io::Result<Vec<u8>> {
+        match fs::read(&self.path) {
+            Ok(contents) => Ok(contents),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn truncate(&mut self, len: u64) -> io::Result<()> {
+        let file = match OpenOptions::new().write(true).open(&self.path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound && len == 0 => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        file.set_len(len)?;
+        file.sync_all()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn flush_replaces_previous_contents() {
+        let path = env::temp_dir().join("rustic-garden-file-store-test.yaml");
+        let mut store = FileStore::new(&path);
+
+        store.write_all(b"first version").expect("write succeeds");
+        store.flush().expect("flush succeeds");
+        store.write_all(b"second").expect("write succeeds");
+        store.flush().expect("flush succeeds");
+
+        assert_eq!(fs::read(&path).expect("read succeeds"), b"second");
+        fs::remove_file(&path).expect("cleanup succeeds");
+    }
+
+    #[test]
+    fn memory_store_keeps_last_flush() {
+        let store = MemoryStore::new();
+        let mut writer = store.clone();
+
+        writer.write_all(b"first version").expect("write succeeds");
+        writer.flush().expect("flush succeeds");
+        writer.write_all(b"second").expect("write succeeds");
+        writer.flush().expect("flush succeeds");
+
+        assert_eq!(store.contents(), b"second");
+    }
+
+    #[test]
+    fn append_store_appends_and_truncates() {
+        let path = env::temp_dir().join("rustic-garden-append-store-test.jsonl");
+        let _ = fs::remove_file(&path);
+        let mut store = AppendStore::new(&path);
+
+        store.write_all(b"first\n").expect("write succeeds");
+        store.write_all(b"second\n").expect("write succeeds");
+        store.flush().expect("flush succeeds");
+        assert_eq!(store.contents().expect("read succeeds"), b"first\nsecond\n");
+
+        store.truncate(6).expect("truncate succeeds");
+        store.write_all(b"third\n").expect("write succeeds");
+        assert_eq!(store.contents().expect("read succeeds"), b"first\nthird\n");
+        fs::remove_file(&path).expect("cleanup succeeds");
+    }
+}
@@ -0,0 +1,325 @@
+extern crate log;
+
+use super::calendar::Calendar;
+use super::logbook::{Logbook, RunId};
+use super::valve::Valves;
+use log::{error, info};
+use std::io;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use sysfs_gpio::{Direction, Edge, Pin};
+
+/// Milliseconds to block waiting for an edge before checking for shutdown.
+const POLL_TIMEOUT_MS: isize = 500;
+
+/// Counts pulses from a hall-effect flow meter.
+///
+/// A counter created with `export` is fed by a background thread watching a GPIO input for rising
+/// edges. A counter created with `new` is detached from any hardware and only counts what is
+/// passed to `add`.
+pub struct PulseCounter {
+    pulses: Arc<AtomicU64>,
+
+    /// Held only to keep the pin exported and its counting thread running.
+    _input: Option<InputPin>,
+}
+
+impl PulseCounter {
+    /// Creates a new counter that is not connected to any GPIO pin.
+    pub fn new() -> PulseCounter {
+        PulseCounter {
+            pulses: Arc::new(AtomicU64::new(0)),
+            _input: None,
+        }
+    }
+
+    /// Creates a new counter fed by rising edges on the given GPIO pin.
+    pub fn export(pin_number: u64) -> sysfs_gpio::Result<PulseCounter> {
+        let pulses = Arc::new(AtomicU64::new(0));
+        let input = InputPin::export(pin_number, Arc::clone(&pulses))?;
+        Ok(PulseCounter {
+            pulses,
+            _input: Some(input),
+        })
+    }
+
+    /// Adds pulses to the counter.
+    pub fn add(&self, pulses: u64) {
+        self.pulses.fetch_add(pulses, Ordering::Relaxed);
+    }
+
+    /// Returns the total number of pulses counted since creation.
+    pub fn total(&self) -> u64 {
+        self.pulses.load(Ordering::Relaxed)
+    }
+}
+
+impl Default for PulseCounter {
+    fn default() -> PulseCounter {
+        PulseCounter::new()
+    }
+}
+
+/// Converts flow meter pulses to a volume of water.
+pub struct FlowMeter {
+    counter: PulseCounter,
+    pulses_per_liter: f64,
+    last_total: u64,
+}
+
+impl FlowMeter {
+    /// Creates a new flow meter reading from the given counter.
+    ///
+    /// `pulses_per_liter` should be positive, as checked when the calendar is initialized.
+    pub fn new(counter: PulseCounter, pulses_per_liter: f64) -> FlowMeter {
+        let last_total = counter.total();
+        FlowMeter {
+            counter,
+            pulses_per_liter,
+            last_total,
+        }
+    }
+
+    /// The counter this meter reads pulses from.
+    pub fn counter(&self) -> &PulseCounter {
+        &self.counter
+    }
+
+    /// Returns the liters delivered since the previous sample.
+    pub fn sample(&mut self) -> f64 {
+        let total = self.counter.total();
+        let pulses = total.wrapping_sub(self.last_total);
+        self.last_total = total;
+        pulses as f64 / self.pulses_per_liter
+    }
+
+    /// Samples the meter and charges the delivered volume to the running schedules.
+    ///
+    /// The volume is attributed to each open valve with `attribute`, then split evenly between
+    /// the incomplete `Record`s of the runs that opened that valve. Returns the total liters
+    /// sampled, including any volume that could not be attributed to a running schedule.
+    pub fn account(
+        &mut self,
+        valves: &Valves,
+        calendar: &Calendar,
+        logbook: &mut Logbook,
+    ) -> io::Result<f64> {
+        let liters = self.sample();
+        if liters == 0.0 {
+            return Ok(liters);
+        }
+
+        let mut open_valves = match valves.open_valves() {
+            Ok(v) => v,
+            Err(e) => {
+                error!("failed to read valve states: {:?}", e);
+                return Err(e.into());
+            }
+        };
+        // Everything passes through the master valve, the zones are what use the water
+        open_valves.retain(|valve| Some(valve.as_str()) != calendar.master_valve());
+        if open_valves.is_empty() {
+            info!("{:.2}L of flow with no open valves", liters);
+        }
+        let volumes = attribute(liters, &open_valves);
+
+        // Runs recorded before their valves were fall back to their schedule's current valves
+        let running: Vec<(RunId, Vec<String>)> = logbook
+            .iter_incomplete()
+            .filter_map(|record| {
+                let valves = match (record.valves.is_empty(), calendar.get(&record.name)) {
+                    (false, _) => record.valves.clone(),
+                    (true, Some(schedule)) => schedule.valves().to_vec(),
+                    (true, None) => return None,
+                };
+                Some((record.id?, valves))
+            })
+            .collect();
+        for (run, run_valves) in &running {
+            let charged: Vec<(String, f64)> = volumes
+                .iter()
+                .filter(|(valve, _)| run_valves.contains(valve))
+                .map(|(valve, liters)| {
+                    let sharing = running
+                        .iter()
+                        .filter(|(_, other)| other.contains(valve))
+                        .count();
+                    (valve.clone(), liters / sharing as f64)
+                })
+                .collect();
+            if !charged.is_empty() {
+                logbook.record_volume(*run, &charged)?;
+            }
+        }
+
+        Ok(liters)
+    }
+}
+
+/// Splits a volume evenly between the open valves.
+///
+/// A single meter on the mains line cannot tell zones apart, so each open valve is charged an
+/// equal share. Returns an empty list if no valves are open.
+pub fn attribute(liters: f64, open_valves: &[String]) -> Vec<(String, f64)> {
+    if open_valves.is_empty() {
+        return Vec::new();
+    }
+
+    let share = liters / open_valves.len() as f64;
+    open_valves
+        .iter()
+        .map(|valve| (valve.clone(), share))
+        .collect()
+}
+
+/// RAII guard for a `sysfs_gpio::Pin` configured as an edge-triggered input.
+///
+/// The pulse counting thread is stopped and the pin unexported when this is dropped.
+struct InputPin {
+    pin_number: u64,
+    pin: Pin,
+    shutdown: Arc<AtomicBool>,
+    worker: Option<thread::JoinHandle<()>>,
+}
+
+impl InputPin {
+    fn export(pin_number: u64, pulses: Arc<AtomicU64>) -> sysfs_gpio::Result<InputPin> {
+        let pin = Pin::new(pin_number);
+        pin.export()?;
+        InputPin::try_configure(&pin)?;
+        let mut poller = pin.get_poller()?;
+
+        let shutdown = Arc::new(AtomicBool::new(false));
+        let worker_shutdown = Arc::clone(&shutdown);
+        let worker = thread::spawn(move || {
+            while !worker_shutdown.load(Ordering::Relaxed) {
+                match poller.poll(POLL_TIMEOUT_MS) {
+                    Ok(Some(_)) => {
+                        pulses.fetch_add(1, Ordering::Relaxed);
+                    }
+                    Ok(None) => (),
+                    Err(e) => {
+                        error!("flow meter on pin {} failed: {:?}", pin_number, e);
+                        return;
+                    }
+                }
+            }
+        });
+
+        Ok(InputPin {
+            pin_number,
+            pin,
+            shutdown,
+            worker: Some(worker),
+        })
+    }
+
+    fn try_configure(pin: &Pin) -> sysfs_gpio::Result<()> {
+        // Same udev race as OutputPin, retry first access to the newly exported GPIO
+        let mut failures = 0;
+        while let Err(e) = pin.set_direction(Direction::In) {
+            thread::sleep(Duration::from_millis(10));
+            failures += 1;
+            if failures > 10 {
+                return Err(e);
+            }
+        }
+
+        pin.set_edge(Edge::RisingEdge)
+    }
+}
+
+// RAII for InputPin shutdown
+impl Drop for InputPin {
+    fn drop(&mut self) {
+        self.shutdown.store(true, Ordering::Relaxed);
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+        if let Err(err) = self.pin.unexport() {
+            println!("Failed to unexport pin {}: {:?}", self.pin_number, err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::logbook::Trigger;
+    use crate::valve::Backend;
+
+    #[test]
+    fn sample_converts_pulses_to_liters() {
+        let counter = PulseCounter::new();
+        counter.add(900);
+        let mut meter = FlowMeter::new(counter, 450.0);
+
+        meter.counter.add(900);
+
+        assert_eq!(meter.sample(), 2.0);
+    }
+
+    #[test]
+    fn sample_only_counts_pulses_since_previous_sample() {
+        let mut meter = FlowMeter::new(PulseCounter::new(), 10.0);
+
+        meter.counter.add(25);
+        assert_eq!(meter.sample(), 2.5);
+
+        meter.counter.add(5);
+        assert_eq!(meter.sample(), 0.5);
+        assert_eq!(meter.sample(), 0.0);
+    }
+
+    #[test]
+    fn account_splits_a_shared_valve_between_its_runs() {
+        let mut valves = Valves::with_backend(Backend::Mock);
+        for (name, pin) in &[("a", 22), ("b", 23)] {
+            valves
+                .register_new_valve(String::from(*name), *pin)
+                .expect("mock valves always register");
+            valves.get_mut(name).expect("registered").open().expect("open succeeds");
+        }
+        let calendar = Calendar::new(Box::new(Vec::<u8>::new()));
+        let mut logbook = Logbook::new(Box::new(Vec::<u8>::new()));
+        let shared = logbook
+            .mark_started_by("shared", &[String::from("a")], Trigger::Schedule)
+            .expect("mark_started_by succeeds");
+        let both = logbook
+            .mark_started_by(
+                "both",
+                &[String::from("a"), String::from("b")],
+                Trigger::Schedule,
+            )
+            .expect("mark_started_by succeeds");
+        let mut meter = FlowMeter::new(PulseCounter::new(), 1.0);
+
+        meter.counter.add(4);
+        assert_eq!(meter.account(&valves, &calendar, &mut logbook).ok(), Some(4.0));
+
+        let shared = logbook.get(shared).expect("record exists");
+        assert_eq!(shared.liters, Some(1.0));
+        let both = logbook.get(both).expect("record exists");
+        assert_eq!(both.liters, Some(3.0));
+        assert_eq!(both.valve_liters.get("b"), Some(&2.0));
+    }
+
+    #[test]
+    fn attribute_splits_evenly_between_open_valves() {
+        let open = vec![String::from("a"), String::from("b")];
+
+        let volumes = attribute(3.0, &open);
+
+        assert_eq!(
+            volumes,
+            vec![(String::from("a"), 1.5), (String::from("b"), 1.5)]
+        );
+    }
+
+    #[test]
+    fn attribute_with_no_open_valves_is_empty() {
+        assert!(attribute(3.0, &[]).is_empty());
+    }
+}
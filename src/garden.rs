@@ -0,0 +1,594 @@
+extern crate log;
+
+use super::calendar::{Calendar, ConfigChange, Occurrence};
+use super::clock::{Clock, SystemClock};
+use super::environment::{Any, AsAny, EnvironmentBuilder, Service};
+use super::error::Error;
+use super::file_store::{AppendStore, FileStore};
+use super::flow_meter::{FlowMeter, PulseCounter};
+use super::leak_detector::LeakDetector;
+use super::logbook::{self, AlarmKind, Logbook, Retention, RunId};
+use super::settings::{Settings, Storage};
+#[cfg(feature = "sqlite")]
+use super::sqlite_store::Database;
+use super::valve::{Backend, Valves};
+use chrono::{DateTime, Datelike, NaiveDate, Utc};
+use log::{error, info, warn};
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::Receiver;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// Service owning the garden's persisted state and hardware.
+pub struct Garden {
+    state: Mutex<GardenState>,
+}
+
+impl Garden {
+    /// Locks the garden state for exclusive access.
+    pub fn lock(&self) -> MutexGuard<'_, GardenState> {
+        self.state.lock().expect("garden state lock is not poisoned")
+    }
+}
+
+impl Service for Garden {
+    fn create(builder: &mut EnvironmentBuilder) -> io::Result<Garden> {
+        let state = GardenState::load(builder.clock(), builder.settings())?;
+        Ok(Garden {
+            state: Mutex::new(state),
+        })
+    }
+
+    fn name() -> &'static str {
+        "Garden"
+    }
+}
+
+impl AsAny for Garden {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+/// The calendar, logbook and valves of the garden.
+pub struct GardenState {
+    pub calendar: Calendar,
+    pub logbook: Logbook,
+    pub valves: Valves,
+
+    /// Runs in progress as they were scheduled when they started, by run id.
+    ///
+    /// Runs finish as planned even if their schedule is changed or deleted meanwhile.
+    pub running: BTreeMap<RunId, Occurrence>,
+
+    flow_meter: Option<FlowMeter>,
+    leak_detector: LeakDetector,
+    last_flow_sample: Option<DateTime<Utc>>,
+    rain_delay_until: Option<DateTime<Utc>>,
+    config_changes: Receiver<ConfigChange>,
+
+    /// Where and when old logbook records are archived, not at all if `None`.
+    archive: Option<(PathBuf, Retention)>,
+    last_compaction: Option<NaiveDate>,
+}
+
+impl GardenState {
+    /// Loads the calendar and logbook from the data directory and exports the configured GPIO
+    /// pins, unless the settings say not to drive hardware.
+    pub fn load(clock: Arc<dyn Clock>, settings: &Settings) -> io::Result<GardenState> {
+        let (calendar, mut logbook) = match settings.storage {
+            Storage::Yaml => load_files(clock, settings)?,
+            Storage::Sqlite => load_database(clock, settings)?,
+        };
+
+        logbook.set_overlap_policy(settings.overlap);
+
+        let backend = settings.backend();
+        if backend == Backend::DryRun {
+            warn!("dry run, valves will not be driven and runs are marked as dry runs");
+            logbook.set_dry_run(true);
+        }
+        let mut valves = Valves::with_backend(backend);
+        for valve in calendar.valves() {
+            valves.register_new_valve(valve.name.clone(), valve.pin)?;
+        }
+
+        let flow_meter = match (calendar.flow_meter(), backend) {
+            (Some(config), Backend::Gpio) => {
+                let counter = PulseCounter::export(config.pin).map_err(|source| Error::Gpio {
+                    pin: config.pin,
+                    source,
+                })?;
+                Some(FlowMeter::new(counter, config.pulses_per_liter))
+            }
+            (Some(config), _) => {
+                Some(FlowMeter::new(PulseCounter::new(), config.pulses_per_liter))
+            }
+            (None, _) => None,
+        };
+
+        let mut state = GardenState::new(calendar, logbook, valves);
+        state.set_flow_meter(flow_meter);
+        state.set_archive(Some((settings.archive_dir(), settings.retention.clone())));
+        Ok(state)
+    }
+
+    /// Creates garden state from already loaded parts, with no flow meter.
+    pub fn new(mut calendar: Calendar, logbook: Logbook, valves: Valves) -> GardenState {
+        let leak_detector = leak_detector(&calendar);
+        let config_changes = calendar.subscribe();
+
+        GardenState {
+            calendar,
+            logbook,
+            valves,
+            running: BTreeMap::new(),
+            flow_meter: None,
+            leak_detector,
+            last_flow_sample: None,
+            rain_delay_until: None,
+            config_changes,
+            archive: None,
+            last_compaction: None,
+        }
+    }
+
+    /// Archives old logbook records to the given directory under the retention policy, or keeps
+    /// them all if `None`.
+    pub fn set_archive(&mut self, archive: Option<(PathBuf, Retention)>) {
+        self.archive = archive;
+    }
+
+    /// Moves logbook records the retention policy no longer keeps into archive files, once a day.
+    ///
+    /// Records are archived by the month they started in, to `logbook-YYYY-MM.yaml`.
+    pub fn compact_logbook(&mut self, now: DateTime<Utc>) -> io::Result<()> {
+        let today = self.calendar.date_of(now);
+        let (dir, retention) = match &self.archive {
+            Some(archive) if self.last_compaction != Some(today) => archive,
+            _ => return Ok(()),
+        };
+
+        self.logbook.compact(now, retention, |removed| {
+            let mut by_month: BTreeMap<(i32, u32), Vec<_>> = BTreeMap::new();
+            for record in removed {
+                let date = record
+                    .started_at()
+                    .map_or(today, |started| started.date().naive_local());
+                by_month
+                    .entry((date.year(), date.month()))
+                    .or_default()
+                    .push(record);
+            }
+            fs::create_dir_all(dir)?;
+            for ((year, month), records) in by_month {
+                let path = dir.join(format!("logbook-{:04}-{:02}.yaml", year, month));
+                info!("archiving {} records to {}", records.len(), path.display());
+                logbook::append_archive(&path, records)?;
+            }
+            Ok(())
+        })?;
+        self.last_compaction = Some(today);
+        Ok(())
+    }
+
+    /// Returns `true` if valve actions are only logged, not driven.
+    pub fn is_dry_run(&self) -> bool {
+        self.valves.backend() == Backend::DryRun
+    }
+
+    /// Brings the valves and leak detection up to date with changes made to the calendar.
+    ///
+    /// New valves are registered and removed ones closed. A changed valve is only registered again
+    /// if its pin moved, so a zone that is running keeps running.
+    pub fn apply_config_changes(&mut self) {
+        let mut reconfigured = false;
+        for change in self.config_changes.try_iter() {
+            match change {
+                ConfigChange::ValveSaved(name) => {
+                    reconfigured = true;
+                    let pin = match self.calendar.valves().find(|valve| valve.name == name) {
+                        Some(valve) => valve.pin,
+                        None => continue,
+                    };
+                    if self.valves.get(&name).map(|valve| valve.pin()) != Some(pin) {
+                        info!("registering valve {} on pin {}", name, pin);
+                        if let Err(e) = self.valves.register_new_valve(name.clone(), pin) {
+                            error!("failed to register valve {}: {}", name, e);
+                        }
+                    }
+                }
+                ConfigChange::ValveDeleted(name) => {
+                    reconfigured = true;
+                    if let Some(mut valve) = self.valves.remove(&name) {
+                        info!("unregistering valve {}", name);
+                        if let Err(e) = valve.close() {
+                            error!("failed to close removed valve {}: {:?}", name, e);
+                        }
+                    }
+                }
+                ConfigChange::SettingsChanged => reconfigured = true,
+                ConfigChange::ScheduleSaved(_) | ConfigChange::ScheduleDeleted(_) => (),
+            }
+        }
+
+        if reconfigured {
+            self.leak_detector = leak_detector(&self.calendar);
+        }
+    }
+
+    /// The flow meter on the mains line, if one is installed.
+    pub fn flow_meter(&self) -> Option<&FlowMeter> {
+        self.flow_meter.as_ref()
+    }
+
+    /// Replaces the flow meter on the mains line.
+    pub fn set_flow_meter(&mut self, flow_meter: Option<FlowMeter>) {
+        self.flow_meter = flow_meter;
+        self.last_flow_sample = None;
+    }
+
+    /// Samples the flow meter, charging water to running schedules and checking for leaks.
+    ///
+    /// Does nothing if no flow meter is configured. The first sample only charges water, there
+    /// is no previous sample to measure a flow rate from.
+    pub fn monitor_flow(&mut self, now: DateTime<Utc>) -> io::Result<()> {
+        let previous_sample = self.last_flow_sample.replace(now);
+
+        let meter = match &mut self.flow_meter {
+            Some(meter) => meter,
+            None => return Ok(()),
+        };
+        let liters = meter.account(&self.valves, &self.calendar, &mut self.logbook)?;
+
+        let elapsed = match previous_sample {
+            Some(t) if t < now => now - t,
+            _ => return Ok(()),
+        };
+        let liters_per_minute = liters / (elapsed.num_milliseconds() as f64 / 60_000.0);
+
+        let master = self.calendar.master_valve();
+        let open_zones: Vec<String> = self
+            .valves
+            .open_valves()?
+            .into_iter()
+            .filter(|valve| Some(valve.as_str()) != master)
+            .collect();
+
+        if let Some(kind) = self.leak_detector.check(liters_per_minute, &open_zones) {
+            self.respond_to_alarm(kind, liters_per_minute, open_zones)?;
+        }
+        Ok(())
+    }
+
+    /// Holds off starting new runs until the given time, or lifts the delay if `None`.
+    ///
+    /// Runs already in progress are not interrupted.
+    pub fn set_rain_delay(&mut self, until: Option<DateTime<Utc>>) {
+        info!("rain delay until {:?}", until);
+        self.rain_delay_until = until;
+    }
+
+    /// Returns `true` if new runs are held off by a rain delay at `now`.
+    pub fn is_rain_delayed(&self, now: DateTime<Utc>) -> bool {
+        self.rain_delay_until.is_some_and(|until| now < until)
+    }
+
+    /// Returns `true` if an alarm is waiting to be acknowledged.
+    pub fn has_active_alarm(&self) -> bool {
+        self.logbook.iter_active_alarms().next().is_some()
+    }
+
+    /// Shuts off water to stop abnormal flow, then records the alarm.
+    fn respond_to_alarm(
+        &mut self,
+        kind: AlarmKind,
+        liters_per_minute: f64,
+        open_zones: Vec<String>,
+    ) -> io::Result<()> {
+        // A leak could be any zone stuck open, a broken pipe only the open ones
+        let mut to_close = match kind {
+            AlarmKind::Leak => self.valves.names(),
+            AlarmKind::HighFlow => open_zones,
+        };
+        if let Some(master) = self.calendar.master_valve() {
+            to_close.retain(|valve| valve != master);
+            to_close.insert(0, String::from(master));
+        }
+
+        for name in &to_close {
+            match self.valves.get_mut(name) {
+                Some(valve) => {
+                    if let Err(e) = valve.close() {
+                        error!("failed to close {} after {:?}: {:?}", name, kind, e);
+                    }
+                }
+                None => warn!("cannot close unknown valve {}", name),
+            }
+        }
+        info!("closed {:?} after {:?}", to_close, kind);
+
+        // Closing the master valve cuts off every run, otherwise only the runs on closed zones
+        let master_closed = self.calendar.master_valve().is_some();
+        let cut_off: Vec<RunId> = self
+            .logbook
+            .iter_incomplete()
+            .filter(|record| {
+                master_closed || record.valves.iter().any(|valve| to_close.contains(valve))
+            })
+            .filter_map(|record| record.id)
+            .collect();
+        let reason = format!("{:?} alarm at {:.1} l/min", kind, liters_per_minute);
+        for run in cut_off {
+            self.logbook.mark_aborted(run, &reason)?;
+            self.running.remove(&run);
+        }
+
+        self.logbook.raise_alarm(kind, liters_per_minute, to_close)?;
+        Ok(())
+    }
+}
+
+/// Loads the calendar and logbook from the YAML files in the data directory.
+fn load_files(clock: Arc<dyn Clock>, settings: &Settings) -> io::Result<(Calendar, Logbook)> {
+    let schedule_path = settings.schedule_path();
+    let mut calendar =
+        Calendar::with_clock(Box::new(FileStore::new(schedule_path.clone())), Arc::clone(&clock));
+    if let Some(mut source) = open_if_exists(&schedule_path)? {
+        calendar.initialize(&mut source)?;
+    }
+    if let Some(version) = calendar.migrated_from() {
+        let backup = format!("{}.v{}.bak", schedule_path.display(), version);
+        fs::copy(&schedule_path, &backup)?;
+        info!(
+            "upgraded {} from version {}, backup at {}",
+            schedule_path.display(),
+            version,
+            backup
+        );
+        calendar.save()?;
+    }
+
+    let logbook = load_logbook_file(clock, settings)?;
+    Ok((calendar, logbook))
+}
+
+fn load_logbook_file(clock: Arc<dyn Clock>, settings: &Settings) -> io::Result<Logbook> {
+    let logbook_path = settings.logbook_path();
+    let mut logbook = Logbook::with_journal(
+        Box::new(FileStore::new(logbook_path.clone())),
+        Box::new(AppendStore::new(settings.journal_path())),
+        clock,
+    );
+    match open_if_exists(&logbook_path)? {
+        Some(mut source) => logbook.initialize(&mut source)?,
+        None => {
+            logbook.replay_journal()?;
+        }
+    }
+    Ok(logbook)
+}
+
+/// Opens the logbook in the data directory on its own, for one-shot commands that change it.
+///
+/// The daemon must not be running, it would overwrite the changes with its own copy.
+pub fn open_logbook(clock: Arc<dyn Clock>, settings: &Settings) -> io::Result<Logbook> {
+    match settings.storage {
+        Storage::Yaml => load_logbook_file(clock, settings),
+        Storage::Sqlite => Ok(load_database(clock, settings)?.1),
+    }
+}
+
+/// Reads a copy of the logbook in the data directory, leaving the stored logbook untouched even
+/// if the copy is changed.
+pub fn read_logbook(settings: &Settings) -> io::Result<Logbook> {
+    let (snapshot, journal) = match settings.storage {
+        Storage::Yaml => (
+            read_if_exists(&settings.logbook_path())?,
+            read_if_exists(&settings.journal_path())?.unwrap_or_default(),
+        ),
+        Storage::Sqlite => read_database_logbook(settings)?,
+    };
+
+    // Replay a copy of the journal, so the stored journal is never truncated
+    let mut logbook = Logbook::with_journal(
+        Box::new(io::sink()),
+        Box::new(journal),
+        Arc::new(SystemClock),
+    );
+    match snapshot {
+        Some(snapshot) => logbook.initialize(&mut snapshot.as_slice())?,
+        None => {
+            logbook.replay_journal()?;
+        }
+    }
+    Ok(logbook)
+}
+
+/// Loads the calendar and logbook from the SQLite database in the data directory.
+#[cfg(feature = "sqlite")]
+fn load_database(clock: Arc<dyn Clock>, settings: &Settings) -> io::Result<(Calendar, Logbook)> {
+    let path = settings.database_path();
+    let database = Database::open(&path)?;
+    info!("using database {}", path.display());
+
+    let mut calendar =
+        Calendar::with_clock(Box::new(database.calendar_store()), Arc::clone(&clock));
+    match database.read_calendar()? {
+        Some(source) => calendar.initialize(&mut source.as_bytes())?,
+        None => warn!("{} has no schedules, starting empty", path.display()),
+    }
+    if let Some(version) = calendar.migrated_from() {
+        info!("upgraded schedules in {} from version {}", path.display(), version);
+        calendar.save()?;
+    }
+
+    let mut logbook = Logbook::with_journal(
+        Box::new(database.logbook_store()),
+        Box::new(database.journal()),
+        clock,
+    );
+    match database.read_logbook()? {
+        Some(source) => logbook.initialize(&mut source.as_bytes())?,
+        None => {
+            logbook.replay_journal()?;
+        }
+    }
+    Ok((calendar, logbook))
+}
+
+/// Reads the logbook snapshot and journal from the SQLite database in the data directory.
+#[cfg(feature = "sqlite")]
+fn read_database_logbook(settings: &Settings) -> io::Result<(Option<Vec<u8>>, Vec<u8>)> {
+    use super::file_store::Journal;
+
+    let database = Database::open(&settings.database_path())?;
+    let snapshot = database.read_logbook()?.map(String::into_bytes);
+    Ok((snapshot, database.journal().contents()?))
+}
+
+#[cfg(not(feature = "sqlite"))]
+fn read_database_logbook(_settings: &Settings) -> io::Result<(Option<Vec<u8>>, Vec<u8>)> {
+    Err(sqlite_unsupported())
+}
+
+#[cfg(not(feature = "sqlite"))]
+fn load_database(_clock: Arc<dyn Clock>, _settings: &Settings) -> io::Result<(Calendar, Logbook)> {
+    Err(sqlite_unsupported())
+}
+
+#[cfg(not(feature = "sqlite"))]
+fn sqlite_unsupported() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        "sqlite storage needs rustic-garden built with the sqlite feature",
+    )
+}
+
+/// Creates a leak detector with the calendar's thresholds and zone limits.
+fn leak_detector(calendar: &Calendar) -> LeakDetector {
+    let zone_limits = calendar
+        .valves()
+        .filter_map(|valve| valve.max_flow_lpm.map(|limit| (valve.name.clone(), limit)))
+        .collect();
+    LeakDetector::new(calendar.leak_detection().clone(), zone_limits)
+}
+
+fn read_if_exists(path: &Path) -> io::Result<Option<Vec<u8>>> {
+    match fs::read(path) {
+        Ok(contents) => Ok(Some(contents)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+fn open_if_exists(path: &Path) -> io::Result<Option<impl Read>> {
+    match File::open(path) {
+        Ok(file) => Ok(Some(file)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            warn!("{} does not exist, starting empty", path.display());
+            Ok(None)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::calendar::DeletePolicy;
+    use crate::clock::SystemClock;
+    use crate::config_persist::ValvePersist;
+    use crate::logbook::{RunOutcome, Trigger};
+    use crate::scratch::ScratchDir;
+    use crate::valve::{Backend, ValveState};
+
+    #[test]
+    fn valve_changes_reach_hardware() {
+        let calendar = Calendar::new(Box::new(Vec::<u8>::new()));
+        let logbook = Logbook::new(Box::new(Vec::<u8>::new()));
+        let mut state = GardenState::new(calendar, logbook, Valves::with_backend(Backend::Mock));
+
+        state
+            .calendar
+            .create_or_replace_valve(ValvePersist {
+                name: String::from("beds"),
+                pin: 22,
+                max_flow_lpm: None,
+                nominal_flow_lpm: None,
+            })
+            .expect("create valve succeeds");
+        state.apply_config_changes();
+        assert_eq!(state.valves.get("beds").expect("registered").pin(), 22);
+
+        state
+            .calendar
+            .delete_valve("beds", DeletePolicy::Fail)
+            .expect("delete valve succeeds");
+        state.apply_config_changes();
+        assert!(state.valves.get("beds").is_none());
+    }
+
+    #[test]
+    fn dry_run_never_exports_pins() {
+        let data_dir = ScratchDir::new("dry-run");
+        let settings = Settings {
+            data_dir: data_dir.path().to_path_buf(),
+            dry_run: true,
+            ..Settings::default()
+        };
+        fs::write(
+            settings.schedule_path(),
+            "version: \"0.2\"\nvalves:\n  - name: beds\n    pin: 22\nflow_meter:\n  pin: 17\n  \
+             pulses_per_liter: 450\nschedules: []\n",
+        )
+        .expect("write succeeds");
+
+        let mut state = GardenState::load(Arc::new(SystemClock), &settings).expect("load succeeds");
+        let valve = state.valves.get_mut("beds").expect("registered");
+        valve.open().expect("open succeeds");
+        assert!(matches!(valve.get_state(), Ok(ValveState::Open)));
+        state
+            .logbook
+            .mark_started_by("by hand", &[], Trigger::Schedule)
+            .expect("mark succeeds");
+
+        assert!(state.is_dry_run());
+        assert!(state.logbook.iter().all(|record| record.dry_run));
+    }
+
+    #[test]
+    fn leak_aborts_running_runs() {
+        let calendar = Calendar::new(Box::new(Vec::<u8>::new()));
+        let logbook = Logbook::new(Box::new(Vec::<u8>::new()));
+        let mut state = GardenState::new(calendar, logbook, Valves::with_backend(Backend::Mock));
+        state
+            .calendar
+            .create_or_replace_valve(ValvePersist {
+                name: String::from("beds"),
+                pin: 22,
+                max_flow_lpm: None,
+                nominal_flow_lpm: None,
+            })
+            .expect("create valve succeeds");
+        state.apply_config_changes();
+        let valves = vec![String::from("beds")];
+        let run = state
+            .logbook
+            .mark_started_by("morning", &valves, Trigger::Schedule)
+            .expect("mark succeeds");
+
+        state
+            .respond_to_alarm(AlarmKind::Leak, 12.0, valves)
+            .expect("respond succeeds");
+
+        let record = state.logbook.get(run).expect("record exists");
+        assert_eq!(record.outcome, Some(RunOutcome::Aborted));
+        assert!(record.completed.is_some());
+        assert!(state.logbook.iter_incomplete().next().is_none());
+        assert!(state.has_active_alarm());
+    }
+}
@@ -0,0 +1,387 @@
+use super::error::{self, Error};
+use super::logbook::{Record, RunOutcome, Trigger};
+use super::statistics::csv_field;
+use chrono::{DateTime, FixedOffset};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::path::Path;
+
+const CSV_HEADER: &str = "schedule,started,completed,liters,valves,dry_run,outcome,trigger,error";
+
+/// The file formats logbook history is exported in.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    /// One run per line, `CSV_HEADER` first. Valves are separated by `;`, the liters through each
+    /// valve and the duration are left out. Quoted fields may hold line breaks.
+    Csv,
+
+    /// One JSON object per line, keeping every field.
+    JsonLines,
+}
+
+impl Format {
+    /// The format a file is in judging by its extension, `.csv` or `.jsonl`.
+    pub fn of_path(path: &Path) -> Option<Format> {
+        match path.extension()?.to_str()? {
+            "csv" => Some(Format::Csv),
+            "jsonl" => Some(Format::JsonLines),
+            _ => None,
+        }
+    }
+}
+
+impl std::str::FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Format, String> {
+        match s {
+            "csv" => Ok(Format::Csv),
+            "jsonl" => Ok(Format::JsonLines),
+            _ => Err(format!("unknown format {}, expected csv or jsonl", s)),
+        }
+    }
+}
+
+/// A finished run as exported, with ISO 8601 timestamps.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct HistoryRecord {
+    pub schedule: String,
+    pub started: DateTime<FixedOffset>,
+    pub completed: DateTime<FixedOffset>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub liters: Option<f64>,
+
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub valve_liters: BTreeMap<String, f64>,
+
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub valves: Vec<String>,
+
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub dry_run: bool,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub outcome: Option<RunOutcome>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub trigger: Option<Trigger>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub duration_secs: Option<i64>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl HistoryRecord {
+    /// Converts a logbook record, or returns `None` if it is still running or its times cannot
+    /// be read.
+    pub fn from_record(record: &Record) -> Option<HistoryRecord> {
+        Some(HistoryRecord {
+            schedule: record.name.clone(),
+            started: record.started_at()?,
+            completed: record.completed_at()?,
+            liters: record.liters,
+            valve_liters: record.valve_liters.clone(),
+            valves: record.valves.clone(),
+            dry_run: record.dry_run,
+            outcome: record.outcome(),
+            trigger: Some(record.trigger()),
+            duration_secs: record.duration().map(|duration| duration.num_seconds()),
+            error: record.error.clone(),
+        })
+    }
+
+    /// Converts back to a logbook record.
+    pub fn into_record(self) -> Record {
+        Record {
+            id: None,
+            name: self.schedule,
+            started: Some(self.started.to_rfc2822()),
+            completed: Some(self.completed.to_rfc2822()),
+            liters: self.liters,
+            valve_liters: self.valve_liters,
+            dry_run: self.dry_run,
+            valves: self.valves,
+            outcome: self.outcome,
+            trigger: self.trigger,
+            duration_secs: self.duration_secs,
+            error: self.error,
+        }
+    }
+
+    fn write_csv(&self, out: &mut dyn Write) -> io::Result<()> {
+        writeln!(
+            out,
+            "{},{},{},{},{},{},{},{},{}",
+            csv_field(&self.schedule),
+            self.started.to_rfc3339(),
+            self.completed.to_rfc3339(),
+            self.liters.map_or(String::new(), |liters| liters.to_string()),
+            csv_field(&self.valves.join(";")),
+            self.dry_run,
+            self.outcome.map_or(String::new(), |outcome| outcome.to_string()),
+            self.trigger.map_or(String::new(), |trigger| trigger.to_string()),
+            csv_field(self.error.as_deref().unwrap_or_default()),
+        )
+    }
+
+    fn parse_csv(line: &str) -> Result<HistoryRecord, String> {
+        let fields = split_csv(line)?;
+        let columns = CSV_HEADER.split(',').count();
+        if fields.len() != columns {
+            return Err(format!("expected {} fields, found {}", columns, fields.len()));
+        }
+        let time = |field: &str| {
+            DateTime::parse_from_rfc3339(field).map_err(|e| format!("invalid time {}: {}", field, e))
+        };
+        let flag = |field: &str| match field {
+            "true" => Ok(true),
+            "false" | "" => Ok(false),
+            _ => Err(format!("invalid flag {}", field)),
+        };
+        Ok(HistoryRecord {
+            schedule: fields[0].clone(),
+            started: time(&fields[1])?,
+            completed: time(&fields[2])?,
+            liters: match fields[3].as_str() {
+                "" => None,
+                liters => Some(liters.parse().map_err(|_| format!("invalid liters {}", liters))?),
+            },
+            valve_liters: BTreeMap::new(),
+            valves: fields[4]
+                .split(';')
+                .filter(|valve| !valve.is_empty())
+                .map(String::from)
+                .collect(),
+            dry_run: flag(&fields[5])?,
+            outcome: parse_optional(&fields[6])?,
+            trigger: parse_optional(&fields[7])?,
+            duration_secs: None,
+            error: Some(fields[8].clone()).filter(|error| !error.is_empty()),
+        })
+    }
+}
+
+/// Writes the finished runs among `records` in `format`, returning how many were written.
+pub fn export<'a, I>(records: I, format: Format, out: &mut dyn Write) -> io::Result<usize>
+where
+    I: IntoIterator<Item = &'a Record>,
+{
+    if format == Format::Csv {
+        writeln!(out, "{}", CSV_HEADER)?;
+    }
+    let mut written = 0;
+    for record in records.into_iter().filter_map(HistoryRecord::from_record) {
+        match format {
+            Format::Csv => record.write_csv(out)?,
+            Format::JsonLines => writeln!(out, "{}", serde_json::to_string(&record)?)?,
+        }
+        written += 1;
+    }
+    Ok(written)
+}
+
+/// Reads runs exported in `format`.
+pub fn import(source: &mut dyn Read, format: Format) -> error::Result<Vec<Record>> {
+    let mut records = Vec::new();
+    let mut lines = BufReader::new(source).lines().enumerate();
+    while let Some((index, line)) = lines.next() {
+        let mut line = line?;
+        // A quoted CSV field can hold line breaks, the run goes on until its quotes are closed
+        while format == Format::Csv && line.matches('"').count() % 2 == 1 {
+            match lines.next() {
+                Some((_, next)) => {
+                    line.push('\n');
+                    line.push_str(&next?);
+                }
+                None => break,
+            }
+        }
+        // A header is skipped
+        let header = format == Format::Csv && index == 0 && line.starts_with("schedule,");
+        if line.trim().is_empty() || header {
+            continue;
+        }
+        let record = match format {
+            Format::Csv => HistoryRecord::parse_csv(&line),
+            Format::JsonLines => serde_json::from_str(&line).map_err(|e| e.to_string()),
+        };
+        let record = record.map_err(|message| Error::parse_at(index + 1, message))?;
+        records.push(record.into_record());
+    }
+    Ok(records)
+}
+
+/// Parses a field that may be left empty.
+fn parse_optional<T>(field: &str) -> Result<Option<T>, String>
+where
+    T: std::str::FromStr<Err = String>,
+{
+    match field {
+        "" => Ok(None),
+        field => field.parse().map(Some),
+    }
+}
+
+/// Splits a CSV line into fields, unquoting them.
+fn split_csv(line: &str) -> Result<Vec<String>, String> {
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (c, quoted) {
+            ('"', true) if chars.peek() == Some(&'"') => {
+                field.push('"');
+                chars.next();
+            }
+            ('"', true) => quoted = false,
+            ('"', false) if field.is_empty() => quoted = true,
+            (',', false) => fields.push(std::mem::take(&mut field)),
+            (c, _) => field.push(c),
+        }
+    }
+    if quoted {
+        return Err(String::from("unterminated quote"));
+    }
+    fields.push(field);
+    Ok(fields)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(name: &str, started: &str, completed: Option<&str>) -> Record {
+        let mut record = Record::from(name);
+        record.started = Some(String::from(started));
+        record.completed = completed.map(String::from);
+        record
+    }
+
+    /// Compares times by instant, chrono versions differ in how RFC 2822 pads the day.
+    fn assert_imported(imported: Vec<Record>, expected: Vec<Record>) {
+        let times = |records: &[Record]| -> Vec<_> {
+            records
+                .iter()
+                .map(|record| (record.started_at(), record.completed_at()))
+                .collect()
+        };
+        assert_eq!(times(&imported), times(&expected));
+
+        let without_times = |records: Vec<Record>| -> Vec<Record> {
+            records
+                .into_iter()
+                .map(|mut record| {
+                    record.started = None;
+                    record.completed = None;
+                    record
+                })
+                .collect()
+        };
+        assert_eq!(without_times(imported), without_times(expected));
+    }
+
+    #[test]
+    fn exports_finished_runs_with_iso_timestamps() {
+        let mut beds = record(
+            "beds, east",
+            "Mon, 01 Jun 2020 07:00:00 -0700",
+            Some("Mon, 01 Jun 2020 07:30:00 -0700"),
+        );
+        beds.valves = vec![String::from("beds valve"), String::from("drip")];
+        beds.liters = Some(42.5);
+        beds.outcome = Some(RunOutcome::Interrupted);
+        beds.error = Some(String::from("a \"leak\""));
+        let running = record("pots", "Mon, 01 Jun 2020 08:00:00 -0700", None);
+
+        let mut csv = Vec::new();
+        let written = export(&[beds.clone(), running], Format::Csv, &mut csv).expect("export succeeds");
+
+        assert_eq!(written, 1);
+        assert_eq!(
+            String::from_utf8(csv.clone()).expect("CSV is UTF-8"),
+            format!(
+                "{}\n\"beds, east\",2020-06-01T07:00:00-07:00,2020-06-01T07:30:00-07:00,42.5,\
+                 beds valve;drip,false,interrupted,schedule,\"a \"\"leak\"\"\"\n",
+                CSV_HEADER
+            )
+        );
+        beds.trigger = Some(Trigger::Schedule);
+        assert_imported(
+            import(&mut csv.as_slice(), Format::Csv).expect("import succeeds"),
+            vec![beds],
+        );
+    }
+
+    #[test]
+    fn csv_fields_keep_line_breaks() {
+        let mut beds = record(
+            "beds",
+            "Mon, 01 Jun 2020 07:00:00 -0700",
+            Some("Mon, 01 Jun 2020 07:30:00 -0700"),
+        );
+        beds.outcome = Some(RunOutcome::Failed);
+        beds.trigger = Some(Trigger::Schedule);
+        beds.error = Some(String::from("valve stuck\n\"beds valve\" did not open"));
+        let mut pots = record(
+            "pots",
+            "Mon, 01 Jun 2020 18:00:00 -0700",
+            Some("Mon, 01 Jun 2020 18:10:00 -0700"),
+        );
+        pots.outcome = Some(RunOutcome::Completed);
+        pots.trigger = Some(Trigger::Schedule);
+
+        let mut csv = Vec::new();
+        export(&[beds.clone(), pots.clone()], Format::Csv, &mut csv).expect("export succeeds");
+
+        assert_imported(
+            import(&mut csv.as_slice(), Format::Csv).expect("import succeeds"),
+            vec![beds, pots],
+        );
+    }
+
+    #[test]
+    fn json_lines_keep_every_field() {
+        let mut pots = record(
+            "pots",
+            "Mon, 01 Jun 2020 18:00:00 +0000",
+            Some("Mon, 01 Jun 2020 18:10:00 +0000"),
+        );
+        pots.valve_liters.insert(String::from("pots valve"), 12.5);
+        pots.trigger = Some(Trigger::Manual);
+        pots.error = Some(String::from("valve stuck"));
+
+        let mut jsonl = Vec::new();
+        export(&[pots.clone()], Format::JsonLines, &mut jsonl).expect("export succeeds");
+
+        assert_eq!(
+            String::from_utf8(jsonl.clone()).expect("JSON is UTF-8"),
+            "{\"schedule\":\"pots\",\"started\":\"2020-06-01T18:00:00+00:00\",\
+             \"completed\":\"2020-06-01T18:10:00+00:00\",\"valve_liters\":{\"pots valve\":12.5},\
+             \"outcome\":\"completed\",\"trigger\":\"manual\",\
+             \"duration_secs\":600,\"error\":\"valve stuck\"}\n"
+        );
+        pots.outcome = Some(RunOutcome::Completed);
+        pots.trigger = Some(Trigger::Manual);
+        pots.duration_secs = Some(600);
+        assert_imported(
+            import(&mut jsonl.as_slice(), Format::JsonLines).expect("import succeeds"),
+            vec![pots],
+        );
+    }
+
+    #[test]
+    fn import_reports_the_bad_line() {
+        let csv = format!("{}\npots,yesterday,today,,,false,,,\n", CSV_HEADER);
+
+        let error = import(&mut csv.as_bytes(), Format::Csv).expect_err("import fails");
+
+        assert!(error.to_string().starts_with("line 2: invalid time yesterday"));
+        assert!(split_csv("\"unterminated").is_err());
+    }
+}
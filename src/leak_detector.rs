@@ -0,0 +1,138 @@
+extern crate log;
+
+use super::config_persist::LeakDetectionPersist;
+use super::logbook::AlarmKind;
+use log::warn;
+use std::collections::HashMap;
+
+/// Watches flow readings for leaks, stuck valves and broken pipes.
+///
+/// A reading is abnormal if water flows while every zone is closed, or if it flows faster than
+/// the open zones allow. An alarm is only reported after `confirm_samples` consecutive abnormal
+/// readings of the same kind, so a zone draining after it closes does not trip it.
+pub struct LeakDetector {
+    settings: LeakDetectionPersist,
+    zone_limits: HashMap<String, f64>,
+    suspect: Option<AlarmKind>,
+    suspect_samples: u32,
+}
+
+impl LeakDetector {
+    /// Creates a new detector with the given maximum flow rate for each zone.
+    pub fn new(settings: LeakDetectionPersist, zone_limits: HashMap<String, f64>) -> LeakDetector {
+        LeakDetector {
+            settings,
+            zone_limits,
+            suspect: None,
+            suspect_samples: 0,
+        }
+    }
+
+    /// Checks a flow reading against the zones that were open while it was taken.
+    ///
+    /// Returns the kind of alarm to raise once abnormal flow has been confirmed.
+    pub fn check(&mut self, liters_per_minute: f64, open_zones: &[String]) -> Option<AlarmKind> {
+        let abnormal = self.classify(liters_per_minute, open_zones);
+
+        if abnormal.is_none() || abnormal != self.suspect {
+            self.suspect = abnormal;
+            self.suspect_samples = 0;
+        }
+        let kind = abnormal?;
+
+        self.suspect_samples += 1;
+        warn!(
+            "abnormal flow {:?} of {:.2}L/min ({}/{})",
+            kind, liters_per_minute, self.suspect_samples, self.settings.confirm_samples
+        );
+        if self.suspect_samples < self.settings.confirm_samples {
+            return None;
+        }
+
+        self.suspect = None;
+        self.suspect_samples = 0;
+        Some(kind)
+    }
+
+    fn classify(&self, liters_per_minute: f64, open_zones: &[String]) -> Option<AlarmKind> {
+        if open_zones.is_empty() {
+            if liters_per_minute > self.settings.idle_max_lpm {
+                return Some(AlarmKind::Leak);
+            }
+            return None;
+        }
+
+        // Only judge high flow when every open zone has a known limit
+        let mut limit = 0.0;
+        for zone in open_zones {
+            limit += self.zone_limits.get(zone)?;
+        }
+
+        if liters_per_minute > limit {
+            Some(AlarmKind::HighFlow)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flow_with_all_zones_closed_is_a_leak() {
+        let mut d = detector(1);
+
+        assert_eq!(d.check(2.0, &[]), Some(AlarmKind::Leak));
+    }
+
+    #[test]
+    fn trickle_with_all_zones_closed_is_tolerated() {
+        let mut d = detector(1);
+
+        assert_eq!(d.check(0.2, &[]), None);
+    }
+
+    #[test]
+    fn flow_above_open_zone_limits_is_high_flow() {
+        let mut d = detector(1);
+
+        assert_eq!(d.check(15.0, &zones(&["a", "b"])), None);
+        assert_eq!(d.check(25.0, &zones(&["a", "b"])), Some(AlarmKind::HighFlow));
+    }
+
+    #[test]
+    fn high_flow_ignored_for_zone_without_limit() {
+        let mut d = detector(1);
+
+        assert_eq!(d.check(100.0, &zones(&["a", "unlimited"])), None);
+    }
+
+    #[test]
+    fn alarm_requires_consecutive_samples() {
+        let mut d = detector(3);
+
+        assert_eq!(d.check(2.0, &[]), None);
+        assert_eq!(d.check(2.0, &[]), None);
+        assert_eq!(d.check(0.0, &[]), None);
+        assert_eq!(d.check(2.0, &[]), None);
+        assert_eq!(d.check(2.0, &[]), None);
+        assert_eq!(d.check(2.0, &[]), Some(AlarmKind::Leak));
+    }
+
+    fn detector(confirm_samples: u32) -> LeakDetector {
+        let settings = LeakDetectionPersist {
+            idle_max_lpm: 0.5,
+            confirm_samples,
+        };
+        let mut limits = HashMap::new();
+        limits.insert(String::from("a"), 12.0);
+        limits.insert(String::from("b"), 8.0);
+        LeakDetector::new(settings, limits)
+    }
+
+    fn zones(names: &[&str]) -> Vec<String> {
+        names.iter().map(|n| String::from(*n)).collect()
+    }
+}
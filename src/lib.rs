@@ -0,0 +1,37 @@
+extern crate log;
+extern crate sysfs_gpio;
+
+#[cfg(test)]
+#[macro_use]
+extern crate static_assertions;
+
+pub mod constants;
+pub mod logbook;
+pub mod logbook_query;
+pub mod valve;
+pub mod calendar;
+pub mod clock;
+pub mod cli;
+pub mod conflicts;
+pub mod ctl;
+pub mod config_persist;
+pub mod config_watcher;
+pub mod environment;
+pub mod error;
+pub mod file_store;
+pub mod flow_meter;
+pub mod garden;
+pub mod history;
+pub mod leak_detector;
+pub mod lint;
+pub mod migrations;
+pub mod protocol;
+#[cfg(test)]
+mod scratch;
+pub mod settings;
+pub mod simulation;
+#[cfg(feature = "sqlite")]
+pub mod sqlite_store;
+pub mod statistics;
+pub mod table;
+pub mod taskmaster;
@@ -0,0 +1,478 @@
+use super::calendar::Calendar;
+use super::constants::MINUTES_PER_DAY;
+use super::error::Error;
+use super::logbook::Logbook;
+use serde_yaml::Value;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::io;
+
+/// How serious a problem found by a check is.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Severity {
+    /// The file will not load, or will not water as intended.
+    Error,
+
+    /// The file loads, but something looks unintended.
+    Warning,
+}
+
+/// A problem found in a file, at a 1-based line and column.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let severity = match self.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        };
+        write!(f, "{}:{}: {}: {}", self.line, self.column, severity, self.message)
+    }
+}
+
+/// Checks a schedule file, returning the calendar it describes if it loads.
+///
+/// Looks for YAML mistakes, values of the wrong type, schedules using unknown valves, pins used
+/// twice and periods that cannot work.
+pub fn check_schedules(source: &str) -> (Vec<Diagnostic>, Option<Calendar>) {
+    let locator = Locator::new(source);
+    let mut diagnostics = trailing_commas(source);
+
+    if let Err(e) = serde_yaml::from_str::<Value>(source) {
+        diagnostics.push(yaml_error(&e));
+        return (diagnostics, None);
+    }
+
+    let mut calendar = Calendar::new(Box::new(io::sink()));
+    if let Err(e) = calendar.initialize(&mut source.as_bytes()) {
+        // The trailing commas already explain why values have the wrong type
+        if diagnostics.is_empty() {
+            diagnostics.push(load_error(&locator, &e));
+        }
+        return (diagnostics, None);
+    }
+
+    if let Some(version) = calendar.migrated_from() {
+        let (line, column) = locator.find(0, "version:").unwrap_or((1, 1));
+        diagnostics.push(warning(
+            line,
+            column,
+            format!("written for schema version {}, the daemon will upgrade it", version),
+        ));
+    }
+    check_valves(&calendar, &locator, &mut diagnostics);
+    check_schedule_timing(&calendar, &locator, &mut diagnostics);
+
+    diagnostics.sort_by_key(|d| (d.line, d.column));
+    (diagnostics, Some(calendar))
+}
+
+/// Checks a logbook file, comparing it to the calendar if one loaded.
+pub fn check_logbook(source: &str, calendar: Option<&Calendar>) -> Vec<Diagnostic> {
+    let locator = Locator::new(source);
+    let mut diagnostics = Vec::new();
+
+    if let Err(e) = serde_yaml::from_str::<Value>(source) {
+        diagnostics.push(yaml_error(&e));
+        return diagnostics;
+    }
+
+    let mut logbook = Logbook::new(Box::new(io::sink()));
+    if let Err(e) = logbook.initialize(&mut source.as_bytes()) {
+        diagnostics.push(load_error(&locator, &e));
+        return diagnostics;
+    }
+
+    let mut incomplete: BTreeMap<&str, usize> = BTreeMap::new();
+    let mut unknown: BTreeSet<&str> = BTreeSet::new();
+    for record in logbook.iter() {
+        let at = |text: &Option<String>| {
+            text.as_ref()
+                .and_then(|t| locator.find(0, t))
+                .or_else(|| locator.find(0, &format!("name: {}", record.name)))
+                .unwrap_or((1, 1))
+        };
+
+        let started = record.started_at();
+        if started.is_none() {
+            let (line, column) = at(&record.started);
+            diagnostics.push(error(
+                line,
+                column,
+                format!("{} has an unreadable start time {:?}", record.name, record.started),
+            ));
+        }
+        match (record.completed.as_ref(), record.completed_at()) {
+            (None, _) => *incomplete.entry(&record.name).or_insert(0) += 1,
+            (Some(_), None) => {
+                let (line, column) = at(&record.completed);
+                diagnostics.push(error(
+                    line,
+                    column,
+                    format!("{} has an unreadable completion time", record.name),
+                ));
+            }
+            (Some(_), Some(completed)) => {
+                if started.is_some_and(|started| completed < started) {
+                    let (line, column) = at(&record.completed);
+                    diagnostics.push(error(
+                        line,
+                        column,
+                        format!("{} completed before it started", record.name),
+                    ));
+                }
+            }
+        }
+
+        if let Some(calendar) = calendar {
+            if calendar.get(&record.name).is_none() && unknown.insert(&record.name) {
+                let (line, column) = at(&None);
+                diagnostics.push(warning(
+                    line,
+                    column,
+                    format!("{} is not a schedule in the calendar", record.name),
+                ));
+            }
+        }
+    }
+
+    for (name, count) in incomplete.into_iter().filter(|(_, count)| *count > 1) {
+        diagnostics.push(warning(
+            1,
+            1,
+            format!("{} has {} unfinished runs, only the latest is resumed", name, count),
+        ));
+    }
+
+    diagnostics.sort_by_key(|d| (d.line, d.column));
+    diagnostics
+}
+
+/// Reports values followed by a comma, a JSON habit that YAML reads as part of the value.
+fn trailing_commas(source: &str) -> Vec<Diagnostic> {
+    let mut diagnostics = Vec::new();
+    for (index, line) in source.lines().enumerate() {
+        let content = line.split(" #").next().unwrap_or(line).trim_end();
+        if !content.ends_with(',') || content.contains('[') || content.contains('{') {
+            continue;
+        }
+        let value = match content.split_once(": ") {
+            Some((_, value)) => value.trim(),
+            None => continue,
+        };
+        diagnostics.push(error(
+            index + 1,
+            content.len(),
+            format!(
+                "trailing comma after {}, YAML does not separate values with commas",
+                value.trim_end_matches(',')
+            ),
+        ));
+    }
+    diagnostics
+}
+
+fn check_valves(calendar: &Calendar, locator: &Locator, diagnostics: &mut Vec<Diagnostic>) {
+    let valves_line = locator.find(0, "valves:").map_or(0, |(line, _)| line);
+    let schedules_line = locator.find(0, "schedules:").map_or(0, |(line, _)| line);
+    let valve_at = |name: &str| {
+        locator
+            .find(valves_line, &format!("name: {}", name))
+            .unwrap_or((1, 1))
+    };
+
+    let mut names = BTreeSet::new();
+    let mut pins: BTreeMap<u64, String> = BTreeMap::new();
+    if let Some(meter) = calendar.flow_meter() {
+        pins.insert(meter.pin, String::from("the flow meter"));
+    }
+    for valve in calendar.valves() {
+        let (line, column) = valve_at(&valve.name);
+        if !names.insert(valve.name.as_str()) {
+            diagnostics.push(error(line, column, format!("valve {} is defined twice", valve.name)));
+        }
+        if let Some(other) = pins.insert(valve.pin, format!("valve {}", valve.name)) {
+            diagnostics.push(error(
+                line,
+                column,
+                format!("valve {} uses pin {}, already used by {}", valve.name, valve.pin, other),
+            ));
+        }
+    }
+
+    if let Some(master) = calendar.master_valve() {
+        if !names.contains(master) {
+            let (line, column) = locator.find(0, "master_valve:").unwrap_or((1, 1));
+            diagnostics.push(error(line, column, format!("unknown master valve {}", master)));
+        }
+    }
+
+    for schedule in calendar.list() {
+        let schedule_line = locator
+            .find(schedules_line, &format!("name: {}", schedule.name()))
+            .map_or(schedules_line, |(line, _)| line);
+        for valve in schedule.valves().iter().filter(|v| !names.contains(v.as_str())) {
+            let (line, column) = locator
+                .find(schedule_line, valve)
+                .unwrap_or((schedule_line.max(1), 1));
+            diagnostics.push(error(
+                line,
+                column,
+                format!("schedule {} uses unknown valve {}", schedule.name(), valve),
+            ));
+        }
+        if schedule.valves().is_empty() {
+            diagnostics.push(warning(
+                schedule_line.max(1),
+                1,
+                format!("schedule {} opens no valves", schedule.name()),
+            ));
+        }
+    }
+}
+
+fn check_schedule_timing(calendar: &Calendar, locator: &Locator, diagnostics: &mut Vec<Diagnostic>) {
+    let schedules_line = locator.find(0, "schedules:").map_or(0, |(line, _)| line);
+    let mut names = BTreeSet::new();
+
+    for schedule in calendar.list() {
+        let name = schedule.name();
+        let schedule_line = locator
+            .find(schedules_line, &format!("name: {}", name))
+            .map_or(schedules_line, |(line, _)| line);
+        let field_at = |field: &str| {
+            locator
+                .find(schedule_line, &format!("{}:", field))
+                .unwrap_or((schedule_line.max(1), 1))
+        };
+
+        if !names.insert(String::from(name)) {
+            let (line, column) = field_at("name");
+            diagnostics.push(error(line, column, format!("schedule {} is defined twice", name)));
+        }
+
+        let period_min = schedule.repeat_period_days() * MINUTES_PER_DAY as u64;
+        if schedule.repeat_period_days() == 0 {
+            let (line, column) = field_at("repeat_period_days");
+            diagnostics.push(error(
+                line,
+                column,
+                format!("schedule {} has a zero repeat period and never runs", name),
+            ));
+        } else if schedule.duration_min() >= period_min {
+            let (line, column) = field_at("duration_min");
+            diagnostics.push(error(
+                line,
+                column,
+                format!("schedule {} runs longer than its repeat period", name),
+            ));
+        }
+        if schedule.duration_min() == 0 {
+            let (line, column) = field_at("duration_min");
+            diagnostics.push(warning(line, column, format!("schedule {} runs for zero minutes", name)));
+        }
+
+        let mut starts = schedule.start_offsets_min().to_vec();
+        starts.sort_unstable();
+        let (line, column) = field_at("start_offsets_min");
+        if starts.is_empty() {
+            diagnostics.push(error(line, column, format!("schedule {} has no start times", name)));
+        }
+        if starts.iter().any(|start| *start >= MINUTES_PER_DAY as u64) {
+            diagnostics.push(warning(
+                line,
+                column,
+                format!("schedule {} starts more than a day after midnight", name),
+            ));
+        }
+        if starts.windows(2).any(|pair| pair[1] - pair[0] < schedule.duration_min()) {
+            diagnostics.push(warning(
+                line,
+                column,
+                format!("schedule {} starts again before its previous run ends", name),
+            ));
+        }
+    }
+}
+
+/// Finds where things are written in a file, for pointing diagnostics at them.
+struct Locator<'a> {
+    lines: Vec<&'a str>,
+}
+
+impl<'a> Locator<'a> {
+    fn new(source: &'a str) -> Locator<'a> {
+        Locator {
+            lines: source.lines().collect(),
+        }
+    }
+
+    /// Finds the first line after line `after` (1-based, 0 for the whole file) where `needle`
+    /// ends the line's content, and returns the line and column where `needle` starts.
+    fn find(&self, after: usize, needle: &str) -> Option<(usize, usize)> {
+        self.lines
+            .iter()
+            .enumerate()
+            .skip(after)
+            .find_map(|(index, line)| {
+                let column = line.find(needle)?;
+                let rest = line[column + needle.len()..].trim_start_matches(['"', '\'']);
+                let rest = rest.trim();
+                if needle.ends_with(':') || rest.is_empty() || rest.starts_with('#') {
+                    Some((index + 1, column + 1))
+                } else {
+                    None
+                }
+            })
+    }
+}
+
+fn yaml_error(e: &serde_yaml::Error) -> Diagnostic {
+    let (line, column) = match e.location() {
+        Some(location) => (location.line(), location.column()),
+        None => (1, 1),
+    };
+    error(line, column, e.to_string())
+}
+
+/// Points a load error at where it was found, or else at the value it complains about if the
+/// message quotes one.
+fn load_error(locator: &Locator, e: &Error) -> Diagnostic {
+    let message = e.to_string();
+    if let Error::Parse {
+        line: Some(line),
+        column,
+        ..
+    } = e
+    {
+        return error(*line, column.unwrap_or(1), message);
+    }
+    let quoted = message.split('"').nth(1).filter(|q| !q.is_empty());
+    let (line, column) = quoted
+        .and_then(|value| locator.find(0, value))
+        .unwrap_or((1, 1));
+    error(line, column, message)
+}
+
+fn error(line: usize, column: usize, message: String) -> Diagnostic {
+    Diagnostic {
+        severity: Severity::Error,
+        line,
+        column,
+        message,
+    }
+}
+
+fn warning(line: usize, column: usize, message: String) -> Diagnostic {
+    Diagnostic {
+        severity: Severity::Warning,
+        line,
+        column,
+        message,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const VALID: &str = r#"version: "0.2"
+valves:
+  - name: beds
+    pin: 22
+  - name: pots
+    pin: 23
+schedules:
+  - name: beds daily
+    start_offsets_min: [420]
+    duration_min: 30
+    repeat_period_days: 1
+    valves:
+      - beds
+"#;
+
+    #[test]
+    fn example_schedule_is_clean() {
+        let (diagnostics, calendar) = check_schedules(include_str!("../examples/schedule.yaml"));
+
+        assert_eq!(diagnostics, vec![]);
+        let logbook = include_str!("../examples/logbook.yaml");
+        assert_eq!(check_logbook(logbook, calendar.as_ref()), vec![]);
+    }
+
+    #[test]
+    fn trailing_commas_are_located() {
+        let source = VALID.replace("duration_min: 30", "duration_min: 30,");
+
+        let (diagnostics, calendar) = check_schedules(&source);
+
+        assert!(calendar.is_none());
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!((diagnostics[0].line, diagnostics[0].column), (10, 21));
+        assert!(diagnostics[0].message.contains("trailing comma"));
+    }
+
+    #[test]
+    fn yaml_syntax_error_is_located() {
+        let source = VALID.replace("    pin: 23", "   pin: 23");
+
+        let (diagnostics, _) = check_schedules(&source);
+
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].line, 6);
+    }
+
+    #[test]
+    fn unknown_valve_and_shared_pin_are_errors() {
+        let source = VALID
+            .replace("      - beds", "      - lawn")
+            .replace("pin: 23", "pin: 22");
+
+        let (diagnostics, _) = check_schedules(&source);
+
+        let found: Vec<(usize, &str)> = diagnostics
+            .iter()
+            .map(|d| (d.line, d.message.as_str()))
+            .collect();
+        assert_eq!(
+            found,
+            vec![
+                (5, "valve pots uses pin 22, already used by valve beds"),
+                (13, "schedule beds daily uses unknown valve lawn"),
+            ]
+        );
+    }
+
+    #[test]
+    fn zero_period_is_an_error() {
+        let source = VALID.replace("repeat_period_days: 1", "repeat_period_days: 0");
+
+        let (diagnostics, _) = check_schedules(&source);
+
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].severity, Severity::Error);
+        assert_eq!(diagnostics[0].line, 11);
+    }
+
+    #[test]
+    fn logbook_run_ending_before_start_is_an_error() {
+        let source = r#"records:
+  - name: beds daily
+    started: "Wed, 01 Jul 2020 07:00:00 -0700"
+    completed: "Wed, 01 Jul 2020 06:00:00 -0700"
+"#;
+        let (_, calendar) = check_schedules(VALID);
+
+        let diagnostics = check_logbook(source, calendar.as_ref());
+
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].line, 4);
+        assert!(diagnostics[0].message.contains("before it started"));
+    }
+}
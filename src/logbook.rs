@@ -1,94 +1,443 @@
 extern crate log;
 
-use chrono::Local;
-use log::{error, info};
+use super::clock::{Clock, SystemClock};
+use super::constants::{
+    JOURNAL_SNAPSHOT_EVENTS, RETENTION_DAYS, RETENTION_RECORDS_PER_SCHEDULE,
+};
+use super::error::{Error, Result};
+use super::file_store::{FileStore, Journal};
+use super::logbook_query::{LogEntry, Query};
+use chrono::{DateTime, Duration, FixedOffset, Local, Utc};
+use log::{error, info, warn};
 use serde::{Deserialize, Serialize};
 use serde_yaml::Value;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::fs::File;
 use std::io;
 use std::io::{BufReader, Read, Write};
+use std::path::Path;
+use std::sync::Arc;
 
 /// Structure for tracking runs and completions of schedules.
+///
+/// Without a journal every change rewrites the whole logbook. With a journal each change is
+/// appended to it as one line, and the whole logbook is only rewritten as a snapshot every
+/// `JOURNAL_SNAPSHOT_EVENTS` changes, when it is compacted, and after replaying the journal.
 pub struct Logbook {
     cache: LogbookData,
-    backing: Box<dyn Write>,
+    backing: Box<dyn Write + Send>,
+    journal: Option<Box<dyn Journal>>,
+    journal_events: usize,
+    clock: Arc<dyn Clock>,
+    dry_run: bool,
+    overlap: OverlapPolicy,
 }
 
 impl Logbook {
     /// Create a new, empty logbook.
-    pub fn new(backing: Box<dyn Write>) -> Self {
+    pub fn new(backing: Box<dyn Write + Send>) -> Self {
+        Logbook::with_clock(backing, Arc::new(SystemClock))
+    }
+
+    /// Create a new, empty logbook that timestamps records with the given clock.
+    pub fn with_clock(backing: Box<dyn Write + Send>, clock: Arc<dyn Clock>) -> Self {
         Logbook {
             cache: LogbookData::new(),
             backing,
+            journal: None,
+            journal_events: 0,
+            clock,
+            dry_run: false,
+            overlap: OverlapPolicy::default(),
+        }
+    }
+
+    /// Create a new, empty logbook that appends changes to `journal` and writes snapshots to
+    /// `backing`.
+    pub fn with_journal(
+        backing: Box<dyn Write + Send>,
+        journal: Box<dyn Journal>,
+        clock: Arc<dyn Clock>,
+    ) -> Self {
+        Logbook {
+            journal: Some(journal),
+            ..Logbook::with_clock(backing, clock)
         }
     }
 
-    /// Marks the schedule as started, recording the current time as the start time.
-    pub fn mark_started(&mut self, schedule_name: &String) -> io::Result<()> {
-        let now: String = Local::now().to_rfc2822();
+    /// Sets whether runs started from now on are marked as dry runs.
+    pub fn set_dry_run(&mut self, dry_run: bool) {
+        self.dry_run = dry_run;
+    }
+
+    /// Sets what happens when a schedule is started while a run of it is still in progress.
+    pub fn set_overlap_policy(&mut self, overlap: OverlapPolicy) {
+        self.overlap = overlap;
+    }
+
+    /// Marks the schedule as started because of `trigger`, opening `valves`, and returns the id
+    /// of the new run. The current time is recorded as the start time.
+    ///
+    /// If a run of the schedule is still in progress the overlap policy decides whether this
+    /// fails, the runs overlap, or the run in progress is aborted.
+    pub fn mark_started_by(
+        &mut self,
+        schedule_name: &str,
+        valves: &[String],
+        trigger: Trigger,
+    ) -> Result<RunId> {
+        let running: Vec<RunId> = self
+            .iter_incomplete()
+            .filter(|record| record.name == schedule_name)
+            .filter_map(|record| record.id)
+            .collect();
+        if !running.is_empty() {
+            match self.overlap {
+                OverlapPolicy::Reject => {
+                    return Err(Error::AlreadyRunning(String::from(schedule_name)));
+                }
+                OverlapPolicy::Allow => info!("{} is already running, overlapping", schedule_name),
+                OverlapPolicy::Replace => {
+                    for run in running {
+                        self.mark_aborted(run, "replaced by a new run")?;
+                    }
+                }
+            }
+        }
+
+        let id = self.cache.next_run_id();
+        let now: String = self.timestamp();
+
+        info!("marking {} as started at {} as run {}", schedule_name, now, id);
 
-        info!("marking {} as started at {}", schedule_name, now);
+        self.commit(Event::Started {
+            id: Some(id),
+            name: String::from(schedule_name),
+            at: now.clone(),
+            dry_run: self.dry_run,
+            valves: valves.to_vec(),
+            trigger: Some(trigger),
+        })?;
+        Ok(id)
+    }
 
-        let mut new_record = Record::new(schedule_name.clone());
-        new_record.started = Some(now.clone());
+    /// Marks the run as completed, recording the current time as the finish time.
+    pub fn mark_completed(&mut self, run: RunId) -> Result<()> {
+        let name = self.name_of(run)?;
+        let now: String = self.timestamp();
 
-        // Persist the new
-        self.cache.records.push(new_record);
+        info!("marking {} as completed at {}", name, now);
 
-        let result = self.sync();
-        info!("{} started at {}", schedule_name, now);
-        return result;
+        self.commit(Event::Completed {
+            id: Some(run),
+            name: name.clone(),
+            at: now.clone(),
+        })?;
+        info!("{} completed at {}", name, now);
+        Ok(())
     }
 
-    /// Marks the schedule as completed, recording the current time as the finish time.
-    pub fn mark_completed(&mut self, schedule_name: &String) -> io::Result<()> {
-        let now: String = Local::now().to_rfc2822();
+    /// Marks the run as cut short from outside, such as by the daemon stopping.
+    pub fn mark_interrupted(&mut self, run: RunId, reason: &str) -> Result<()> {
+        self.end(run, RunOutcome::Interrupted, reason)
+    }
 
-        info!("marking {} as completed at {}", schedule_name, now);
+    /// Marks the run as stopped on purpose before its end, such as by an alarm.
+    pub fn mark_aborted(&mut self, run: RunId, reason: &str) -> Result<()> {
+        self.end(run, RunOutcome::Aborted, reason)
+    }
 
-        // Find the record for start time
-        if let Some(record) = self.cache.find_most_recent_mut(schedule_name) {
-            // Ensure it was not already marked as complete
-            if let Some(v) = &record.completed {
-                error!(
-                    "record for {} was already completed at {}",
-                    schedule_name, v
-                );
-                return Err(io::Error::new(
-                    io::ErrorKind::InvalidInput,
-                    "already completed",
-                ));
-            }
+    /// Marks the run as ended by an error, such as a valve that would not open.
+    pub fn mark_failed(&mut self, run: RunId, error: &str) -> Result<()> {
+        self.end(run, RunOutcome::Failed, error)
+    }
 
-            // Persist the completion time
-            record.completed = Some(now.clone());
+    fn end(&mut self, run: RunId, outcome: RunOutcome, error: &str) -> Result<()> {
+        let name = self.name_of(run)?;
+        let now: String = self.timestamp();
 
-            let result = self.sync();
-            info!("{} completed at {}", schedule_name, now);
-            return result;
-        } else {
-            error!("no record for {} found, never started", schedule_name);
-            return Err(io::Error::new(io::ErrorKind::InvalidInput, "never started"));
+        warn!("{} {} at {}: {}", name, outcome, now, error);
+
+        self.commit(Event::Ended {
+            id: Some(run),
+            name,
+            at: now,
+            outcome,
+            error: Some(String::from(error)),
+        })
+    }
+
+    /// Records that the run of a schedule due at `due`, which would have opened `valves`, was not
+    /// started.
+    pub fn mark_skipped(
+        &mut self,
+        schedule_name: &str,
+        due: DateTime<FixedOffset>,
+        valves: &[String],
+        reason: &str,
+    ) -> Result<()> {
+        let now: String = self.timestamp();
+
+        info!("skipping {} due at {}: {}", schedule_name, due, reason);
+
+        self.commit(Event::Skipped(Skip {
+            name: String::from(schedule_name),
+            due: due.to_rfc2822(),
+            at: now,
+            reason: String::from(reason),
+            valves: valves.to_vec(),
+        }))
+    }
+
+    /// Returns `true` if the run of a schedule due at `due` was skipped.
+    pub fn was_skipped(&self, schedule_name: &str, due: DateTime<FixedOffset>) -> bool {
+        self.cache.skipped.iter().any(|skip| {
+            skip.name == schedule_name && skip.due_at() == Some(due)
+        })
+    }
+
+    /// Returns an iterator over the runs that were skipped.
+    pub fn iter_skipped(&self) -> impl Iterator<Item = &Skip> {
+        self.cache.skipped.iter()
+    }
+
+    /// Finds the runs and skipped runs matching `query`.
+    pub fn query(&self, query: &Query) -> Vec<LogEntry> {
+        query.run(self)
+    }
+
+    /// Adds water delivered through each valve to the record of a run in progress.
+    pub fn record_volume(&mut self, run: RunId, volumes: &[(String, f64)]) -> Result<()> {
+        self.commit(Event::Volume {
+            id: Some(run),
+            name: self.name_of(run)?,
+            liters: volumes.to_vec(),
+        })
+    }
+
+    /// Finds the record of a run by id.
+    pub fn get(&self, run: RunId) -> Option<&Record> {
+        self.cache.records.iter().rfind(|record| record.id == Some(run))
+    }
+
+    /// Records a new alarm, unless an unacknowledged alarm of the same kind is already active.
+    ///
+    /// Returns `true` if a new alarm was recorded.
+    pub fn raise_alarm(
+        &mut self,
+        kind: AlarmKind,
+        liters_per_minute: f64,
+        valves: Vec<String>,
+    ) -> Result<bool> {
+        if self.iter_active_alarms().any(|(_, alarm)| alarm.kind == kind) {
+            return Ok(false);
         }
+
+        let now: String = self.timestamp();
+        error!(
+            "{:?} alarm at {}: {:.2}L/min through {:?}",
+            kind, now, liters_per_minute, valves
+        );
+
+        self.commit(Event::Alarm(Alarm {
+            kind,
+            detected: now,
+            liters_per_minute,
+            valves,
+            acknowledged: None,
+        }))?;
+        Ok(true)
+    }
+
+    /// Acknowledges an active alarm by id, clearing it.
+    pub fn acknowledge_alarm(&mut self, id: usize) -> Result<()> {
+        let now: String = self.timestamp();
+        self.commit(Event::Acknowledged {
+            id,
+            at: now.clone(),
+        })?;
+        info!("alarm {} acknowledged at {}", id, now);
+        Ok(())
+    }
+
+    /// Returns an iterator over all alarms, acknowledged or not.
+    pub fn iter_alarms(&self) -> impl Iterator<Item = &Alarm> {
+        self.cache.alarms.iter()
+    }
+
+    /// Returns an iterator over the unacknowledged alarms and their ids.
+    pub fn iter_active_alarms(&self) -> impl Iterator<Item = (usize, &Alarm)> {
+        self.cache
+            .alarms
+            .iter()
+            .enumerate()
+            .filter(|(_, alarm)| alarm.acknowledged.is_none())
     }
 
     /// Initializes the in-memory records cache, usually on on upstart.
-    pub fn initialize(&mut self, source: &mut dyn Read) -> io::Result<()> {
+    ///
+    /// Reads the snapshot from `source`, then replays the journal if there is one.
+    pub fn initialize(&mut self, source: &mut dyn Read) -> Result<()> {
         let reader = BufReader::new(source);
 
         // Deserialize
-        let r = serde_yaml::from_reader(reader);
-        if let Err(e) = r {
-            return Err(io::Error::new(io::ErrorKind::InvalidData, e));
+        let value: Value = serde_yaml::from_reader(reader)?;
+        self.cache = serde_yaml::from_value(value)?;
+        self.cache.assign_run_ids();
+        self.replay_journal()?;
+        Ok(())
+    }
+
+    /// Applies the changes in the journal newer than the snapshot, returning how many there were.
+    ///
+    /// A last line cut short by a crash mid-write is truncated away. If any changes were replayed
+    /// a new snapshot is written and the journal emptied.
+    pub fn replay_journal(&mut self) -> Result<usize> {
+        let contents = match &mut self.journal {
+            Some(journal) => journal.contents()?,
+            None => return Ok(0),
+        };
+
+        let mut replayed = 0;
+        let mut offset = 0;
+        for (index, line) in contents.split_inclusive(|byte| *byte == b'\n').enumerate() {
+            let entry = match serde_json::from_slice::<Entry>(line) {
+                Ok(entry) if line.ends_with(b"\n") => entry,
+                result => {
+                    if offset + line.len() < contents.len() {
+                        let message = match result {
+                            Err(e) => e.to_string(),
+                            Ok(_) => String::from("unterminated line"),
+                        };
+                        return Err(Error::Parse {
+                            message: format!("journal line {}: {}", index + 1, message),
+                            line: Some(index + 1),
+                            column: None,
+                        });
+                    }
+                    warn!("discarding partly written journal line {}", index + 1);
+                    if let Some(journal) = &mut self.journal {
+                        journal.truncate(offset as u64)?;
+                    }
+                    break;
+                }
+            };
+            offset += line.len();
+
+            // Changes already in the snapshot are left from a crash before the journal was emptied
+            if entry.sequence <= self.cache.sequence {
+                continue;
+            }
+            self.apply(entry.event).map_err(|e| Error::Parse {
+                message: format!("journal line {}: {}", index + 1, e),
+                line: Some(index + 1),
+                column: None,
+            })?;
+            self.cache.sequence = entry.sequence;
+            replayed += 1;
         }
-        let value: Value = r.unwrap();
 
-        return match serde_yaml::from_value(value) {
-            Ok(data) => {
-                self.cache = data;
-                Ok(())
+        if replayed > 0 {
+            info!("replayed {} logbook changes from the journal", replayed);
+            self.snapshot()?;
+        }
+        Ok(replayed)
+    }
+
+    /// Merges finished runs from another logbook, such as another controller's export, returning
+    /// how many were added.
+    ///
+    /// Runs already in the logbook, by schedule and start time, and runs that have not finished
+    /// are left out. The rest are placed in start time order.
+    pub fn import_records(&mut self, records: Vec<Record>) -> Result<usize> {
+        let mut seen: Vec<(String, DateTime<FixedOffset>)> = self
+            .cache
+            .records
+            .iter()
+            .filter_map(|record| Some((record.name.clone(), record.started_at()?)))
+            .collect();
+        let mut new = Vec::new();
+        for record in records {
+            let started = match (record.started_at(), record.completed_at()) {
+                (Some(started), Some(_)) => started,
+                _ => continue,
+            };
+            if seen.iter().any(|(name, at)| *name == record.name && *at == started) {
+                continue;
             }
-            Err(e) => Err(io::Error::new(io::ErrorKind::InvalidData, e)),
-        };
+            seen.push((record.name.clone(), started));
+            new.push(record);
+        }
+
+        let imported = new.len();
+        if imported > 0 {
+            info!("importing {} records", imported);
+            self.commit(Event::Imported { records: new })?;
+        }
+        Ok(imported)
+    }
+
+    /// Writes the whole logbook to its backing store, emptying the journal.
+    pub fn save(&mut self) -> Result<()> {
+        self.snapshot()
+    }
+
+    /// Removes records the retention policy no longer keeps, returning how many were removed.
+    ///
+    /// The records are handed to `archive` oldest first before they are removed, if it fails the
+    /// logbook is left as it was. Runs still in progress and the latest record of each schedule are
+    /// always kept, scheduling relies on them. Records whose times cannot be read are only removed
+    /// by the record limit.
+    pub fn compact<F>(
+        &mut self,
+        now: DateTime<Utc>,
+        retention: &Retention,
+        archive: F,
+    ) -> Result<usize>
+    where
+        F: FnOnce(Vec<Record>) -> Result<()>,
+    {
+        let cutoff = retention
+            .max_age_days
+            .map(|days| now - Duration::days(i64::from(days)));
+        let mut seen: HashMap<&str, usize> = HashMap::new();
+        let mut keep = vec![true; self.cache.records.len()];
+
+        for (index, record) in self.cache.records.iter().enumerate().rev() {
+            let newer = seen.entry(&record.name).or_insert(0);
+            *newer += 1;
+            if record.completed.is_none() || *newer == 1 {
+                continue;
+            }
+            let too_many = retention
+                .max_records_per_schedule
+                .is_some_and(|limit| *newer > limit);
+            let too_old = match (cutoff, record.completed_at().or_else(|| record.started_at())) {
+                (Some(cutoff), Some(at)) => at < cutoff,
+                _ => false,
+            };
+            keep[index] = !(too_many || too_old);
+        }
+
+        if keep.iter().all(|kept| *kept) {
+            return Ok(0);
+        }
+        let removed: Vec<Record> = self
+            .cache
+            .records
+            .iter()
+            .zip(&keep)
+            .filter(|(_, kept)| !**kept)
+            .map(|(record, _)| record.clone())
+            .collect();
+        let count = removed.len();
+        archive(removed)?;
+
+        let mut kept = keep.into_iter();
+        self.cache.records.retain(|_| kept.next().unwrap_or(true));
+        self.snapshot()?;
+        Ok(count)
     }
 
     /// Returns an iterator over the records.
@@ -106,24 +455,193 @@ impl Logbook {
 }
 
 impl Logbook {
+    /// The schedule name of a run, failing if the logbook has no such run.
+    fn name_of(&self, run: RunId) -> Result<String> {
+        match self.get(run) {
+            Some(record) => Ok(record.name.clone()),
+            None => {
+                error!("no record for run {} found, never started", run);
+                Err(Error::RunNotFound(format!("run {}", run)))
+            }
+        }
+    }
+
+    /// The current time as stored in records.
+    fn timestamp(&self) -> String {
+        self.clock.now().with_timezone(&Local).to_rfc2822()
+    }
+
+    /// Applies a change to the in-memory records cache and persists it.
+    ///
+    /// Nothing is changed or persisted if the change does not apply.
+    fn commit(&mut self, event: Event) -> Result<()> {
+        self.apply(event.clone())?;
+        self.cache.sequence += 1;
+
+        let journal = match &mut self.journal {
+            Some(journal) => journal,
+            None => return self.sync(),
+        };
+        let mut line = serde_json::to_string(&Entry {
+            sequence: self.cache.sequence,
+            event,
+        })?;
+        line.push('\n');
+        journal.write_all(line.as_bytes())?;
+        journal.flush()?;
+
+        self.journal_events += 1;
+        if self.journal_events >= JOURNAL_SNAPSHOT_EVENTS {
+            self.snapshot()?;
+        }
+        Ok(())
+    }
+
+    /// Applies a change to the in-memory records cache.
+    fn apply(&mut self, event: Event) -> Result<()> {
+        match event {
+            Event::Started {
+                id,
+                name,
+                at,
+                dry_run,
+                valves,
+                trigger,
+            } => {
+                let mut new_record = Record::new(name);
+                new_record.id = Some(self.cache.claim_run_id(id));
+                new_record.started = Some(at);
+                new_record.dry_run = dry_run;
+                new_record.valves = valves;
+                new_record.trigger = Some(trigger.unwrap_or(Trigger::Schedule));
+                self.cache.records.push(new_record);
+            }
+            Event::Completed { id, name, at } => {
+                let record = self.cache.find_running_mut(id, &name)?;
+                record.finish(at, RunOutcome::Completed);
+            }
+            Event::Ended {
+                id,
+                name,
+                at,
+                outcome,
+                error,
+            } => {
+                let record = self.cache.find_running_mut(id, &name)?;
+                record.finish(at, outcome);
+                record.error = error;
+            }
+            Event::Skipped(skip) => self.cache.skipped.push(skip),
+            Event::Volume { id, name, liters } => {
+                let record = match id {
+                    Some(_) => self.cache.find_running_mut(id, &name)?,
+                    None => {
+                        let record = self
+                            .cache
+                            .records
+                            .iter_mut()
+                            .rfind(|record| record.name == name && record.completed.is_none());
+                        match record {
+                            Some(record) => record,
+                            None => {
+                                error!("no running record for {} found", name);
+                                return Err(Error::NotRunning(name));
+                            }
+                        }
+                    }
+                };
+                for (valve, liters) in liters {
+                    *record.liters.get_or_insert(0.0) += liters;
+                    *record.valve_liters.entry(valve).or_insert(0.0) += liters;
+                }
+            }
+            Event::Alarm(alarm) => self.cache.alarms.push(alarm),
+            Event::Acknowledged { id, at } => {
+                let alarm = match self.cache.alarms.get_mut(id) {
+                    Some(alarm) => alarm,
+                    None => return Err(Error::AlarmNotFound(id)),
+                };
+                if let Some(v) = &alarm.acknowledged {
+                    error!("alarm {} was already acknowledged at {}", id, v);
+                    return Err(Error::AlarmAlreadyAcknowledged(id));
+                }
+                alarm.acknowledged = Some(at);
+            }
+            Event::Imported { records } => {
+                for mut record in records {
+                    // Ids from another logbook mean nothing here
+                    record.id = Some(self.cache.claim_run_id(None));
+                    self.cache.insert_in_order(record);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes the whole logbook and empties the journal, whose changes it now includes.
+    fn snapshot(&mut self) -> Result<()> {
+        self.sync()?;
+        if let Some(journal) = &mut self.journal {
+            journal.truncate(0)?;
+        }
+        self.journal_events = 0;
+        Ok(())
+    }
+
     /// Syncs the in-memory records cache to persistent storage.
-    fn sync(&mut self) -> io::Result<()> {
+    fn sync(&mut self) -> Result<()> {
         // Convert to serde_yaml
         let r = serde_yaml::to_value(&self.cache);
         if let Err(e) = r {
-            return Err(io::Error::new(io::ErrorKind::InvalidData, e));
+            return Err(Error::Storage(io::Error::new(io::ErrorKind::InvalidData, e)));
         }
         let value: Value = r.unwrap();
 
         // Serialize
         let data = serde_yaml::to_string(&value);
         if let Err(e) = data {
-            return Err(io::Error::new(io::ErrorKind::InvalidData, e));
+            return Err(Error::Storage(io::Error::new(io::ErrorKind::InvalidData, e)));
         }
         let data: String = data.unwrap();
 
         // Return the result of writing to storage
-        return self.backing.write_all(data.as_bytes());
+        self.backing.write_all(data.as_bytes())?;
+        self.backing.flush()?;
+        Ok(())
+    }
+}
+
+/// Appends records to the archive logbook at `path`, creating it if needed.
+///
+/// Archives have the same format as the logbook, so they can be read back with `initialize`.
+pub fn append_archive(path: &Path, records: Vec<Record>) -> Result<()> {
+    let mut archive = Logbook::new(Box::new(FileStore::new(path)));
+    match File::open(path) {
+        Ok(mut file) => archive.initialize(&mut file)?,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e.into()),
+    }
+    archive.cache.records.extend(records);
+    archive.sync()
+}
+
+/// How long records are kept in the logbook before they are archived.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Retention {
+    /// Completed records older than this are archived, or kept forever if `None`.
+    pub max_age_days: Option<u32>,
+
+    /// Only this many of the latest records of each schedule are kept, or all if `None`.
+    pub max_records_per_schedule: Option<usize>,
+}
+
+impl Default for Retention {
+    fn default() -> Retention {
+        Retention {
+            max_age_days: Some(RETENTION_DAYS),
+            max_records_per_schedule: Some(RETENTION_RECORDS_PER_SCHEDULE),
+        }
     }
 }
 
@@ -147,19 +665,156 @@ impl<'a> Iterator for Iter<'a> {
     }
 }
 
+/// A change to the logbook, as written to the journal.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+enum Event {
+    Started {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<RunId>,
+        name: String,
+        at: String,
+        #[serde(default)]
+        dry_run: bool,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        valves: Vec<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        trigger: Option<Trigger>,
+    },
+    Completed {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<RunId>,
+        name: String,
+        at: String,
+    },
+    Ended {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<RunId>,
+        name: String,
+        at: String,
+        outcome: RunOutcome,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+    },
+    Skipped(Skip),
+    Volume {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<RunId>,
+        name: String,
+        liters: Vec<(String, f64)>,
+    },
+    Alarm(Alarm),
+    Acknowledged {
+        id: usize,
+        at: String,
+    },
+    Imported {
+        records: Vec<Record>,
+    },
+}
+
+/// A line of the journal, numbered so changes already in the snapshot can be told apart.
+#[derive(Debug, Serialize, Deserialize)]
+struct Entry {
+    sequence: u64,
+
+    #[serde(flatten)]
+    event: Event,
+}
+
 #[derive(Debug, PartialEq, Serialize, Deserialize)]
 struct LogbookData {
     records: Vec<Record>,
+
+    #[serde(default)]
+    alarms: Vec<Alarm>,
+
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    skipped: Vec<Skip>,
+
+    /// The number of the last journal change included.
+    #[serde(default, skip_serializing_if = "is_zero")]
+    sequence: u64,
+
+    /// The id of the last run started, so ids are never reused once records are archived.
+    #[serde(default, skip_serializing_if = "is_zero")]
+    last_run_id: u64,
 }
 
 impl LogbookData {
     fn new() -> Self {
-        Self { records: vec![] }
+        Self {
+            records: vec![],
+            alarms: vec![],
+            skipped: vec![],
+            sequence: 0,
+            last_run_id: 0,
+        }
+    }
+
+    /// The id the next run started will get.
+    fn next_run_id(&self) -> RunId {
+        RunId(self.last_run_id + 1)
+    }
+
+    /// Takes `id` for a run, or the next id if `None`, so later runs get later ids.
+    fn claim_run_id(&mut self, id: Option<RunId>) -> RunId {
+        let id = id.unwrap_or_else(|| self.next_run_id());
+        self.last_run_id = self.last_run_id.max(id.0);
+        id
+    }
+
+    /// Gives an id to every record written before runs had them, in record order so the same
+    /// logbook always gets the same ids.
+    fn assign_run_ids(&mut self) {
+        for index in 0..self.records.len() {
+            if self.records[index].id.is_none() {
+                self.records[index].id = Some(self.claim_run_id(None));
+            }
+        }
+    }
+
+    /// Inserts a finished record before the first that started after it, and before any run of
+    /// the same schedule still in progress so that run stays the most recent.
+    fn insert_in_order(&mut self, record: Record) {
+        let started = record.started_at();
+        let index = self
+            .records
+            .iter()
+            .position(|other| {
+                other.started_at() > started
+                    || (other.name == record.name && other.completed.is_none())
+            })
+            .unwrap_or(self.records.len());
+        self.records.insert(index, record);
+    }
+
+    /// Finds the `Record` of a run by id, or the most recent for a schedule by name if `id` is
+    /// `None`, failing unless it is still running.
+    fn find_running_mut(&mut self, id: Option<RunId>, name: &String) -> Result<&mut Record> {
+        let record = match id {
+            Some(id) => self.records.iter_mut().rfind(|record| record.id == Some(id)),
+            None => self.find_most_recent_mut(name),
+        };
+        let record = match record {
+            Some(record) => record,
+            None => {
+                error!("no record for {} found, never started", name);
+                return Err(Error::RunNotFound(name.clone()));
+            }
+        };
+        // Ensure it was not already marked as complete
+        if let Some(v) = &record.completed {
+            error!("record for {} was already completed at {}", name, v);
+            return Err(Error::NotRunning(name.clone()));
+        }
+        Ok(record)
     }
 
     /// Finds the most recent `Record` for a schedule by name.
     ///
     /// Returns `None` if there are no records for the given schedule.
+    #[cfg(test)]
     fn find_most_recent(&self, name: &String) -> Option<&Record> {
         return self.records.iter().rfind(|record| record.name == *name);
     }
@@ -175,6 +830,10 @@ impl LogbookData {
 /// A record of when a schedule was started and completed.
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct Record {
+    /// The id of the run, or `None` until it is added to a logbook.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<RunId>,
+
     /// The name of the schedule this record tracks.
     pub name: String,
 
@@ -183,18 +842,282 @@ pub struct Record {
 
     /// The time when this schedule was completed or `None` if it was not completed.
     pub completed: Option<String>,
+
+    /// The liters of water delivered during this run or `None` if it was not metered.
+    #[serde(default)]
+    pub liters: Option<f64>,
+
+    /// The liters of water delivered during this run through each valve.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub valve_liters: BTreeMap<String, f64>,
+
+    /// The run happened in dry run, no valves were actually opened.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub dry_run: bool,
+
+    /// The valves opened for this run, empty if it was recorded before valves were.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub valves: Vec<String>,
+
+    /// How the run ended, or `None` while it is running or if it was recorded before outcomes
+    /// were, see `outcome()`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub outcome: Option<RunOutcome>,
+
+    /// What started the run, or `None` if it was recorded before triggers were, see `trigger()`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub trigger: Option<Trigger>,
+
+    /// How long the run lasted in seconds, or `None` while it is running or if it was recorded
+    /// before durations were, see `duration()`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub duration_secs: Option<i64>,
+
+    /// Why the run was interrupted, aborted or failed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
 }
 
 impl Record {
+    /// The time when this schedule was started, or `None` if it was not started or the time
+    /// cannot be read.
+    pub fn started_at(&self) -> Option<DateTime<FixedOffset>> {
+        parse_timestamp(&self.started)
+    }
+
+    /// The time when this schedule was completed, or `None` if it was not completed or the time
+    /// cannot be read.
+    pub fn completed_at(&self) -> Option<DateTime<FixedOffset>> {
+        parse_timestamp(&self.completed)
+    }
+
+    /// How the run ended, or `None` while it is running.
+    pub fn outcome(&self) -> Option<RunOutcome> {
+        match (self.outcome, &self.completed) {
+            (Some(outcome), _) => Some(outcome),
+            (None, Some(_)) => Some(RunOutcome::Completed),
+            (None, None) => None,
+        }
+    }
+
+    /// What started the run.
+    pub fn trigger(&self) -> Trigger {
+        self.trigger.unwrap_or(Trigger::Schedule)
+    }
+
+    /// How long the run lasted, or `None` while it is running or if its times cannot be read.
+    pub fn duration(&self) -> Option<Duration> {
+        match self.duration_secs {
+            Some(seconds) => Some(Duration::seconds(seconds)),
+            None => Some(self.completed_at()? - self.started_at()?),
+        }
+    }
+
+    fn finish(&mut self, at: String, outcome: RunOutcome) {
+        self.completed = Some(at);
+        self.outcome = Some(outcome);
+        self.duration_secs = self.duration().map(|duration| duration.num_seconds());
+    }
+
     fn new(name: String) -> Self {
         Self {
+            id: None,
             name,
             started: None,
             completed: None,
+            liters: None,
+            valve_liters: BTreeMap::new(),
+            dry_run: false,
+            valves: Vec::new(),
+            outcome: None,
+            trigger: None,
+            duration_secs: None,
+            error: None,
+        }
+    }
+}
+
+/// Identifies a run, unique among the runs a logbook has recorded.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize)]
+pub struct RunId(pub u64);
+
+impl fmt::Display for RunId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// What to do when a schedule is started while a run of it is still in progress.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OverlapPolicy {
+    /// The new run is refused.
+    #[default]
+    Reject,
+
+    /// Both runs go ahead, each completed on its own.
+    Allow,
+
+    /// The run in progress is aborted and the new run goes ahead.
+    Replace,
+}
+
+/// How a run ended.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RunOutcome {
+    /// The run ran its course.
+    Completed,
+
+    /// The run was cut short from outside, such as by the daemon stopping.
+    Interrupted,
+
+    /// The run was stopped on purpose before its end.
+    Aborted,
+
+    /// The run was due but not started, only used for `Skip`s.
+    Skipped,
+
+    /// The run ended because of an error.
+    Failed,
+}
+
+impl fmt::Display for RunOutcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            RunOutcome::Completed => "completed",
+            RunOutcome::Interrupted => "interrupted",
+            RunOutcome::Aborted => "aborted",
+            RunOutcome::Skipped => "skipped",
+            RunOutcome::Failed => "failed",
+        })
+    }
+}
+
+impl std::str::FromStr for RunOutcome {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<RunOutcome, String> {
+        match s {
+            "completed" => Ok(RunOutcome::Completed),
+            "interrupted" => Ok(RunOutcome::Interrupted),
+            "aborted" => Ok(RunOutcome::Aborted),
+            "skipped" => Ok(RunOutcome::Skipped),
+            "failed" => Ok(RunOutcome::Failed),
+            _ => Err(format!("unknown outcome {}", s)),
+        }
+    }
+}
+
+/// What started a run.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Trigger {
+    /// The run was due on its schedule.
+    Schedule,
+
+    /// The run was started by hand.
+    Manual,
+
+    /// The run was started by a sensor reading, such as dry soil.
+    Sensor,
+
+    /// The run was started again after the daemon restarted mid-run.
+    Recovery,
+}
+
+impl fmt::Display for Trigger {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Trigger::Schedule => "schedule",
+            Trigger::Manual => "manual",
+            Trigger::Sensor => "sensor",
+            Trigger::Recovery => "recovery",
+        })
+    }
+}
+
+impl std::str::FromStr for Trigger {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Trigger, String> {
+        match s {
+            "schedule" => Ok(Trigger::Schedule),
+            "manual" => Ok(Trigger::Manual),
+            "sensor" => Ok(Trigger::Sensor),
+            "recovery" => Ok(Trigger::Recovery),
+            _ => Err(format!("unknown trigger {}", s)),
         }
     }
 }
 
+/// A run that was due but not started.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Skip {
+    /// The name of the schedule.
+    pub name: String,
+
+    /// The time the run was due to start.
+    pub due: String,
+
+    /// The time the run was skipped.
+    pub at: String,
+
+    /// Why the run was skipped.
+    pub reason: String,
+
+    /// The valves the run would have opened.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub valves: Vec<String>,
+}
+
+impl Skip {
+    /// The time the run was due to start, or `None` if the time cannot be read.
+    pub fn due_at(&self) -> Option<DateTime<FixedOffset>> {
+        parse_timestamp(&Some(self.due.clone()))
+    }
+}
+
+fn is_zero(n: &u64) -> bool {
+    *n == 0
+}
+
+fn parse_timestamp(timestamp: &Option<String>) -> Option<DateTime<FixedOffset>> {
+    timestamp
+        .as_ref()
+        .and_then(|t| DateTime::parse_from_rfc2822(t).ok())
+}
+
+/// The kinds of abnormal flow that raise an alarm.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AlarmKind {
+    /// Water is flowing while every zone is closed, a leak or a stuck valve.
+    Leak,
+
+    /// Water is flowing faster than the open zones allow, a broken pipe or head.
+    HighFlow,
+}
+
+/// A record of abnormal flow detected by the flow meter.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Alarm {
+    /// What kind of abnormal flow was detected.
+    pub kind: AlarmKind,
+
+    /// The time when the alarm was raised.
+    pub detected: String,
+
+    /// The measured flow rate when the alarm was raised.
+    pub liters_per_minute: f64,
+
+    /// The valves that were closed in response.
+    pub valves: Vec<String>,
+
+    /// The time when the alarm was acknowledged or `None` if it is still active.
+    pub acknowledged: Option<String>,
+}
+
 impl From<&str> for Record {
     fn from(s: &str) -> Self {
         Self::new(String::from(s))
@@ -211,6 +1134,10 @@ impl From<String> for Record {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::clock::VirtualClock;
+    use crate::file_store::AppendStore;
+    use crate::scratch::ScratchDir;
+    use chrono::{Datelike, Duration, TimeZone, Utc};
     use std::{mem, ptr};
 
     #[test]
@@ -225,15 +1152,15 @@ mod tests {
         assert_eq!(record.started, None);
     }
 
-    /// Tests that the logbook `mark_started` syncs to persistent storage by checking the storage
+    /// Tests that the logbook `mark_started_by` syncs to persistent storage by checking the storage
     /// is not empty afterwards.
     #[test]
     fn logbook_mark_started_syncs() {
         let mut l = Logbook::new(Box::new(Vec::<u8>::new()));
 
         let schedule = String::from("any schedule");
-        l.mark_started(&String::from("any schedule"))
-            .expect("mark_started succeeds");
+        l.mark_started_by("any schedule", &[], Trigger::Schedule)
+            .expect("mark_started_by succeeds");
 
         let d = peek_logbook(&mut l);
         let record = d.find_most_recent(&schedule);
@@ -250,10 +1177,10 @@ mod tests {
         let mut l = Logbook::new(Box::new(Vec::<u8>::new()));
 
         let schedule = String::from("any schedule");
-        l.mark_started(&String::from("any schedule"))
-            .expect("mark_started succeeds");
-        l.mark_completed(&String::from("any schedule"))
-            .expect("mark_completed succeeds");
+        let run = l
+            .mark_started_by("any schedule", &[], Trigger::Schedule)
+            .expect("mark_started_by succeeds");
+        l.mark_completed(run).expect("mark_completed succeeds");
 
         let d = peek_logbook(&mut l);
         let record = d.find_most_recent(&schedule);
@@ -263,18 +1190,36 @@ mod tests {
         assert!(record.completed.is_some());
     }
 
+    #[test]
+    fn mark_started_and_completed_use_injected_clock() {
+        let start = Utc.ymd(2020, 7, 1).and_hms(14, 0, 0);
+        let clock = Arc::new(VirtualClock::new(start));
+        let mut l = Logbook::with_clock(Box::new(Vec::<u8>::new()), clock.clone());
+        let schedule = String::from("any schedule");
+
+        let run = l
+            .mark_started_by(&schedule, &[], Trigger::Schedule)
+            .expect("mark_started_by succeeds");
+        clock.advance(Duration::minutes(30));
+        l.mark_completed(run).expect("mark_completed succeeds");
+
+        let record = l.iter().next().expect("record exists");
+        assert_eq!(record.started_at(), Some(start.into()));
+        assert_eq!(record.completed_at(), Some((start + Duration::minutes(30)).into()));
+    }
+
     #[test]
     fn test_mark_completed_of_unstarted_schedule_fails() {
         let mut l = Logbook::new(Box::new(Vec::<u8>::new()));
 
-        assert!(l.mark_completed(&String::from("any schedule")).is_err());
+        assert!(l.mark_completed(RunId(1)).is_err());
     }
 
     #[test]
     fn test_mark_completed_of_unstarted_schedule_does_not_sync() {
         let mut l = Logbook::new(Box::new(Vec::<u8>::new()));
 
-        l.mark_completed(&String::from("any schedule")).expect_err("mark_completed fails");
+        l.mark_completed(RunId(1)).expect_err("mark_completed fails");
 
         assert_eq!(peek_storage(&mut l).len(), 0);
     }
@@ -284,18 +1229,358 @@ mod tests {
         let schedule = String::from("any schedule");
         let mut l = Logbook::new(Box::new(Vec::<u8>::new()));
 
-        l.mark_started(&schedule).expect("mark_started succeeds");
-        l.mark_completed(&schedule)
-            .expect("first mark_completed succeeds");
+        let run = l
+            .mark_started_by(&schedule, &[], Trigger::Schedule)
+            .expect("mark_started_by succeeds");
+        l.mark_completed(run).expect("first mark_completed succeeds");
 
-        assert!(l.mark_completed(&schedule).is_err());
+        assert!(l.mark_completed(run).is_err());
+    }
+
+    #[test]
+    fn record_volume_accumulates_on_running_record() {
+        let schedule = String::from("any schedule");
+        let mut l = Logbook::new(Box::new(Vec::<u8>::new()));
+
+        let run = l
+            .mark_started_by(&schedule, &[], Trigger::Schedule)
+            .expect("mark_started_by succeeds");
+        l.record_volume(run, &[(String::from("a"), 1.5), (String::from("b"), 1.5)])
+            .expect("record_volume succeeds");
+        l.record_volume(run, &[(String::from("a"), 2.0)])
+            .expect("record_volume succeeds");
+
+        let record = l.cache.find_most_recent(&schedule).expect("record exists");
+        assert_eq!(record.liters, Some(5.0));
+        assert_eq!(record.valve_liters.get("a"), Some(&3.5));
+        assert_eq!(record.valve_liters.get("b"), Some(&1.5));
+    }
+
+    #[test]
+    fn record_volume_of_completed_schedule_fails() {
+        let schedule = String::from("any schedule");
+        let mut l = Logbook::new(Box::new(Vec::<u8>::new()));
+
+        let run = l
+            .mark_started_by(&schedule, &[], Trigger::Schedule)
+            .expect("mark_started_by succeeds");
+        l.mark_completed(run).expect("mark_completed succeeds");
+
+        assert!(l.record_volume(run, &[(String::from("a"), 1.0)]).is_err());
+    }
+
+    #[test]
+    fn record_without_volume_fields_deserializes() {
+        let data: LogbookData =
+            serde_yaml::from_str("records:\n  - name: any\n    started: ~\n    completed: ~\n")
+                .expect("deserialize succeeds");
+
+        assert_eq!(data.records[0].liters, None);
+        assert!(data.records[0].valve_liters.is_empty());
+    }
+
+    #[test]
+    fn record_without_outcome_fields_derives_them() {
+        let data: LogbookData = serde_yaml::from_str(
+            "records:
+  - name: beds
+    started: \"Wed, 01 Jul 2020 14:00:00 +0000\"
+    completed: \"Wed, 01 Jul 2020 14:30:00 +0000\"
+  - name: lawn
+    started: \"Wed, 01 Jul 2020 16:00:00 +0000\"
+    completed: ~
+",
+        )
+        .expect("deserialize succeeds");
+
+        let [beds, lawn] = &data.records[..] else {
+            panic!("expected two records");
+        };
+        assert_eq!(beds.outcome(), Some(RunOutcome::Completed));
+        assert_eq!(beds.trigger(), Trigger::Schedule);
+        assert_eq!(beds.duration(), Some(Duration::minutes(30)));
+        assert_eq!(lawn.outcome(), None);
+        assert_eq!(lawn.duration(), None);
+    }
+
+    #[test]
+    fn runs_record_how_they_ended() {
+        let start = Utc.ymd(2020, 7, 1).and_hms(14, 0, 0);
+        let clock = Arc::new(VirtualClock::new(start));
+        let mut l = Logbook::with_clock(Box::new(Vec::<u8>::new()), clock.clone());
+        let valves = vec![String::from("beds valve")];
+
+        let beds = l
+            .mark_started_by("beds", &valves, Trigger::Sensor)
+            .expect("mark_started_by succeeds");
+        let pots = l
+            .mark_started_by("pots", &[], Trigger::Manual)
+            .expect("mark_started_by succeeds");
+        clock.advance(Duration::minutes(12));
+        l.mark_aborted(beds, "high flow").expect("mark_aborted succeeds");
+        l.mark_failed(pots, "valve would not open")
+            .expect("mark_failed succeeds");
+        assert!(l.mark_failed(pots, "again").is_err());
+
+        let beds = l.iter().next().expect("record exists");
+        assert_eq!(beds.outcome, Some(RunOutcome::Aborted));
+        assert_eq!(beds.trigger, Some(Trigger::Sensor));
+        assert_eq!(beds.valves, valves);
+        assert_eq!(beds.duration_secs, Some(12 * 60));
+        assert_eq!(beds.error.as_deref(), Some("high flow"));
+        let pots = l.iter().nth(1).expect("record exists");
+        assert_eq!(pots.outcome, Some(RunOutcome::Failed));
+        assert_eq!(pots.trigger(), Trigger::Manual);
+    }
+
+    #[test]
+    fn overlapping_runs_follow_the_policy() {
+        let mut l = Logbook::new(Box::new(Vec::<u8>::new()));
+        let first = l
+            .mark_started_by("pots", &[], Trigger::Schedule)
+            .expect("mark_started_by succeeds");
+
+        let rejected = l.mark_started_by("pots", &[], Trigger::Manual).unwrap_err();
+        assert!(matches!(rejected, Error::AlreadyRunning(_)));
+        assert_eq!(l.iter().count(), 1);
+
+        l.set_overlap_policy(OverlapPolicy::Allow);
+        let second = l.mark_started_by("pots", &[], Trigger::Manual).expect("overlap allowed");
+        assert_ne!(first, second);
+        l.mark_completed(first).expect("mark_completed succeeds");
+        assert!(l.get(first).expect("first run").completed.is_some());
+        assert!(l.get(second).expect("second run").completed.is_none());
+
+        l.set_overlap_policy(OverlapPolicy::Replace);
+        let third = l.mark_started_by("pots", &[], Trigger::Schedule).expect("replace succeeds");
+        assert_eq!(l.get(second).expect("second run").outcome, Some(RunOutcome::Aborted));
+        assert!(l.get(third).expect("third run").completed.is_none());
+        assert!(l.mark_completed(second).is_err());
+    }
+
+    #[test]
+    fn raise_alarm_syncs() {
+        let mut l = Logbook::new(Box::new(Vec::<u8>::new()));
+
+        assert!(l.raise_alarm(AlarmKind::Leak, 2.0, vec![]).expect("raise_alarm succeeds"));
+
+        let d = peek_logbook(&mut l);
+        assert_eq!(d.alarms.len(), 1);
+        assert_eq!(d.alarms[0].kind, AlarmKind::Leak);
+    }
+
+    #[test]
+    fn raise_alarm_while_same_kind_active_is_ignored() {
+        let mut l = Logbook::new(Box::new(Vec::<u8>::new()));
+
+        l.raise_alarm(AlarmKind::Leak, 2.0, vec![]).expect("raise_alarm succeeds");
+        let raised = l.raise_alarm(AlarmKind::Leak, 3.0, vec![]).expect("raise_alarm succeeds");
+
+        assert!(!raised);
+        assert_eq!(l.iter_active_alarms().count(), 1);
+    }
+
+    #[test]
+    fn acknowledge_alarm_clears_it() {
+        let mut l = Logbook::new(Box::new(Vec::<u8>::new()));
+
+        l.raise_alarm(AlarmKind::HighFlow, 40.0, vec![String::from("a")])
+            .expect("raise_alarm succeeds");
+        let (id, _) = l.iter_active_alarms().next().expect("alarm is active");
+        l.acknowledge_alarm(id).expect("acknowledge_alarm succeeds");
+
+        assert_eq!(l.iter_active_alarms().count(), 0);
+        assert!(matches!(
+            l.acknowledge_alarm(id),
+            Err(Error::AlarmAlreadyAcknowledged(_))
+        ));
+        assert!(matches!(
+            l.acknowledge_alarm(id + 1),
+            Err(Error::AlarmNotFound(_))
+        ));
+    }
+
+    #[test]
+    fn compact_keeps_running_and_latest_records() {
+        let start = Utc.ymd(2020, 1, 1).and_hms(7, 0, 0);
+        let clock = Arc::new(VirtualClock::new(start));
+        let mut l = Logbook::with_clock(Box::new(Vec::<u8>::new()), clock.clone());
+        let (daily, yearly, stuck) = (
+            String::from("daily"),
+            String::from("yearly"),
+            String::from("stuck"),
+        );
+        let run = l
+            .mark_started_by(&yearly, &[], Trigger::Schedule)
+            .expect("mark_started_by succeeds");
+        l.mark_completed(run).expect("mark_completed succeeds");
+        l.mark_started_by(&stuck, &[], Trigger::Schedule).expect("mark_started_by succeeds");
+        for _ in 0..5 {
+            let run = l
+                .mark_started_by(&daily, &[], Trigger::Schedule)
+                .expect("mark_started_by succeeds");
+            l.mark_completed(run).expect("mark_completed succeeds");
+            clock.advance(Duration::days(1));
+        }
+        clock.advance(Duration::days(30));
+
+        let retention = Retention {
+            max_age_days: Some(32),
+            max_records_per_schedule: Some(3),
+        };
+        assert!(l
+            .compact(clock.now(), &retention, |_| Err(io::Error::other("disk full").into()))
+            .is_err());
+        assert_eq!(l.iter().count(), 7);
+
+        let mut removed = Vec::new();
+        l.compact(clock.now(), &retention, |records| {
+            removed = records;
+            Ok(())
+        })
+        .expect("compact succeeds");
+
+        // Days 1 and 2 are past the record limit, day 3 is too old, day 4 and 5 are kept
+        let removed: Vec<&str> = removed.iter().map(|r| r.name.as_str()).collect();
+        assert_eq!(removed, vec!["daily"; 3]);
+        let kept: Vec<&str> = l.iter().map(|r| r.name.as_str()).collect();
+        assert_eq!(kept, vec!["yearly", "stuck", "daily", "daily"]);
+        assert_eq!(peek_logbook(&mut l).records.len(), 4);
+    }
+
+    #[test]
+    fn journal_replays_changes_since_snapshot() {
+        let dir = ScratchDir::new("journal");
+        let (snapshot, journal) = (dir.join("logbook.yaml"), dir.join("logbook.journal"));
+        let open = || {
+            Logbook::with_journal(
+                Box::new(FileStore::new(&snapshot)),
+                Box::new(AppendStore::new(&journal)),
+                Arc::new(SystemClock),
+            )
+        };
+        let schedule = String::from("any schedule");
+
+        let mut l = open();
+        let run = l
+            .mark_started_by(&schedule, &[], Trigger::Schedule)
+            .expect("mark_started_by succeeds");
+        l.record_volume(run, &[(String::from("a"), 2.0)])
+            .expect("record_volume succeeds");
+        l.mark_completed(run).expect("mark_completed succeeds");
+        l.raise_alarm(AlarmKind::Leak, 1.0, vec![]).expect("raise_alarm succeeds");
+        assert!(!snapshot.exists());
+        let lines = std::fs::read(&journal).expect("journal exists");
+
+        // A crash part way through appending the next change
+        let mut partial = lines.clone();
+        partial.extend_from_slice(b"{\"sequence\":5,\"event\":\"sta");
+        std::fs::write(&journal, &partial).expect("write succeeds");
+        let mut replayed = open();
+        assert_eq!(replayed.replay_journal().expect("replay succeeds"), 4);
+        assert_eq!(replayed.cache, l.cache);
+        assert_eq!(std::fs::read(&journal).expect("journal exists"), b"");
+
+        // A crash after the snapshot was written but before the journal was emptied
+        std::fs::write(&journal, &lines).expect("write succeeds");
+        let mut restarted = open();
+        restarted
+            .initialize(&mut File::open(&snapshot).expect("snapshot exists"))
+            .expect("initialize succeeds");
+        assert_eq!(restarted.cache, l.cache);
+    }
+
+    #[test]
+    fn journal_with_bad_line_before_the_end_is_rejected() {
+        let mut journal = b"not json\n".to_vec();
+        journal.extend_from_slice(b"{\"sequence\":1,\"event\":\"alarm\",\"kind\":\"leak\",");
+        journal.extend_from_slice(b"\"detected\":\"now\",\"liters_per_minute\":1.0,\"valves\":[]}\n");
+        let mut l = Logbook::with_journal(
+            Box::new(Vec::<u8>::new()),
+            Box::new(journal),
+            Arc::new(SystemClock),
+        );
+
+        assert!(l.replay_journal().is_err());
+    }
+
+    #[test]
+    fn archive_appends_to_existing_file() {
+        let dir = ScratchDir::new("archive");
+        let path = dir.join("2020-06.yaml");
+
+        append_archive(&path, vec![Record::from("a")]).expect("archive succeeds");
+        append_archive(&path, vec![Record::from("b")]).expect("archive succeeds");
+
+        let mut archive = Logbook::new(Box::new(io::sink()));
+        archive
+            .initialize(&mut File::open(&path).expect("archive exists"))
+            .expect("archive reads");
+        let names: Vec<&str> = archive.iter().map(|r| r.name.as_str()).collect();
+        assert_eq!(names, vec!["a", "b"]);
+    }
+
+    #[test]
+    fn import_merges_in_start_order_without_duplicates() {
+        let clock = Arc::new(VirtualClock::new(Utc.ymd(2020, 6, 2).and_hms(7, 0, 0)));
+        let mut l = Logbook::with_clock(Box::new(Vec::<u8>::new()), clock.clone());
+        let beds = String::from("beds");
+        let run = l
+            .mark_started_by(&beds, &[], Trigger::Schedule)
+            .expect("mark_started_by succeeds");
+        clock.advance(Duration::minutes(30));
+        l.mark_completed(run).expect("mark_completed succeeds");
+        clock.advance(Duration::hours(1));
+        let running = l
+            .mark_started_by(&beds, &[], Trigger::Schedule)
+            .expect("mark_started_by succeeds");
+
+        let run = |day: u32, name: &str| {
+            let mut record = Record::from(name);
+            let started = Utc.ymd(2020, 6, day).and_hms(7, 0, 0);
+            record.started = Some(started.to_rfc2822());
+            record.completed = Some((started + Duration::minutes(30)).to_rfc2822());
+            record
+        };
+        let mut same_start = run(2, "beds");
+        same_start.started = Some(String::from("Tue, 02 Jun 2020 09:00:00 +0200"));
+        let mut unfinished = run(1, "pots");
+        unfinished.completed = None;
+
+        let imported = l
+            .import_records(vec![
+                run(1, "beds"),
+                run(3, "beds"),
+                run(1, "beds"),
+                same_start,
+                unfinished,
+            ])
+            .expect("import succeeds");
+
+        assert_eq!(imported, 2);
+        let days: Vec<(u32, bool)> = l
+            .iter()
+            .map(|record| {
+                let started = record.started_at().expect("start is readable");
+                (started.day(), record.completed.is_some())
+            })
+            .collect();
+        assert_eq!(days, vec![(1, true), (2, true), (3, true), (2, false)]);
+        assert_eq!(
+            l.iter().filter_map(|record| record.id).collect::<Vec<_>>(),
+            vec![RunId(3), RunId(1), RunId(4), RunId(2)]
+        );
+        l.mark_completed(running).expect("the run in progress is still running");
     }
 
     /// Helper to peek at the internal `Logbook` storage
     fn peek_logbook(logbook: &mut Logbook) -> LogbookData {
         let mock_storage = peek_storage(logbook);
-        let value = serde_yaml::from_slice(mock_storage.as_slice())
-            .expect("deserialize mock storage succeeds");
+
+        // Each sync appends a whole document to the mock storage, only the last one is current
+        let contents = std::str::from_utf8(mock_storage.as_slice()).expect("storage is utf8");
+        let latest = &contents[contents.rfind("---").unwrap_or(0)..];
+        let value = serde_yaml::from_str(latest).expect("deserialize mock storage succeeds");
         return serde_yaml::from_value(value).expect("decode mock storage succeeds");
     }
 
@@ -0,0 +1,376 @@
+use super::logbook::{Logbook, Record, RunOutcome, Skip, Trigger};
+use chrono::{DateTime, FixedOffset};
+use serde::{Deserialize, Serialize};
+
+/// How a logged run turned out, to filter the logbook by.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    /// The run ran its course.
+    Completed,
+
+    /// The run was cut short from outside.
+    Interrupted,
+
+    /// The run was stopped on purpose before its end.
+    Aborted,
+
+    /// The run ended because of an error.
+    Failed,
+
+    /// The run was due but not started.
+    Skipped,
+
+    /// The run was started by hand, however it turned out.
+    Manual,
+}
+
+impl std::str::FromStr for Outcome {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Outcome, String> {
+        match s {
+            "completed" => Ok(Outcome::Completed),
+            "interrupted" => Ok(Outcome::Interrupted),
+            "aborted" => Ok(Outcome::Aborted),
+            "failed" => Ok(Outcome::Failed),
+            "skipped" => Ok(Outcome::Skipped),
+            "manual" => Ok(Outcome::Manual),
+            _ => Err(format!("unknown outcome {}", s)),
+        }
+    }
+}
+
+/// A run, or a run that was skipped, found in the logbook.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum LogEntry {
+    Run(Record),
+    Skipped(Skip),
+}
+
+impl LogEntry {
+    /// The name of the schedule.
+    pub fn name(&self) -> &str {
+        match self {
+            LogEntry::Run(record) => &record.name,
+            LogEntry::Skipped(skip) => &skip.name,
+        }
+    }
+
+    /// When the run started, or was due for a skipped run, or `None` if that cannot be read.
+    pub fn time(&self) -> Option<DateTime<FixedOffset>> {
+        match self {
+            LogEntry::Run(record) => record.started_at(),
+            LogEntry::Skipped(skip) => skip.due_at(),
+        }
+    }
+
+    /// How the run ended, or `None` while it is running.
+    pub fn outcome(&self) -> Option<RunOutcome> {
+        match self {
+            LogEntry::Run(record) => record.outcome(),
+            LogEntry::Skipped(_) => Some(RunOutcome::Skipped),
+        }
+    }
+
+    /// Returns `true` if the run opened `valve`, or would have if it was skipped.
+    pub fn uses_valve(&self, valve: &str) -> bool {
+        match self {
+            LogEntry::Run(record) => {
+                record.valves.iter().any(|v| v == valve) || record.valve_liters.contains_key(valve)
+            }
+            LogEntry::Skipped(skip) => skip.valves.iter().any(|v| v == valve),
+        }
+    }
+
+    /// Returns `true` if the run turned out as `outcome`.
+    pub fn has_outcome(&self, outcome: Outcome) -> bool {
+        let ended = match outcome {
+            Outcome::Completed => RunOutcome::Completed,
+            Outcome::Interrupted => RunOutcome::Interrupted,
+            Outcome::Aborted => RunOutcome::Aborted,
+            Outcome::Failed => RunOutcome::Failed,
+            Outcome::Skipped => RunOutcome::Skipped,
+            Outcome::Manual => {
+                return matches!(self, LogEntry::Run(record) if record.trigger() == Trigger::Manual)
+            }
+        };
+        self.outcome() == Some(ended)
+    }
+}
+
+/// A search of the logbook, built up from filters that must all match.
+///
+/// Results are in time order, oldest first. With a `limit` they are paged back from the newest,
+/// page 0 holding the latest `limit` matches, page 1 the ones before and so on.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(default)]
+pub struct Query {
+    /// Only runs of this schedule.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub schedule: Option<String>,
+
+    /// Only runs opening this valve.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub valve: Option<String>,
+
+    /// Only runs starting, or due, at or after this time.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub since: Option<DateTime<FixedOffset>>,
+
+    /// Only runs starting, or due, before this time.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub until: Option<DateTime<FixedOffset>>,
+
+    /// Only runs that turned out this way.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub outcome: Option<Outcome>,
+
+    /// The page of results, counting back from the newest.
+    #[serde(skip_serializing_if = "is_zero")]
+    pub page: usize,
+
+    /// The most results on a page, or all of them if `None`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub limit: Option<usize>,
+}
+
+impl Query {
+    /// A query matching every run.
+    pub fn new() -> Query {
+        Query::default()
+    }
+
+    pub fn schedule(mut self, name: &str) -> Query {
+        self.schedule = Some(String::from(name));
+        self
+    }
+
+    pub fn valve(mut self, name: &str) -> Query {
+        self.valve = Some(String::from(name));
+        self
+    }
+
+    pub fn since(mut self, time: DateTime<FixedOffset>) -> Query {
+        self.since = Some(time);
+        self
+    }
+
+    pub fn until(mut self, time: DateTime<FixedOffset>) -> Query {
+        self.until = Some(time);
+        self
+    }
+
+    pub fn outcome(mut self, outcome: Outcome) -> Query {
+        self.outcome = Some(outcome);
+        self
+    }
+
+    pub fn page(mut self, page: usize) -> Query {
+        self.page = page;
+        self
+    }
+
+    pub fn limit(mut self, limit: usize) -> Query {
+        self.limit = Some(limit);
+        self
+    }
+
+    /// Finds the runs in `logbook` matching every filter.
+    pub fn run(&self, logbook: &Logbook) -> Vec<LogEntry> {
+        let mut entries: Vec<LogEntry> = logbook
+            .iter()
+            .cloned()
+            .map(LogEntry::Run)
+            .chain(logbook.iter_skipped().cloned().map(LogEntry::Skipped))
+            .filter(|entry| self.matches(entry))
+            .collect();
+        // Stable, so runs whose times cannot be read keep their order at the start
+        entries.sort_by_key(LogEntry::time);
+
+        if let Some(limit) = self.limit {
+            let end = entries
+                .len()
+                .saturating_sub(self.page.saturating_mul(limit));
+            let start = end.saturating_sub(limit);
+            entries.truncate(end);
+            entries.drain(..start);
+        }
+        entries
+    }
+
+    /// Returns `true` if `entry` passes every filter.
+    pub fn matches(&self, entry: &LogEntry) -> bool {
+        if self
+            .schedule
+            .as_deref()
+            .is_some_and(|name| entry.name() != name)
+        {
+            return false;
+        }
+        if self
+            .valve
+            .as_deref()
+            .is_some_and(|valve| !entry.uses_valve(valve))
+        {
+            return false;
+        }
+        if self
+            .outcome
+            .is_some_and(|outcome| !entry.has_outcome(outcome))
+        {
+            return false;
+        }
+        if self.since.is_some() || self.until.is_some() {
+            let time = match entry.time() {
+                Some(time) => time,
+                None => return false,
+            };
+            if self.since.is_some_and(|since| time < since)
+                || self.until.is_some_and(|until| time >= until)
+            {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+fn is_zero(n: &usize) -> bool {
+    *n == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::{Clock, VirtualClock};
+    use chrono::{Duration, TimeZone, Utc};
+    use std::sync::Arc;
+
+    fn garden_logbook() -> Logbook {
+        let clock = Arc::new(VirtualClock::new(Utc.ymd(2020, 6, 1).and_hms(7, 0, 0)));
+        let mut logbook = Logbook::with_clock(Box::new(Vec::new()), clock.clone());
+        let beds = vec![String::from("beds valve")];
+        let pots = vec![String::from("pots valve")];
+
+        // Day 1, beds run and pots is interrupted
+        let beds_run = logbook
+            .mark_started_by("beds", &beds, Trigger::Schedule)
+            .expect("start succeeds");
+        let pots_run = logbook
+            .mark_started_by("pots", &pots, Trigger::Schedule)
+            .expect("start succeeds");
+        clock.advance(Duration::minutes(20));
+        logbook
+            .mark_completed(beds_run)
+            .expect("complete succeeds");
+        logbook
+            .mark_interrupted(pots_run, "leak")
+            .expect("interrupt succeeds");
+
+        // Day 2, beds is skipped and pots run by hand
+        clock.advance(Duration::days(1));
+        let due = clock.now().with_timezone(&FixedOffset::east(0));
+        logbook
+            .mark_skipped("beds", due, &beds, "rain delay")
+            .expect("skip succeeds");
+        clock.advance(Duration::hours(1));
+        let pots_run = logbook
+            .mark_started_by("pots", &pots, Trigger::Manual)
+            .expect("start succeeds");
+        clock.advance(Duration::minutes(10));
+        logbook
+            .mark_completed(pots_run)
+            .expect("complete succeeds");
+        logbook
+    }
+
+    fn names(entries: &[LogEntry]) -> Vec<String> {
+        entries
+            .iter()
+            .map(|entry| match entry {
+                LogEntry::Run(record) => record.name.clone(),
+                LogEntry::Skipped(skip) => format!("{} skipped", skip.name),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn filters_by_schedule_valve_and_outcome() {
+        let logbook = garden_logbook();
+
+        assert_eq!(
+            names(&logbook.query(&Query::new())),
+            vec!["beds", "pots", "beds skipped", "pots"]
+        );
+        assert_eq!(
+            names(&logbook.query(&Query::new().schedule("beds"))),
+            vec!["beds", "beds skipped"]
+        );
+        assert_eq!(
+            names(&logbook.query(&Query::new().valve("pots valve"))),
+            vec!["pots", "pots"]
+        );
+        assert_eq!(
+            names(&logbook.query(&Query::new().outcome(Outcome::Completed))),
+            vec!["beds", "pots"]
+        );
+        assert_eq!(
+            names(&logbook.query(&Query::new().outcome(Outcome::Interrupted))),
+            vec!["pots"]
+        );
+        assert_eq!(
+            names(&logbook.query(&Query::new().outcome(Outcome::Skipped))),
+            vec!["beds skipped"]
+        );
+        let manual = logbook.query(&Query::new().outcome(Outcome::Manual));
+        assert!(matches!(manual.as_slice(), [LogEntry::Run(record)] if record.trigger() == Trigger::Manual));
+    }
+
+    #[test]
+    fn filters_by_time_window() {
+        let logbook = garden_logbook();
+        let day_2 = FixedOffset::east(0).ymd(2020, 6, 2).and_hms(0, 0, 0);
+
+        assert_eq!(
+            names(&logbook.query(&Query::new().until(day_2))),
+            vec!["beds", "pots"]
+        );
+        assert_eq!(
+            names(&logbook.query(&Query::new().since(day_2).valve("beds valve"))),
+            vec!["beds skipped"]
+        );
+    }
+
+    #[test]
+    fn pages_back_from_newest() {
+        let logbook = garden_logbook();
+
+        assert_eq!(
+            names(&logbook.query(&Query::new().limit(3))),
+            vec!["pots", "beds skipped", "pots"]
+        );
+        assert_eq!(
+            names(&logbook.query(&Query::new().limit(3).page(1))),
+            vec!["beds"]
+        );
+        assert!(logbook.query(&Query::new().limit(3).page(2)).is_empty());
+    }
+
+    #[test]
+    fn query_round_trips_with_defaults_omitted() {
+        let query = Query::new()
+            .schedule("beds")
+            .outcome(Outcome::Skipped)
+            .limit(5);
+
+        let json = serde_json::to_string(&query).expect("serialize succeeds");
+
+        assert_eq!(json, r#"{"schedule":"beds","outcome":"skipped","limit":5}"#);
+        assert_eq!(
+            serde_json::from_str::<Query>(&json).expect("deserialize succeeds"),
+            query
+        );
+    }
+}
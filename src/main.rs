@@ -1,114 +1,282 @@
 extern crate log;
 extern crate pretty_env_logger;
-extern crate sysfs_gpio;
 
-#[macro_use]
-extern crate static_assertions;
-
-mod constants;
-mod logbook;
-mod valve;
-mod calendar;
-mod config_persist;
-mod environment;
-
-use tokio::prelude::*;
-use tokio::sync::mpsc::{Receiver, Sender};
-use tokio::sync::mpsc;
-
-use environment::{Environment, Service, ServiceKit, Any, AsAny};
-use std::sync::{Arc, Weak};
+use chrono::{DateTime, Utc};
+use log::{info, warn};
+use rustic_garden::calendar::DeletePolicy;
+use rustic_garden::clock::{Clock, SystemClock};
+use rustic_garden::config_watcher::ConfigWatcher;
+use rustic_garden::environment::{
+    Any, AsAny, Environment, EnvironmentBuilder, Lifecycle, Service, ServiceHealth, ServiceKit,
+};
+use rustic_garden::error::{self, Error};
+use rustic_garden::garden::{Garden, GardenState};
+use rustic_garden::protocol::{AlarmStatus, ErrorCode, Request, Response, ValveStatus};
+use rustic_garden::settings::{self, Settings};
+use rustic_garden::statistics::{Period, Statistics};
+use rustic_garden::taskmaster::Taskmaster;
+use rustic_garden::{cli, constants};
+use std::io;
+use std::process;
+use std::sync::Arc;
+use tokio::signal::unix::{signal, SignalKind};
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     // Bootstrapping
-    pretty_env_logger::init();
-
-    // Create our environment
-    let (env_owned, env) = Environment::bootstrap();
-    let env = unsafe { &mut *env };
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let (settings, args) = match Settings::from_args(&args) {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(2);
+        }
+    };
+    if args.first().map(String::as_str) == Some("--help") {
+        println!("{}", settings::USAGE);
+        return Ok(());
+    }
+    pretty_env_logger::formatted_builder()
+        .parse_filters(&settings.log_level)
+        .init();
 
-    // Special shutdown handling
-    let (sender, mut shutdown_receiver) = mpsc::channel::<bool>(100);
+    // One-shot subcommands run without starting any services
+    let clock: Arc<dyn Clock> = Arc::new(SystemClock);
+    if let Some(result) = cli::run(&settings, &clock, &args) {
+        return result;
+    }
 
-    // Start root services
-    let kit = ServiceKit::with_env(env_owned, env)
-        .with_dep::<RequestHandler>()
-        .with_dep::<Taskmaster>()
-        .new();
+    // Create our environment
+    let mut builder = EnvironmentBuilder::with_settings(clock, settings);
 
-    env.finish_bootstrap();
+    // Register root services, then start them all
+    builder
+        .register::<RequestHandler>()?
+        .register::<Taskmaster>()?
+        .register::<ConfigWatcher>()?;
+    let env = builder.build();
+    if let Err(e) = Environment::start(&env).await {
+        Environment::stop(&env).await;
+        return Err(e.into());
+    }
 
-    shutdown_receiver.recv().await;
+    // Run until asked to stop, then stop services before the ones they depend on
+    let mut terminate = signal(SignalKind::terminate())?;
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = terminate.recv() => {}
+    }
+    info!("shutting down");
+    Environment::stop(&env).await;
 
     Ok(())
 }
 
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
 use tokio::net::{TcpListener, TcpStream};
 
 // Services UI inbound requests
-struct RequestHandler {
-    kit: ServiceKit,
-}
+struct RequestHandler {}
 
 impl RequestHandler {
-    async fn handle_request(socket: TcpStream) {
-        // Do some stuff, respond to ui, use channels etc
+    /// Accepts control connections until the listener fails.
+    async fn serve(mut listener: TcpListener, env: Arc<Environment>) -> io::Result<()> {
+        loop {
+            let (socket, _) = listener.accept().await?;
+            let env = Arc::clone(&env);
+            tokio::spawn(async move {
+                if let Err(e) = RequestHandler::handle_request(socket, env).await {
+                    warn!("control connection failed: {}", e);
+                }
+            });
+        }
     }
-}
 
-impl Service for RequestHandler {
-    fn start(env_owned: Arc<Environment>, env: &mut Environment) -> RequestHandler {
-        let inst = RequestHandler {
-            kit: ServiceKit::with_env(env_owned, env).new(),
-        };
+    async fn handle_request(mut socket: TcpStream, env: Arc<Environment>) -> io::Result<()> {
+        let (reader, mut writer) = socket.split();
+        let mut lines = BufReader::new(reader).lines();
 
-        tokio::spawn(async move {
-            let mut listener = TcpListener::bind("127.0.0.1:7292").await.expect("bind address succeeds");
+        while let Some(line) = lines.next_line().await? {
+            let response = match serde_json::from_str::<Request>(&line) {
+                Ok(request) => {
+                    let garden = env.get::<Garden>();
+                    let mut state = garden.lock();
+                    let now = env.clock().now();
+                    let response =
+                        RequestHandler::respond(&mut state, request, &env.health(), now);
+                    state.apply_config_changes();
+                    response
+                }
+                Err(e) => Response::Error {
+                    message: format!("malformed request: {}", e),
+                    code: Some(ErrorCode::MalformedRequest),
+                },
+            };
 
-            loop {
-                let (socket, _) = listener.accept().await.unwrap();
-                tokio::spawn(async move {
-                    RequestHandler::handle_request(socket).await;
-                });
-            }
-        });
-        
-        return inst;
+            let mut reply = serde_json::to_string(&response)?;
+            reply.push('\n');
+            writer.write_all(reply.as_bytes()).await?;
+        }
+
+        Ok(())
     }
 
-    fn name() -> &'static str {
-        "RequestHandler"
+    fn respond(
+        state: &mut GardenState,
+        request: Request,
+        services: &[ServiceHealth],
+        now: DateTime<Utc>,
+    ) -> Response {
+        let result = match request {
+            Request::Alarms => {
+                let alarms = state
+                    .logbook
+                    .iter_active_alarms()
+                    .map(|(id, alarm)| AlarmStatus {
+                        id,
+                        alarm: alarm.clone(),
+                    })
+                    .collect();
+                return Response::Alarms { alarms };
+            }
+            Request::AcknowledgeAlarm { id } => state.logbook.acknowledge_alarm(id),
+            Request::Conflicts { days } => {
+                let days = days.unwrap_or(constants::CONFLICT_CHECK_DAYS);
+                let conflicts = state.calendar.find_conflicts(now, days);
+                return Response::Conflicts { conflicts };
+            }
+            Request::Upcoming { days, valve } => {
+                let days = days.unwrap_or(constants::UPCOMING_DAYS);
+                let occurrences = state.calendar.upcoming(now, days, valve.as_deref());
+                return Response::Upcoming { occurrences };
+            }
+            Request::SaveSchedule { schedule } => {
+                match state.calendar.create_or_replace_schedule(schedule) {
+                    Ok(warnings) => return Response::ScheduleSaved { warnings },
+                    Err(e) => Err(e),
+                }
+            }
+            Request::Schedules => {
+                let schedules = state.calendar.list().collect();
+                return Response::Schedules { schedules };
+            }
+            Request::DeleteSchedule { name } => state.calendar.delete_schedule(&name),
+            Request::RunSchedule { name } => match Taskmaster::run_now(state, &name, now) {
+                Ok(run) => return Response::RunStarted { run },
+                Err(e) => Err(e),
+            },
+            Request::Valves => {
+                let open = match state.valves.open_valves() {
+                    Ok(open) => open,
+                    Err(e) => return Response::from(e),
+                };
+                let valves = state
+                    .calendar
+                    .valves()
+                    .map(|valve| ValveStatus {
+                        valve: valve.clone(),
+                        open: open.contains(&valve.name),
+                    })
+                    .collect();
+                return Response::Valves { valves };
+            }
+            Request::OpenValve { name } => RequestHandler::set_valve(state, &name, true),
+            Request::CloseValve { name } => RequestHandler::set_valve(state, &name, false),
+            Request::SaveValve { valve } => state.calendar.create_or_replace_valve(valve),
+            Request::DeleteValve { name, policy } => state
+                .calendar
+                .delete_valve(&name, policy.unwrap_or(DeletePolicy::Fail)),
+            Request::Logbook { mut query } => {
+                query.limit = query.limit.or(Some(constants::LOGBOOK_TAIL));
+                return Response::Entries {
+                    entries: state.logbook.query(&query),
+                };
+            }
+            Request::Report {
+                from,
+                until,
+                period,
+            } => {
+                let statistics = Statistics {
+                    from,
+                    until,
+                    period: period.unwrap_or(Period::Day),
+                };
+                return Response::Report {
+                    report: statistics.compile(&state.logbook, &state.calendar, now),
+                };
+            }
+            Request::Status => return RequestHandler::status(state, services, now),
+        };
+
+        match result {
+            Ok(()) => Response::Ok,
+            Err(e) => Response::from(e),
+        }
     }
 }
 
-impl AsAny for RequestHandler {
-    fn as_any(&self) -> &dyn Any {
-        self
+impl RequestHandler {
+    /// What the garden is doing, and how the daemon's `services` are.
+    fn status(state: &GardenState, services: &[ServiceHealth], now: DateTime<Utc>) -> Response {
+        Response::Status {
+            open_valves: state.valves.open_valves().unwrap_or_default(),
+            running: state.running.values().cloned().collect(),
+            next: state
+                .calendar
+                .upcoming(now, constants::UPCOMING_DAYS, None)
+                .into_iter()
+                .next(),
+            active_alarms: state.logbook.iter_active_alarms().count(),
+            dry_run: state.is_dry_run(),
+            services: services.to_vec(),
+            timezone: state.calendar.timezone().map(|tz| String::from(tz.name())),
+        }
+    }
+
+    fn set_valve(state: &mut GardenState, name: &str, open: bool) -> error::Result<()> {
+        let valve = match state.valves.get_mut(name) {
+            Some(valve) => valve,
+            None => return Err(Error::ValveNotFound(String::from(name))),
+        };
+        info!("{} {} by hand", if open { "opening" } else { "closing" }, name);
+        if open {
+            valve.open()
+        } else {
+            valve.close()
+        }
     }
 }
 
-// Background checking of any needed schedule fulfilments
-struct Taskmaster {}
-impl Taskmaster {}
+impl Service for RequestHandler {
+    fn create(builder: &mut EnvironmentBuilder) -> io::Result<RequestHandler> {
+        ServiceKit::with_builder(builder)
+            .with_dep::<Garden>()?
+            .new();
+        Ok(RequestHandler {})
+    }
 
-impl Service for Taskmaster {
-    fn start(env_owned: Arc<Environment>, env: &mut Environment) -> Taskmaster {
-        tokio::spawn(async move {
-            // Poll some file state about schedules
-            // Launch schedules
-            // Compute wakeup
-        });
+    fn start<'a>(&'a self, env: &'a Arc<Environment>) -> Lifecycle<'a> {
+        Box::pin(async move {
+            // Bind before reporting ready, so a taken address fails the start
+            let address = &env.settings().bind;
+            let listener = TcpListener::bind(address).await?;
+            info!("listening for control requests on {}", address);
 
-        Taskmaster {}
+            env.spawn(
+                RequestHandler::name(),
+                RequestHandler::serve(listener, Arc::clone(env)),
+            );
+            Ok(())
+        })
     }
 
     fn name() -> &'static str {
-        "Taskmaster"
+        "RequestHandler"
     }
 }
 
-impl AsAny for Taskmaster {
+impl AsAny for RequestHandler {
     fn as_any(&self) -> &dyn Any {
         self
     }
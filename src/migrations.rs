@@ -0,0 +1,161 @@
+extern crate log;
+
+use super::error::{Error, Result};
+use log::info;
+use serde_yaml::{Mapping, Value};
+
+/// The schedule schema version written by this build.
+pub const SCHEMA_VERSION: &str = "0.2";
+
+/// An upgrade of a schedule file from one schema version to the next.
+struct Migration {
+    from: &'static str,
+    to: &'static str,
+    apply: fn(&mut Mapping) -> Result<()>,
+}
+
+/// Every upgrade step, oldest first. Each step's `to` is the next step's `from`.
+const MIGRATIONS: &[Migration] = &[Migration {
+    from: "0.1",
+    to: "0.2",
+    apply: single_start_to_list,
+}];
+
+/// Upgrades a parsed schedule file to `SCHEMA_VERSION` in place.
+///
+/// Returns the version the file was written in if it had to be upgraded, or `None` if it was
+/// already current. A file with no `version` is taken to be the oldest schema. Files written by
+/// a newer build, or with a version this build never knew, are refused.
+pub fn migrate(config: &mut Value) -> Result<Option<String>> {
+    let config = match config.as_mapping_mut() {
+        Some(mapping) => mapping,
+        None => return Err(invalid("schedule file is not a mapping")),
+    };
+
+    let original = match config.get(&key("version")) {
+        Some(Value::String(version)) => version.clone(),
+        Some(other) => return Err(invalid(&format!("invalid version {:?}", other))),
+        None => String::from(MIGRATIONS[0].from),
+    };
+    if original == SCHEMA_VERSION {
+        return Ok(None);
+    }
+    if is_newer(&original, SCHEMA_VERSION) {
+        return Err(invalid(&format!(
+            "schedule file version {} is newer than the supported version {}, upgrade \
+             rustic-garden to load it",
+            original, SCHEMA_VERSION
+        )));
+    }
+
+    let first = MIGRATIONS
+        .iter()
+        .position(|migration| migration.from == original)
+        .ok_or_else(|| invalid(&format!("unknown schedule file version {}", original)))?;
+    for migration in &MIGRATIONS[first..] {
+        info!("migrating schedules from {} to {}", migration.from, migration.to);
+        (migration.apply)(config)?;
+        match config.get_mut(&key("version")) {
+            Some(version) => *version = key(migration.to),
+            None => {
+                config.insert(key("version"), key(migration.to));
+            }
+        }
+    }
+
+    Ok(Some(original))
+}
+
+/// 0.1 to 0.2: schedules can start several times a day, `start_offset_min` becomes the list
+/// `start_offsets_min`.
+fn single_start_to_list(config: &mut Mapping) -> Result<()> {
+    let schedules = match config.get_mut(&key("schedules")) {
+        Some(Value::Sequence(schedules)) => schedules,
+        _ => return Ok(()),
+    };
+
+    for schedule in schedules {
+        let schedule = match schedule.as_mapping_mut() {
+            Some(mapping) => mapping,
+            None => return Err(invalid("schedule is not a mapping")),
+        };
+        if let Some(offset) = schedule.remove(&key("start_offset_min")) {
+            schedule.insert(key("start_offsets_min"), Value::Sequence(vec![offset]));
+        }
+    }
+    Ok(())
+}
+
+/// Returns `true` if dotted version `a` is later than `b`, comparing numerically.
+fn is_newer(a: &str, b: &str) -> bool {
+    let parse = |version: &str| -> Vec<u64> {
+        version
+            .split('.')
+            .map(|part| part.parse().unwrap_or(0))
+            .collect()
+    };
+    parse(a) > parse(b)
+}
+
+fn key(name: &str) -> Value {
+    Value::String(String::from(name))
+}
+
+fn invalid(message: &str) -> Error {
+    Error::InvalidSchedule(String::from(message))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn single_start_becomes_list() {
+        let mut config: Value = serde_yaml::from_str(
+            "version: \"0.1\"\nvalves: []\nschedules:\n  - name: a\n    start_offset_min: 420\n",
+        )
+        .expect("yaml parses");
+
+        assert_eq!(
+            migrate(&mut config).expect("migrate succeeds"),
+            Some(String::from("0.1"))
+        );
+
+        let expected: Value = serde_yaml::from_str(&format!(
+            "version: \"{}\"\nvalves: []\nschedules:\n  - name: a\n    start_offsets_min: [420]\n",
+            SCHEMA_VERSION
+        ))
+        .expect("yaml parses");
+        assert_eq!(config, expected);
+    }
+
+    #[test]
+    fn current_version_is_untouched() {
+        let yaml = format!("version: \"{}\"\nschedules: []\n", SCHEMA_VERSION);
+        let mut config: Value = serde_yaml::from_str(&yaml).expect("yaml parses");
+        let original = config.clone();
+
+        assert_eq!(migrate(&mut config).expect("migrate succeeds"), None);
+        assert_eq!(config, original);
+    }
+
+    #[test]
+    fn newer_version_is_refused() {
+        let mut config: Value =
+            serde_yaml::from_str("version: \"1.10\"\nschedules: []\n").expect("yaml parses");
+
+        let error = migrate(&mut config).expect_err("migrate fails");
+        assert!(error.to_string().contains("newer"));
+    }
+
+    #[test]
+    fn missing_version_is_oldest() {
+        let mut config: Value =
+            serde_yaml::from_str("schedules: []\n").expect("yaml parses");
+
+        assert_eq!(
+            migrate(&mut config).expect("migrate succeeds"),
+            Some(String::from(MIGRATIONS[0].from))
+        );
+    }
+}
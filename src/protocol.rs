@@ -0,0 +1,320 @@
+use super::calendar::{DeletePolicy, Occurrence, Schedule};
+use super::config_persist::ValvePersist;
+use super::conflicts::Conflict;
+use super::environment::ServiceHealth;
+use super::error::Error;
+use super::logbook::Alarm;
+use super::logbook_query::{LogEntry, Query};
+use super::statistics::{Period, WateringReport};
+use chrono::NaiveDate;
+use serde::{Deserialize, Serialize};
+
+/// A request sent to the daemon's control port, one JSON object per line.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum Request {
+    /// Lists the unacknowledged alarms.
+    Alarms,
+
+    /// Acknowledges an alarm by id, allowing watering to resume.
+    AcknowledgeAlarm { id: usize },
+
+    /// Finds conflicts between schedules over the next `days` days.
+    Conflicts { days: Option<u32> },
+
+    /// Lists the runs starting in the next `days` days, optionally only those opening `valve`.
+    Upcoming {
+        days: Option<u32>,
+        valve: Option<String>,
+    },
+
+    /// Lists the schedules.
+    Schedules,
+
+    /// Adds a new schedule or overwrites an existing one with the same name.
+    SaveSchedule { schedule: Schedule },
+
+    /// Removes a schedule.
+    DeleteSchedule { name: String },
+
+    /// Starts a schedule right away, for its usual duration.
+    RunSchedule { name: String },
+
+    /// Lists the valves and whether each is open.
+    Valves,
+
+    /// Opens a valve by hand, it stays open until closed.
+    OpenValve { name: String },
+
+    /// Closes a valve by hand.
+    CloseValve { name: String },
+
+    /// Adds a new valve or overwrites an existing one with the same name.
+    SaveValve { valve: ValvePersist },
+
+    /// Removes a valve. Schedules still using it make this fail unless `policy` is `cascade`.
+    DeleteValve {
+        name: String,
+        policy: Option<DeletePolicy>,
+    },
+
+    /// Searches the logbook. Without a `limit` only the most recent few matches are listed.
+    Logbook {
+        #[serde(flatten)]
+        query: Query,
+    },
+
+    /// Compiles watering statistics for the days `from` to `until`, totalled by `period`.
+    Report {
+        from: NaiveDate,
+        until: NaiveDate,
+        period: Option<Period>,
+    },
+
+    /// Summarizes what the garden is doing.
+    Status,
+}
+
+/// The daemon's reply to a `Request`, one JSON object per line.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "response", rename_all = "snake_case")]
+pub enum Response {
+    /// The unacknowledged alarms.
+    Alarms { alarms: Vec<AlarmStatus> },
+
+    /// Conflicts between schedules.
+    Conflicts { conflicts: Vec<Conflict> },
+
+    /// Runs starting soon, in start order.
+    Upcoming { occurrences: Vec<Occurrence> },
+
+    /// The schedules, sorted by name.
+    Schedules { schedules: Vec<Schedule> },
+
+    /// The schedule was saved, possibly conflicting with others.
+    ScheduleSaved { warnings: Vec<Conflict> },
+
+    /// A run was started by hand.
+    RunStarted { run: Occurrence },
+
+    /// The valves, sorted by name.
+    Valves { valves: Vec<ValveStatus> },
+
+    /// Logbook runs and skipped runs, oldest first.
+    Entries { entries: Vec<LogEntry> },
+
+    /// Watering statistics.
+    Report { report: WateringReport },
+
+    /// What the garden is doing.
+    Status {
+        /// Valves currently open, sorted by name.
+        open_valves: Vec<String>,
+
+        /// Runs in progress.
+        running: Vec<Occurrence>,
+
+        /// The next run to start, if any is scheduled in the coming week.
+        next: Option<Occurrence>,
+
+        /// The number of unacknowledged alarms.
+        active_alarms: usize,
+
+        /// Valves are not driven, actions are only logged.
+        #[serde(default)]
+        dry_run: bool,
+
+        /// How each of the daemon's services is doing.
+        #[serde(default)]
+        services: Vec<ServiceHealth>,
+
+        /// The garden's IANA timezone, or `None` if it runs on the daemon's local time.
+        #[serde(default)]
+        timezone: Option<String>,
+    },
+
+    /// The request succeeded with nothing to report.
+    Ok,
+
+    /// The request failed.
+    Error {
+        message: String,
+
+        /// What kind of failure it was, left out by daemons from before codes were sent.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        code: Option<ErrorCode>,
+    },
+}
+
+impl From<Error> for Response {
+    fn from(e: Error) -> Response {
+        Response::Error {
+            code: Some(ErrorCode::from(&e)),
+            message: e.to_string(),
+        }
+    }
+}
+
+/// Why a request failed, for clients that handle some failures differently.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// The request was not valid JSON or not a known command.
+    MalformedRequest,
+
+    /// No schedule has the name given.
+    ScheduleNotFound,
+
+    /// No valve has the name given.
+    ValveNotFound,
+
+    /// No alarm has the id given.
+    AlarmNotFound,
+
+    /// The alarm was already acknowledged.
+    AlarmAlreadyAcknowledged,
+
+    /// The schedule or valve change would leave the schedules invalid.
+    InvalidSchedule,
+
+    /// The schedule is already running.
+    AlreadyRunning,
+
+    /// The run is not in progress.
+    NotRunning,
+
+    /// Watering is stopped until the active alarms are acknowledged.
+    AlarmActive,
+
+    /// A GPIO pin could not be used.
+    Gpio,
+
+    /// The daemon could not read or write its files or database.
+    Storage,
+
+    /// A file could not be parsed.
+    Parse,
+}
+
+impl From<&Error> for ErrorCode {
+    fn from(e: &Error) -> ErrorCode {
+        match e {
+            Error::ScheduleNotFound(_) => ErrorCode::ScheduleNotFound,
+            Error::ValveNotFound(_) => ErrorCode::ValveNotFound,
+            Error::AlarmNotFound(_) => ErrorCode::AlarmNotFound,
+            Error::AlarmAlreadyAcknowledged(_) => ErrorCode::AlarmAlreadyAcknowledged,
+            Error::InvalidSchedule(_) => ErrorCode::InvalidSchedule,
+            Error::AlreadyRunning(_) => ErrorCode::AlreadyRunning,
+            Error::RunNotFound(_) | Error::NotRunning(_) => ErrorCode::NotRunning,
+            Error::AlarmActive => ErrorCode::AlarmActive,
+            Error::Gpio { .. } => ErrorCode::Gpio,
+            Error::Storage(_) => ErrorCode::Storage,
+            Error::Parse { .. } => ErrorCode::Parse,
+        }
+    }
+}
+
+/// An active alarm and the id used to acknowledge it.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct AlarmStatus {
+    pub id: usize,
+
+    #[serde(flatten)]
+    pub alarm: Alarm,
+}
+
+/// A configured valve and whether it is open.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ValveStatus {
+    #[serde(flatten)]
+    pub valve: ValvePersist,
+
+    pub open: bool,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::environment::Health;
+
+    #[test]
+    fn request_uses_command_tag() {
+        let request: Request =
+            serde_json::from_str(r#"{"command":"acknowledge_alarm","id":3}"#)
+                .expect("deserialize succeeds");
+
+        assert_eq!(request, Request::AcknowledgeAlarm { id: 3 });
+    }
+
+    #[test]
+    fn logbook_request_accepts_a_bare_limit() {
+        let request: Request = serde_json::from_str(r#"{"command":"logbook","limit":5}"#)
+            .expect("deserialize succeeds");
+
+        assert_eq!(
+            request,
+            Request::Logbook {
+                query: Query::new().limit(5)
+            }
+        );
+    }
+
+    #[test]
+    fn error_response_round_trips() {
+        let response = Response::from(Error::ScheduleNotFound(String::from("pots")));
+
+        let line = serde_json::to_string(&response).expect("serialize succeeds");
+
+        assert_eq!(
+            line,
+            r#"{"response":"error","message":"no schedule named pots","code":"schedule_not_found"}"#
+        );
+        assert_eq!(
+            serde_json::from_str::<Response>(&line).expect("deserialize succeeds"),
+            response
+        );
+    }
+
+    #[test]
+    fn acknowledging_twice_has_its_own_code() {
+        let response = Response::from(Error::AlarmAlreadyAcknowledged(3));
+
+        assert_eq!(
+            serde_json::to_string(&response).expect("serialize succeeds"),
+            r#"{"response":"error","message":"alarm 3 is already acknowledged","code":"alarm_already_acknowledged"}"#
+        );
+    }
+
+    #[test]
+    fn error_response_without_code_deserializes() {
+        let response: Response =
+            serde_json::from_str(r#"{"response":"error","message":"any message"}"#)
+                .expect("deserialize succeeds");
+
+        assert_eq!(
+            response,
+            Response::Error {
+                message: String::from("any message"),
+                code: None,
+            }
+        );
+    }
+
+    #[test]
+    fn status_lists_service_health() {
+        let line = r#"{"response":"status","open_valves":[],"running":[],"next":null,"active_alarms":0,"services":[{"name":"RequestHandler","state":"failed","error":"address in use"}]}"#;
+
+        match serde_json::from_str::<Response>(line).expect("deserialize succeeds") {
+            Response::Status { services, .. } => assert_eq!(
+                services,
+                vec![ServiceHealth {
+                    name: String::from("RequestHandler"),
+                    health: Health::Failed {
+                        error: String::from("address in use")
+                    },
+                }]
+            ),
+            other => panic!("unexpected response {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,41 @@
+//! Scratch directories for tests that touch the filesystem.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+static NEXT_DIR: AtomicUsize = AtomicUsize::new(0);
+
+/// An empty directory for one test, removed with its contents when dropped.
+///
+/// Names include the process id and a counter, so tests running in parallel, or concurrent runs
+/// of the test binary, never share a directory.
+pub struct ScratchDir {
+    path: PathBuf,
+}
+
+impl ScratchDir {
+    pub fn new(test: &str) -> ScratchDir {
+        let n = NEXT_DIR.fetch_add(1, Ordering::Relaxed);
+        let name = format!("rustic-garden-{}-{}-{}", test, process::id(), n);
+        let path = std::env::temp_dir().join(name);
+        fs::create_dir_all(&path).expect("create scratch dir succeeds");
+        ScratchDir { path }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The path of `name` inside the directory.
+    pub fn join(&self, name: &str) -> PathBuf {
+        self.path.join(name)
+    }
+}
+
+impl Drop for ScratchDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
@@ -0,0 +1,285 @@
+use super::constants::{
+    ARCHIVE_DIR, CONTROL_ADDRESS, DATABASE_FILE, DATA_DIR, JOURNAL_FILE, LOGBOOK_FILE, LOG_LEVEL,
+    SCHEDULE_FILE, SETTINGS_PATH,
+};
+use super::logbook::{OverlapPolicy, Retention};
+use super::valve::Backend;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+pub const USAGE: &str = "\
+usage: rustic-garden [options] [upcoming|simulate|check|export|import|import-sqlite ...]
+
+options:
+  --config PATH       daemon settings file             RUSTIC_GARDEN_CONFIG
+  --data-dir DIR      directory of schedule.yaml and   RUSTIC_GARDEN_DATA_DIR
+                      logbook.yaml
+  --bind HOST:PORT    control protocol address         RUSTIC_GARDEN_BIND
+  --log-level LEVEL   error, warn, info, debug, trace  RUSTIC_GARDEN_LOG_LEVEL
+  --gpio BACKEND      gpio, or mock for no hardware    RUSTIC_GARDEN_GPIO
+  --dry-run           log valve actions only           RUSTIC_GARDEN_DRY_RUN
+  --storage STORAGE   yaml, or sqlite for garden.db    RUSTIC_GARDEN_STORAGE";
+
+/// How the daemon is set up on this machine.
+///
+/// Settings are read from a YAML file, then overridden by environment variables, then by
+/// command-line options. Anything not given keeps its default.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Settings {
+    /// Directory holding `schedule.yaml` and `logbook.yaml`.
+    pub data_dir: PathBuf,
+
+    /// Address the control protocol listens on, as `HOST:PORT`.
+    pub bind: String,
+
+    /// Log filter, a level or `env_logger` directives such as `info,rustic_garden=debug`.
+    pub log_level: String,
+
+    /// How valves are driven.
+    pub gpio: Backend,
+
+    /// Valves are never driven, actions are only logged.
+    pub dry_run: bool,
+
+    /// When old logbook records are moved to the archive.
+    pub retention: Retention,
+
+    /// Where schedules and the logbook are kept.
+    pub storage: Storage,
+
+    /// What happens when a schedule is due or run by hand while a run of it is in progress.
+    pub overlap: OverlapPolicy,
+}
+
+/// Where schedules and the logbook are kept.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Storage {
+    /// `schedule.yaml`, `logbook.yaml` and the logbook journal in the data directory.
+    Yaml,
+
+    /// An SQLite database in the data directory, needs the `sqlite` feature.
+    Sqlite,
+}
+
+impl Default for Settings {
+    fn default() -> Settings {
+        Settings {
+            data_dir: PathBuf::from(DATA_DIR),
+            bind: String::from(CONTROL_ADDRESS),
+            log_level: String::from(LOG_LEVEL),
+            gpio: Backend::Gpio,
+            dry_run: false,
+            retention: Retention::default(),
+            storage: Storage::Yaml,
+            overlap: OverlapPolicy::Reject,
+        }
+    }
+}
+
+impl Settings {
+    /// Reads the settings for this process from leading `args`, the environment and the settings
+    /// file, returning them with the arguments left over after the options.
+    pub fn from_args(args: &[String]) -> io::Result<(Settings, Vec<String>)> {
+        Settings::resolve(args, &|name| std::env::var(name).ok())
+    }
+
+    /// Like `from_args`, looking up environment variables with `var`.
+    fn resolve(
+        args: &[String],
+        var: &dyn Fn(&str) -> Option<String>,
+    ) -> io::Result<(Settings, Vec<String>)> {
+        let mut options = Vec::new();
+        let mut rest = args.iter();
+        let mut remaining = Vec::new();
+        while let Some(arg) = rest.next() {
+            if arg == "--dry-run" {
+                options.push((arg.as_str(), String::from("true")));
+            } else if arg.starts_with("--") && arg != "--help" {
+                let value = rest
+                    .next()
+                    .ok_or_else(|| invalid(format!("{} requires a value", arg)))?;
+                options.push((arg.as_str(), value.clone()));
+            } else {
+                remaining.push(arg.clone());
+                remaining.extend(rest.cloned());
+                break;
+            }
+        }
+
+        let option = |flag: &str| {
+            options
+                .iter()
+                .rev()
+                .find(|(name, _)| *name == flag)
+                .map(|(_, value)| value.clone())
+        };
+        let mut settings = match option("--config").or_else(|| var("RUSTIC_GARDEN_CONFIG")) {
+            Some(path) => Settings::read(&path)?,
+            None => match fs::read_to_string(SETTINGS_PATH) {
+                Ok(source) => Settings::parse(&source)?,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => Settings::default(),
+                Err(e) => return Err(e),
+            },
+        };
+
+        for (flag, variable) in &[
+            ("--data-dir", "RUSTIC_GARDEN_DATA_DIR"),
+            ("--bind", "RUSTIC_GARDEN_BIND"),
+            ("--log-level", "RUSTIC_GARDEN_LOG_LEVEL"),
+            ("--gpio", "RUSTIC_GARDEN_GPIO"),
+            ("--dry-run", "RUSTIC_GARDEN_DRY_RUN"),
+            ("--storage", "RUSTIC_GARDEN_STORAGE"),
+        ] {
+            if let Some(value) = var(variable) {
+                settings.set(flag, &value)?;
+            }
+        }
+        for (flag, value) in options.iter().filter(|(flag, _)| *flag != "--config") {
+            settings.set(flag, value)?;
+        }
+        Ok((settings, remaining))
+    }
+
+    /// Reads settings from the YAML file at `path`.
+    pub fn read(path: &str) -> io::Result<Settings> {
+        Settings::parse(&fs::read_to_string(path)?)
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path, e)))
+    }
+
+    fn parse(source: &str) -> io::Result<Settings> {
+        serde_yaml::from_str(source).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    /// Sets the setting named by a command-line `flag`.
+    fn set(&mut self, flag: &str, value: &str) -> io::Result<()> {
+        match flag {
+            "--data-dir" => self.data_dir = PathBuf::from(value),
+            "--bind" => self.bind = String::from(value),
+            "--log-level" => self.log_level = String::from(value),
+            "--gpio" => {
+                self.gpio = match value {
+                    "gpio" => Backend::Gpio,
+                    "mock" => Backend::Mock,
+                    _ => return Err(invalid(format!("unknown GPIO backend {}", value))),
+                }
+            }
+            "--dry-run" => {
+                self.dry_run = match value {
+                    "true" | "1" | "yes" => true,
+                    "false" | "0" | "no" | "" => false,
+                    _ => return Err(invalid(format!("invalid dry run flag {}", value))),
+                }
+            }
+            "--storage" => {
+                self.storage = match value {
+                    "yaml" => Storage::Yaml,
+                    "sqlite" => Storage::Sqlite,
+                    _ => return Err(invalid(format!("unknown storage {}", value))),
+                }
+            }
+            _ => return Err(invalid(format!("unknown option {}\n\n{}", flag, USAGE))),
+        }
+        Ok(())
+    }
+
+    /// The path of the schedule file.
+    pub fn schedule_path(&self) -> PathBuf {
+        self.data_dir.join(SCHEDULE_FILE)
+    }
+
+    /// The path of the logbook file.
+    pub fn logbook_path(&self) -> PathBuf {
+        self.data_dir.join(LOGBOOK_FILE)
+    }
+
+    /// The path of the logbook journal, changes since the logbook was last written.
+    pub fn journal_path(&self) -> PathBuf {
+        self.data_dir.join(JOURNAL_FILE)
+    }
+
+    /// The path of the SQLite database used with `Storage::Sqlite`.
+    pub fn database_path(&self) -> PathBuf {
+        self.data_dir.join(DATABASE_FILE)
+    }
+
+    /// The directory old logbook records are archived to.
+    pub fn archive_dir(&self) -> PathBuf {
+        self.data_dir.join(ARCHIVE_DIR)
+    }
+
+    /// How valves are actually driven, never through GPIO in dry run.
+    pub fn backend(&self) -> Backend {
+        if self.dry_run {
+            Backend::DryRun
+        } else {
+            self.gpio
+        }
+    }
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scratch::ScratchDir;
+
+    #[test]
+    fn options_override_environment_override_file() {
+        let dir = ScratchDir::new("settings");
+        let path = dir.join("settings.yaml");
+        fs::write(&path, "data_dir: /var/lib/garden\nbind: 0.0.0.0:7000\nlog_level: debug\n")
+            .expect("write succeeds");
+        let config = path.to_string_lossy().into_owned();
+        let var = |name: &str| match name {
+            "RUSTIC_GARDEN_CONFIG" => Some(config.clone()),
+            "RUSTIC_GARDEN_BIND" => Some(String::from("0.0.0.0:7100")),
+            "RUSTIC_GARDEN_LOG_LEVEL" => Some(String::from("warn")),
+            _ => None,
+        };
+
+        let (settings, rest) = Settings::resolve(
+            &args(&["--log-level", "trace", "--dry-run", "check", "--schedule", "a.yaml"]),
+            &var,
+        )
+        .expect("settings resolve");
+
+        assert_eq!(
+            settings,
+            Settings {
+                data_dir: PathBuf::from("/var/lib/garden"),
+                bind: String::from("0.0.0.0:7100"),
+                log_level: String::from("trace"),
+                gpio: Backend::Gpio,
+                dry_run: true,
+                retention: Retention::default(),
+                storage: Storage::Yaml,
+                overlap: OverlapPolicy::Reject,
+            }
+        );
+        assert_eq!(rest, args(&["check", "--schedule", "a.yaml"]));
+        assert_eq!(settings.schedule_path(), PathBuf::from("/var/lib/garden/schedule.yaml"));
+        assert_eq!(settings.backend(), Backend::DryRun);
+    }
+
+    #[test]
+    fn invalid_values_are_rejected() {
+        let none = |_: &str| None;
+
+        assert!(Settings::resolve(&args(&["--gpio", "pigpio"]), &none).is_err());
+        assert!(Settings::resolve(&args(&["--bind"]), &none).is_err());
+        assert!(Settings::resolve(&args(&["--port", "7000"]), &none).is_err());
+        assert!(Settings::resolve(&args(&["--storage", "postgres"]), &none).is_err());
+        assert!(Settings::parse("data_dir: /srv\nport: 7000\n").is_err());
+    }
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| String::from(*arg)).collect()
+    }
+}
@@ -0,0 +1,303 @@
+extern crate log;
+
+use super::calendar::Calendar;
+use super::clock::{Clock, VirtualClock};
+use super::error::{self, Error};
+use super::flow_meter::{FlowMeter, PulseCounter};
+use super::garden::GardenState;
+use super::logbook::Logbook;
+use super::taskmaster::Taskmaster;
+use super::valve::{Backend, Valves};
+use chrono::{Duration, NaiveDate, Utc};
+use log::info;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::sync::Arc;
+
+/// Pulses per liter of the meter simulated when the schedule does not configure one.
+const SIMULATED_PULSES_PER_LITER: f64 = 1000.0;
+
+/// Daily rainfall, read from CSV lines of `date,rain_mm`.
+///
+/// Dates are written as `YYYY-MM-DD`. A header line and blank lines are skipped, days without a
+/// line had no rain.
+#[derive(Debug, Default)]
+pub struct Weather {
+    rain_mm: HashMap<NaiveDate, f64>,
+}
+
+impl Weather {
+    pub fn parse(source: &mut dyn Read) -> error::Result<Weather> {
+        let mut rain_mm = HashMap::new();
+        for (index, line) in BufReader::new(source).lines().enumerate() {
+            let line = line?;
+            let line = line.trim();
+            if line.is_empty() || (index == 0 && line.starts_with("date")) {
+                continue;
+            }
+
+            let invalid =
+                |what: &str| Error::parse_at(index + 1, format!("{} in {:?}", what, line));
+            let mut fields = line.split(',').map(str::trim);
+            let date = fields
+                .next()
+                .and_then(|date| NaiveDate::parse_from_str(date, "%Y-%m-%d").ok())
+                .ok_or_else(|| invalid("invalid date"))?;
+            let rain = fields
+                .next()
+                .and_then(|rain| rain.parse::<f64>().ok())
+                .ok_or_else(|| invalid("invalid rainfall"))?;
+            rain_mm.insert(date, rain);
+        }
+        Ok(Weather { rain_mm })
+    }
+
+    /// The rainfall on `date` in millimeters.
+    pub fn rain_mm(&self, date: NaiveDate) -> f64 {
+        self.rain_mm.get(&date).copied().unwrap_or(0.0)
+    }
+}
+
+/// Water used through one valve over a simulation.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ZoneUsage {
+    pub runs: usize,
+    pub minutes: i64,
+    pub liters: f64,
+}
+
+/// The outcome of a simulation.
+#[derive(Debug, Default)]
+pub struct Report {
+    /// Runs started.
+    pub runs: usize,
+
+    /// Runs not started because of rain.
+    pub skipped: usize,
+
+    /// Alarms raised.
+    pub alarms: usize,
+
+    /// Use of each zone valve, by name.
+    pub zones: BTreeMap<String, ZoneUsage>,
+}
+
+/// Replays schedules over a range of days with a virtual clock and mock valves.
+///
+/// The real scheduler is stepped a minute at a time. While a zone is open, the flow meter is fed
+/// the valve's `nominal_flow_lpm`, so water is charged to runs the same way as in the garden.
+/// Days with at least `rain_skip_mm` of rain get a rain delay, no new runs start on them.
+pub struct Simulation {
+    pub from: NaiveDate,
+    pub until: NaiveDate,
+    pub weather: Option<Weather>,
+    pub rain_skip_mm: f64,
+}
+
+impl Simulation {
+    /// Runs the schedules read from `schedule` from the start of `from` to the end of `until`.
+    ///
+    /// The resulting logbook is written to `backing`. Runs still in progress at the end are left
+    /// incomplete.
+    pub fn run(
+        &self,
+        schedule: &mut dyn Read,
+        backing: Box<dyn Write + Send>,
+    ) -> io::Result<Report> {
+        let mut calendar = Calendar::new(Box::new(io::sink()));
+        calendar.initialize(schedule)?;
+
+        let start = calendar.start_of_day(self.from);
+        let end = calendar.start_of_day(self.until.succ());
+        let clock = Arc::new(VirtualClock::new(start));
+        let mut state = self.garden(calendar, backing, Arc::clone(&clock) as Arc<dyn Clock>);
+
+        let nominal: HashMap<String, f64> = state
+            .calendar
+            .valves()
+            .filter_map(|valve| valve.nominal_flow_lpm.map(|lpm| (valve.name.clone(), lpm)))
+            .collect();
+        let mut rain_days = HashSet::new();
+        let mut today = None;
+        let mut pending_pulses = 0.0;
+
+        while clock.now() < end {
+            let now = clock.now();
+            let date = state.calendar.date_of(now);
+            if today != Some(date) {
+                today = Some(date);
+                if self.check_weather(&mut state, date) {
+                    rain_days.insert(date);
+                }
+            }
+
+            state.monitor_flow(now)?;
+            Taskmaster::evaluate_schedules(&mut state, now)?;
+
+            // Water flows through the open zones until the next step
+            if let Some(meter) = state.flow_meter() {
+                let lpm: f64 = state
+                    .valves
+                    .open_valves()
+                    .map_err(io::Error::other)?
+                    .iter()
+                    .filter_map(|valve| nominal.get(valve))
+                    .sum();
+                pending_pulses += lpm * self.pulses_per_liter(&state.calendar);
+                meter.counter().add(pending_pulses.floor() as u64);
+                pending_pulses = pending_pulses.fract();
+            }
+
+            clock.advance(Duration::minutes(1));
+        }
+
+        let skipped = state
+            .logbook
+            .iter_skipped()
+            .filter_map(|skip| skip.due_at())
+            .filter(|due| rain_days.contains(&state.calendar.date_of(due.with_timezone(&Utc))))
+            .count();
+        let mut report = Report {
+            runs: state.logbook.iter().count(),
+            skipped,
+            alarms: state.logbook.iter_alarms().count(),
+            zones: BTreeMap::new(),
+        };
+        for record in state.logbook.iter() {
+            let minutes = match (record.started_at(), record.completed_at()) {
+                (Some(started), Some(completed)) => (completed - started).num_minutes(),
+                _ => 0,
+            };
+            for valve in &record.valves {
+                let usage = report.zones.entry(valve.clone()).or_default();
+                usage.runs += 1;
+                usage.minutes += minutes;
+                usage.liters += record.valve_liters.get(valve).copied().unwrap_or(0.0);
+            }
+        }
+        Ok(report)
+    }
+
+    /// Builds a garden with mock valves and a detached flow meter.
+    fn garden(
+        &self,
+        calendar: Calendar,
+        backing: Box<dyn Write + Send>,
+        clock: Arc<dyn Clock>,
+    ) -> GardenState {
+        let mut valves = Valves::with_backend(Backend::Mock);
+        for valve in calendar.valves() {
+            valves
+                .register_new_valve(valve.name.clone(), valve.pin)
+                .expect("mock valves always register");
+        }
+        let pulses_per_liter = self.pulses_per_liter(&calendar);
+        let logbook = Logbook::with_clock(backing, clock);
+
+        let mut state = GardenState::new(calendar, logbook, valves);
+        state.set_flow_meter(Some(FlowMeter::new(PulseCounter::new(), pulses_per_liter)));
+        state
+    }
+
+    fn pulses_per_liter(&self, calendar: &Calendar) -> f64 {
+        calendar
+            .flow_meter()
+            .map_or(SIMULATED_PULSES_PER_LITER, |meter| meter.pulses_per_liter)
+    }
+
+    /// Sets a rain delay for the rest of `date` if it rained enough, returning whether it did.
+    fn check_weather(&self, state: &mut GardenState, date: NaiveDate) -> bool {
+        let rain_mm = match &self.weather {
+            Some(weather) => weather.rain_mm(date),
+            None => return false,
+        };
+        if rain_mm < self.rain_skip_mm {
+            return false;
+        }
+
+        info!("{}mm of rain on {}, delaying runs until the next day", rain_mm, date);
+        state.set_rain_delay(Some(state.calendar.start_of_day(date.succ())));
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SCHEDULE: &str = r#"
+version: "0.2"
+timezone: America/Los_Angeles
+master_valve: main
+valves:
+  - name: main
+    pin: 18
+  - name: beds
+    pin: 22
+    nominal_flow_lpm: 10
+  - name: pots
+    pin: 23
+    nominal_flow_lpm: 4
+schedules:
+  - name: beds every other day
+    start_offsets_min: [420]
+    duration_min: 30
+    repeat_period_days: 2
+    valves:
+      - beds
+  - name: pots daily
+    start_offsets_min: [1080]
+    duration_min: 15
+    repeat_period_days: 1
+    valves:
+      - pots
+"#;
+
+    #[test]
+    fn reports_minutes_and_liters_per_zone() {
+        let report = simulation(None)
+            .run(&mut SCHEDULE.as_bytes(), Box::new(io::sink()))
+            .expect("simulation succeeds");
+
+        assert_eq!(report.runs, 7 + 14);
+        assert_eq!(report.skipped, 0);
+        assert_eq!(report.alarms, 0);
+        let beds = &report.zones["beds"];
+        assert_eq!((beds.runs, beds.minutes), (7, 7 * 30));
+        assert!((beds.liters - 7.0 * 30.0 * 10.0).abs() < 0.01);
+        let pots = &report.zones["pots"];
+        assert_eq!((pots.runs, pots.minutes), (14, 14 * 15));
+        assert!((pots.liters - 14.0 * 15.0 * 4.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn rainy_days_skip_runs() {
+        let csv = "date,rain_mm\n2020-07-01,12.5\n2020-07-02,1.0\n";
+        let weather = Weather::parse(&mut csv.as_bytes()).expect("weather parses");
+
+        let report = simulation(Some(weather))
+            .run(&mut SCHEDULE.as_bytes(), Box::new(io::sink()))
+            .expect("simulation succeeds");
+
+        // July 1st has a run of both schedules, the 2nd is below the threshold
+        assert_eq!(report.skipped, 2);
+        assert_eq!(report.runs, 7 + 14 - 2);
+    }
+
+    #[test]
+    fn invalid_weather_line_is_rejected() {
+        let csv = "date,rain_mm\nyesterday,3\n";
+
+        assert!(Weather::parse(&mut csv.as_bytes()).is_err());
+    }
+
+    fn simulation(weather: Option<Weather>) -> Simulation {
+        Simulation {
+            from: NaiveDate::from_ymd(2020, 7, 1),
+            until: NaiveDate::from_ymd(2020, 7, 14),
+            weather,
+            rain_skip_mm: 5.0,
+        }
+    }
+}
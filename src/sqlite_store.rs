@@ -0,0 +1,694 @@
+use super::config_persist::{SchedulePersist, ValvePersist};
+use super::file_store::Journal;
+use super::logbook::{Alarm, Record, RunId, Skip};
+use rusqlite::{params, Connection, OptionalExtension, Transaction};
+use serde::de::DeserializeOwned;
+use serde_yaml::{Mapping, Value};
+use std::io;
+use std::io::Write;
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS config (key TEXT PRIMARY KEY, value TEXT NOT NULL);
+CREATE TABLE IF NOT EXISTS valves (
+    name TEXT PRIMARY KEY,
+    pin INTEGER NOT NULL,
+    max_flow_lpm REAL,
+    nominal_flow_lpm REAL
+);
+CREATE TABLE IF NOT EXISTS schedules (
+    name TEXT PRIMARY KEY,
+    start_offsets_min TEXT NOT NULL,
+    duration_min INTEGER NOT NULL,
+    repeat_period_days INTEGER NOT NULL,
+    valves TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS logbook (key TEXT PRIMARY KEY, value TEXT NOT NULL);
+CREATE TABLE IF NOT EXISTS records (
+    id INTEGER PRIMARY KEY,
+    name TEXT NOT NULL,
+    started TEXT,
+    completed TEXT,
+    liters REAL,
+    valve_liters TEXT NOT NULL,
+    dry_run INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS records_name ON records (name);
+CREATE TABLE IF NOT EXISTS alarms (
+    id INTEGER PRIMARY KEY,
+    kind TEXT NOT NULL,
+    detected TEXT NOT NULL,
+    liters_per_minute REAL NOT NULL,
+    valves TEXT NOT NULL,
+    acknowledged TEXT
+);
+CREATE TABLE IF NOT EXISTS skipped (
+    id INTEGER PRIMARY KEY,
+    name TEXT NOT NULL,
+    due TEXT NOT NULL,
+    at TEXT NOT NULL,
+    reason TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS journal (id INTEGER PRIMARY KEY, line TEXT NOT NULL);
+";
+
+/// Changes to `SCHEMA`, applied in order to databases whose `user_version` is older.
+const MIGRATIONS: &[&str] = &["
+ALTER TABLE records ADD COLUMN valves TEXT NOT NULL DEFAULT '[]';
+ALTER TABLE skipped ADD COLUMN valves TEXT NOT NULL DEFAULT '[]';
+", "
+ALTER TABLE records ADD COLUMN outcome TEXT;
+ALTER TABLE records ADD COLUMN trigger TEXT;
+ALTER TABLE records ADD COLUMN duration_secs INTEGER;
+ALTER TABLE records ADD COLUMN error TEXT;
+", "
+ALTER TABLE records ADD COLUMN run_id INTEGER;
+"];
+
+/// An embedded SQLite database holding the schedules, valves and logbook.
+///
+/// The database hands out stores that take the place of the YAML files: `Calendar` and
+/// `Logbook` write the same documents to them, which are split into rows in one transaction on
+/// each flush, and read them back from `read_calendar` and `read_logbook`.
+#[derive(Clone)]
+pub struct Database {
+    connection: Arc<Mutex<Connection>>,
+}
+
+impl Database {
+    /// Opens the database at `path`, creating it if it does not exist.
+    pub fn open(path: &Path) -> io::Result<Database> {
+        Database::with_connection(Connection::open(path).map_err(io::Error::other)?)
+    }
+
+    /// Opens a new database that only exists in memory.
+    pub fn open_in_memory() -> io::Result<Database> {
+        Database::with_connection(Connection::open_in_memory().map_err(io::Error::other)?)
+    }
+
+    fn with_connection(mut connection: Connection) -> io::Result<Database> {
+        let transaction = connection.transaction().map_err(io::Error::other)?;
+        execute(&transaction, SCHEMA)?;
+        let version: usize = transaction
+            .query_row("PRAGMA user_version", [], |row| row.get(0))
+            .map_err(io::Error::other)?;
+        for migration in MIGRATIONS.iter().skip(version) {
+            execute(&transaction, migration)?;
+        }
+        execute(
+            &transaction,
+            &format!("PRAGMA user_version = {}", MIGRATIONS.len()),
+        )?;
+        transaction.commit().map_err(io::Error::other)?;
+        Ok(Database {
+            connection: Arc::new(Mutex::new(connection)),
+        })
+    }
+
+    /// A store for the schedules and valves written by a `Calendar`.
+    pub fn calendar_store(&self) -> SqliteStore {
+        SqliteStore::new(self.clone(), Document::Calendar)
+    }
+
+    /// A store for the snapshots written by a `Logbook`.
+    pub fn logbook_store(&self) -> SqliteStore {
+        SqliteStore::new(self.clone(), Document::Logbook)
+    }
+
+    /// A journal for the changes to a `Logbook` since its last snapshot.
+    pub fn journal(&self) -> SqliteJournal {
+        SqliteJournal {
+            database: self.clone(),
+            buffer: Vec::new(),
+        }
+    }
+
+    /// Reads the schedules and valves back as a schedule file, or `None` if none were written.
+    pub fn read_calendar(&self) -> io::Result<Option<String>> {
+        let connection = self.lock();
+        let config: Vec<(String, String)> = query(
+            &connection,
+            "SELECT key, value FROM config ORDER BY rowid",
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )?;
+        if config.is_empty() {
+            return Ok(None);
+        }
+
+        let valves = query(
+            &connection,
+            "SELECT name, pin, max_flow_lpm, nominal_flow_lpm FROM valves ORDER BY rowid",
+            |row| {
+                Ok(ValvePersist {
+                    name: row.get(0)?,
+                    pin: row.get::<_, i64>(1)? as u64,
+                    max_flow_lpm: row.get(2)?,
+                    nominal_flow_lpm: row.get(3)?,
+                })
+            },
+        )?;
+        let schedules = query(
+            &connection,
+            "SELECT name, start_offsets_min, duration_min, repeat_period_days, valves
+             FROM schedules ORDER BY rowid",
+            |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, i64>(2)?,
+                    row.get::<_, i64>(3)?,
+                    row.get::<_, String>(4)?,
+                ))
+            },
+        )?
+        .into_iter()
+        .map(|(name, offsets, duration, period, valves)| {
+            Ok(SchedulePersist {
+                name,
+                start_offsets_min: from_json(&offsets)?,
+                duration_min: duration as u64,
+                repeat_period_days: period as u64,
+                valves: from_json(&valves)?,
+            })
+        })
+        .collect::<io::Result<Vec<_>>>()?;
+
+        let mut document = Mapping::new();
+        for (key, value) in config {
+            let value: serde_json::Value = from_json(&value)?;
+            document.insert(Value::from(key), to_yaml(&value)?);
+        }
+        document.insert(Value::from("valves"), to_yaml(&valves)?);
+        document.insert(Value::from("schedules"), to_yaml(&schedules)?);
+        serde_yaml::to_string(&document)
+            .map(Some)
+            .map_err(invalid_data)
+    }
+
+    /// Reads the logbook back as a logbook file, or `None` if none was written.
+    pub fn read_logbook(&self) -> io::Result<Option<String>> {
+        let connection = self.lock();
+        let sequence: Option<String> = connection
+            .query_row(
+                "SELECT value FROM logbook WHERE key = 'sequence'",
+                [],
+                |row| row.get(0),
+            )
+            .optional()
+            .map_err(io::Error::other)?;
+        let sequence: u64 = match sequence {
+            Some(sequence) => from_json(&sequence)?,
+            None => return Ok(None),
+        };
+        let last_run_id: Option<String> = connection
+            .query_row(
+                "SELECT value FROM logbook WHERE key = 'last_run_id'",
+                [],
+                |row| row.get(0),
+            )
+            .optional()
+            .map_err(io::Error::other)?;
+        let last_run_id: u64 = match last_run_id {
+            Some(last_run_id) => from_json(&last_run_id)?,
+            None => 0,
+        };
+
+        let records = query(
+            &connection,
+            "SELECT name, started, completed, liters, valve_liters, dry_run, valves, outcome,
+             trigger, duration_secs, error, run_id FROM records ORDER BY id",
+            |row| {
+                Ok((
+                    Record {
+                        id: row.get::<_, Option<u64>>(11)?.map(RunId),
+                        name: row.get(0)?,
+                        started: row.get(1)?,
+                        completed: row.get(2)?,
+                        liters: row.get(3)?,
+                        valve_liters: Default::default(),
+                        dry_run: row.get(5)?,
+                        valves: Vec::new(),
+                        outcome: None,
+                        trigger: None,
+                        duration_secs: row.get(9)?,
+                        error: row.get(10)?,
+                    },
+                    row.get::<_, String>(4)?,
+                    row.get::<_, String>(6)?,
+                    row.get::<_, Option<String>>(7)?,
+                    row.get::<_, Option<String>>(8)?,
+                ))
+            },
+        )?
+        .into_iter()
+        .map(|(mut record, valve_liters, valves, outcome, trigger)| {
+            record.valve_liters = from_json(&valve_liters)?;
+            record.valves = from_json(&valves)?;
+            record.outcome = outcome.map(|o| o.parse()).transpose().map_err(invalid_data)?;
+            record.trigger = trigger.map(|t| t.parse()).transpose().map_err(invalid_data)?;
+            Ok(record)
+        })
+        .collect::<io::Result<Vec<_>>>()?;
+        let alarms = query(
+            &connection,
+            "SELECT kind, detected, liters_per_minute, valves, acknowledged
+             FROM alarms ORDER BY id",
+            |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, f64>(2)?,
+                    row.get::<_, String>(3)?,
+                    row.get::<_, Option<String>>(4)?,
+                ))
+            },
+        )?
+        .into_iter()
+        .map(
+            |(kind, detected, liters_per_minute, valves, acknowledged)| {
+                Ok(Alarm {
+                    kind: serde_json::from_value(serde_json::Value::from(kind))?,
+                    detected,
+                    liters_per_minute,
+                    valves: from_json(&valves)?,
+                    acknowledged,
+                })
+            },
+        )
+        .collect::<io::Result<Vec<_>>>()?;
+        let skipped = query(
+            &connection,
+            "SELECT name, due, at, reason, valves FROM skipped ORDER BY id",
+            |row| {
+                Ok((
+                    Skip {
+                        name: row.get(0)?,
+                        due: row.get(1)?,
+                        at: row.get(2)?,
+                        reason: row.get(3)?,
+                        valves: Vec::new(),
+                    },
+                    row.get::<_, String>(4)?,
+                ))
+            },
+        )?
+        .into_iter()
+        .map(|(mut skip, valves)| {
+            skip.valves = from_json(&valves)?;
+            Ok(skip)
+        })
+        .collect::<io::Result<Vec<_>>>()?;
+
+        let mut document = Mapping::new();
+        document.insert(Value::from("records"), to_yaml(&records)?);
+        document.insert(Value::from("alarms"), to_yaml(&alarms)?);
+        if !skipped.is_empty() {
+            document.insert(Value::from("skipped"), to_yaml(&skipped)?);
+        }
+        if sequence != 0 {
+            document.insert(Value::from("sequence"), Value::from(sequence));
+        }
+        if last_run_id != 0 {
+            document.insert(Value::from("last_run_id"), Value::from(last_run_id));
+        }
+        serde_yaml::to_string(&document)
+            .map(Some)
+            .map_err(invalid_data)
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Connection> {
+        self.connection
+            .lock()
+            .expect("database lock is not poisoned")
+    }
+}
+
+/// The documents a `SqliteStore` can hold.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Document {
+    Calendar,
+    Logbook,
+}
+
+/// A `Write` sink that replaces a document in the database on each flush.
+///
+/// Like `FileStore`, writes are buffered in memory. Flushing parses the buffered YAML and
+/// replaces the rows it is stored in within one transaction, so a crash mid-write leaves the
+/// previous document in place.
+pub struct SqliteStore {
+    database: Database,
+    document: Document,
+    buffer: Vec<u8>,
+}
+
+impl SqliteStore {
+    fn new(database: Database, document: Document) -> SqliteStore {
+        SqliteStore {
+            database,
+            document,
+            buffer: Vec::new(),
+        }
+    }
+}
+
+impl Write for SqliteStore {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+        let document: Mapping = serde_yaml::from_slice(&self.buffer).map_err(invalid_data)?;
+
+        let mut connection = self.database.lock();
+        let transaction = connection.transaction().map_err(io::Error::other)?;
+        match self.document {
+            Document::Calendar => write_calendar(&transaction, document)?,
+            Document::Logbook => write_logbook(&transaction, document)?,
+        }
+        transaction.commit().map_err(io::Error::other)?;
+
+        self.buffer.clear();
+        Ok(())
+    }
+}
+
+/// A `Journal` keeping one row per line in the database.
+///
+/// Lines are only inserted once they are complete, so a crash never leaves one half written.
+pub struct SqliteJournal {
+    database: Database,
+    buffer: Vec<u8>,
+}
+
+impl Write for SqliteJournal {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let complete = match self.buffer.iter().rposition(|byte| *byte == b'\n') {
+            Some(end) => end + 1,
+            None => return Ok(()),
+        };
+        let lines: Vec<u8> = self.buffer.drain(..complete).collect();
+        let lines = String::from_utf8(lines).map_err(invalid_data)?;
+
+        let mut connection = self.database.lock();
+        let transaction = connection.transaction().map_err(io::Error::other)?;
+        for line in lines.split_inclusive('\n') {
+            transaction
+                .execute("INSERT INTO journal (line) VALUES (?1)", params![line])
+                .map_err(io::Error::other)?;
+        }
+        transaction.commit().map_err(io::Error::other)
+    }
+}
+
+impl Journal for SqliteJournal {
+    fn contents(&mut self) -> io::Result<Vec<u8>> {
+        let lines: Vec<String> = query(
+            &self.database.lock(),
+            "SELECT line FROM journal ORDER BY id",
+            |row| row.get(0),
+        )?;
+        Ok(lines.concat().into_bytes())
+    }
+
+    fn truncate(&mut self, len: u64) -> io::Result<()> {
+        let connection = self.database.lock();
+        let lines: Vec<(i64, String)> = query(
+            &connection,
+            "SELECT id, line FROM journal ORDER BY id",
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )?;
+
+        let mut end = 0;
+        for (id, line) in lines {
+            end += line.len() as u64;
+            if end > len {
+                connection
+                    .execute("DELETE FROM journal WHERE id >= ?1", params![id])
+                    .map_err(io::Error::other)?;
+                break;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Replaces the schedules, valves and the rest of the schedule file with `document`.
+fn write_calendar(transaction: &Transaction, mut document: Mapping) -> io::Result<()> {
+    let valves: Vec<ValvePersist> = take(&mut document, "valves")?;
+    let schedules: Vec<SchedulePersist> = take(&mut document, "schedules")?;
+
+    execute(
+        transaction,
+        "DELETE FROM config; DELETE FROM valves; DELETE FROM schedules;",
+    )?;
+    for (key, value) in document {
+        let key = key
+            .as_str()
+            .ok_or_else(|| invalid_data("schedule file keys must be strings"))?;
+        transaction
+            .execute(
+                "INSERT INTO config (key, value) VALUES (?1, ?2)",
+                params![key, to_json(&value)?],
+            )
+            .map_err(io::Error::other)?;
+    }
+    for valve in valves {
+        transaction
+            .execute(
+                "INSERT INTO valves (name, pin, max_flow_lpm, nominal_flow_lpm)
+                 VALUES (?1, ?2, ?3, ?4)",
+                params![
+                    valve.name,
+                    valve.pin as i64,
+                    valve.max_flow_lpm,
+                    valve.nominal_flow_lpm
+                ],
+            )
+            .map_err(io::Error::other)?;
+    }
+    for schedule in schedules {
+        transaction
+            .execute(
+                "INSERT INTO schedules
+                 (name, start_offsets_min, duration_min, repeat_period_days, valves)
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![
+                    schedule.name,
+                    to_json(&schedule.start_offsets_min)?,
+                    schedule.duration_min as i64,
+                    schedule.repeat_period_days as i64,
+                    to_json(&schedule.valves)?,
+                ],
+            )
+            .map_err(io::Error::other)?;
+    }
+    Ok(())
+}
+
+/// Replaces the records, alarms and skipped runs with `document`.
+fn write_logbook(transaction: &Transaction, mut document: Mapping) -> io::Result<()> {
+    let records: Vec<Record> = take(&mut document, "records")?;
+    let alarms: Vec<Alarm> = take(&mut document, "alarms")?;
+    let skipped: Vec<Skip> = take(&mut document, "skipped")?;
+    let sequence: u64 = take::<Option<u64>>(&mut document, "sequence")?.unwrap_or(0);
+    let last_run_id: u64 = take::<Option<u64>>(&mut document, "last_run_id")?.unwrap_or(0);
+
+    execute(
+        transaction,
+        "DELETE FROM logbook; DELETE FROM records; DELETE FROM alarms; DELETE FROM skipped;",
+    )?;
+    transaction
+        .execute(
+            "INSERT INTO logbook (key, value) VALUES ('sequence', ?1), ('last_run_id', ?2)",
+            params![sequence.to_string(), last_run_id.to_string()],
+        )
+        .map_err(io::Error::other)?;
+    for record in records {
+        transaction
+            .execute(
+                "INSERT INTO records
+                 (name, started, completed, liters, valve_liters, dry_run, valves, outcome,
+                  trigger, duration_secs, error, run_id)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)",
+                params![
+                    record.name,
+                    record.started,
+                    record.completed,
+                    record.liters,
+                    to_json(&record.valve_liters)?,
+                    record.dry_run,
+                    to_json(&record.valves)?,
+                    record.outcome.map(|outcome| outcome.to_string()),
+                    record.trigger.map(|trigger| trigger.to_string()),
+                    record.duration_secs,
+                    record.error,
+                    record.id.map(|id| id.0),
+                ],
+            )
+            .map_err(io::Error::other)?;
+    }
+    for alarm in alarms {
+        let kind = serde_json::to_value(alarm.kind)?;
+        transaction
+            .execute(
+                "INSERT INTO alarms (kind, detected, liters_per_minute, valves, acknowledged)
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![
+                    kind.as_str(),
+                    alarm.detected,
+                    alarm.liters_per_minute,
+                    to_json(&alarm.valves)?,
+                    alarm.acknowledged,
+                ],
+            )
+            .map_err(io::Error::other)?;
+    }
+    for skip in skipped {
+        transaction
+            .execute(
+                "INSERT INTO skipped (name, due, at, reason, valves) VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![
+                    skip.name,
+                    skip.due,
+                    skip.at,
+                    skip.reason,
+                    to_json(&skip.valves)?
+                ],
+            )
+            .map_err(io::Error::other)?;
+    }
+    Ok(())
+}
+
+/// Removes `key` from `document`, deserializing it or its default if it is missing.
+fn take<T: DeserializeOwned + Default>(document: &mut Mapping, key: &str) -> io::Result<T> {
+    match document.remove(&Value::from(key)) {
+        Some(value) => serde_yaml::from_value(value).map_err(invalid_data),
+        None => Ok(T::default()),
+    }
+}
+
+fn query<T, F>(connection: &Connection, sql: &str, map: F) -> io::Result<Vec<T>>
+where
+    F: FnMut(&rusqlite::Row) -> rusqlite::Result<T>,
+{
+    let mut statement = connection.prepare(sql).map_err(io::Error::other)?;
+    let rows = statement.query_map([], map).map_err(io::Error::other)?;
+    rows.collect::<rusqlite::Result<Vec<T>>>()
+        .map_err(io::Error::other)
+}
+
+fn execute(transaction: &Transaction, sql: &str) -> io::Result<()> {
+    transaction.execute_batch(sql).map_err(io::Error::other)
+}
+
+fn to_json<T: serde::Serialize>(value: &T) -> io::Result<String> {
+    Ok(serde_json::to_string(value)?)
+}
+
+fn from_json<T: DeserializeOwned>(value: &str) -> io::Result<T> {
+    Ok(serde_json::from_str(value)?)
+}
+
+fn to_yaml<T: serde::Serialize>(value: &T) -> io::Result<Value> {
+    serde_yaml::to_value(value).map_err(invalid_data)
+}
+
+fn invalid_data<E>(e: E) -> io::Error
+where
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::calendar::Calendar;
+    use crate::clock::VirtualClock;
+    use crate::file_store::MemoryStore;
+    use crate::logbook::{Logbook, Trigger};
+    use chrono::{TimeZone, Utc};
+
+    #[test]
+    fn calendar_round_trips_through_the_database() {
+        let source = std::fs::read_to_string("examples/schedule.yaml").expect("example exists");
+        let database = Database::open_in_memory().expect("database opens");
+        assert_eq!(database.read_calendar().expect("read succeeds"), None);
+
+        let file = MemoryStore::new();
+        for store in [
+            Box::new(database.calendar_store()) as Box<dyn Write + Send>,
+            Box::new(file.clone()),
+        ] {
+            let mut calendar = Calendar::new(store);
+            calendar
+                .initialize(&mut source.as_bytes())
+                .expect("example parses");
+            calendar.save().expect("save succeeds");
+        }
+
+        let stored = database
+            .read_calendar()
+            .expect("read succeeds")
+            .expect("calendar was written");
+        assert_eq!(
+            serde_yaml::from_str::<serde_json::Value>(&stored).expect("stored calendar is YAML"),
+            serde_yaml::from_slice::<serde_json::Value>(&file.contents())
+                .expect("saved calendar is YAML")
+        );
+    }
+
+    #[test]
+    fn logbook_changes_are_journaled_and_replayed() {
+        let database = Database::open_in_memory().expect("database opens");
+        let clock = Arc::new(VirtualClock::new(Utc.ymd(2020, 6, 1).and_hms(7, 0, 0)));
+        let mut logbook = Logbook::with_journal(
+            Box::new(database.logbook_store()),
+            Box::new(database.journal()),
+            clock.clone(),
+        );
+        let run = logbook
+            .mark_started_by("Pots daily", &[], Trigger::Schedule)
+            .expect("start succeeds");
+        logbook.mark_completed(run).expect("completion succeeds");
+        assert_eq!(database.read_logbook().expect("read succeeds"), None);
+
+        let mut reloaded = Logbook::with_journal(
+            Box::new(database.logbook_store()),
+            Box::new(database.journal()),
+            clock,
+        );
+        assert_eq!(reloaded.replay_journal().expect("replay succeeds"), 2);
+        assert_eq!(reloaded.iter().count(), 1);
+        assert_eq!(reloaded.iter_incomplete().count(), 0);
+        assert!(database
+            .journal()
+            .contents()
+            .expect("read succeeds")
+            .is_empty());
+
+        let snapshot = database
+            .read_logbook()
+            .expect("read succeeds")
+            .expect("snapshot was written");
+        let mut restored = Logbook::new(Box::new(Vec::new()));
+        restored
+            .initialize(&mut snapshot.as_bytes())
+            .expect("snapshot parses");
+        assert_eq!(
+            restored.iter().collect::<Vec<_>>(),
+            reloaded.iter().collect::<Vec<_>>()
+        );
+    }
+}
@@ -0,0 +1,378 @@
+use super::calendar::Calendar;
+use super::logbook::{Logbook, Record};
+use chrono::{DateTime, Datelike, Duration, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io;
+use std::io::Write;
+
+/// The length of time watering is totalled over.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Period {
+    Day,
+
+    /// Weeks starting on Monday.
+    Week,
+    Month,
+}
+
+impl Period {
+    /// The first day of the period `date` falls in.
+    pub fn start_of(&self, date: NaiveDate) -> NaiveDate {
+        match self {
+            Period::Day => date,
+            Period::Week => date - Duration::days(date.weekday().num_days_from_monday() as i64),
+            Period::Month => date.with_day(1).expect("every month has a first day"),
+        }
+    }
+}
+
+impl std::str::FromStr for Period {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Period, String> {
+        match s {
+            "day" => Ok(Period::Day),
+            "week" => Ok(Period::Week),
+            "month" => Ok(Period::Month),
+            _ => Err(format!("unknown period {}, expected day, week or month", s)),
+        }
+    }
+}
+
+/// Watering through one valve over one period.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct ZoneTotal {
+    pub zone: String,
+
+    /// The first day of the period.
+    pub period: NaiveDate,
+
+    /// Runs that opened the valve.
+    pub runs: usize,
+
+    /// Minutes the valve was open.
+    pub minutes: f64,
+
+    /// Liters delivered through the valve, or `None` if none of the runs were metered.
+    pub liters: Option<f64>,
+}
+
+/// How the runs of one schedule went.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct ScheduleSummary {
+    /// Runs that finished, whether they completed or were interrupted.
+    pub runs: usize,
+
+    /// The average length of the runs that finished, in minutes.
+    pub average_minutes: f64,
+
+    /// Runs that were due but neither started nor logged as skipped.
+    pub missed: usize,
+
+    /// Runs that were logged as skipped.
+    pub skipped: usize,
+}
+
+/// Statistics on watering over a range of days.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct WateringReport {
+    /// Totals per zone and period, by zone then period.
+    pub zones: Vec<ZoneTotal>,
+
+    /// Summaries by schedule name.
+    pub schedules: BTreeMap<String, ScheduleSummary>,
+
+    /// The number of skipped runs by why they were skipped.
+    pub skipped_by_reason: BTreeMap<String, usize>,
+}
+
+impl WateringReport {
+    /// Writes the zone totals as CSV lines of `period,zone,runs,minutes,liters`.
+    pub fn write_zones_csv(&self, out: &mut dyn Write) -> io::Result<()> {
+        writeln!(out, "period,zone,runs,minutes,liters")?;
+        for total in &self.zones {
+            writeln!(
+                out,
+                "{},{},{},{:.1},{}",
+                total.period,
+                csv_field(&total.zone),
+                total.runs,
+                total.minutes,
+                total
+                    .liters
+                    .map_or(String::new(), |liters| format!("{:.1}", liters))
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Compiles a `WateringReport` from the logbook.
+///
+/// Days are in the calendar's timezone, `from` and `until` both included. Runs are counted on the
+/// day they started, and only once they finished. Missed runs are found by projecting the current
+/// schedules over the days, so they are only accurate if the schedules have not changed since.
+pub struct Statistics {
+    pub from: NaiveDate,
+    pub until: NaiveDate,
+    pub period: Period,
+}
+
+impl Statistics {
+    pub fn compile(
+        &self,
+        logbook: &Logbook,
+        calendar: &Calendar,
+        now: DateTime<Utc>,
+    ) -> WateringReport {
+        let start = calendar.start_of_day(self.from);
+        let end = calendar.start_of_day(self.until + Duration::days(1));
+        let in_range = |at: DateTime<Utc>| at >= start && at < end;
+
+        let mut report = WateringReport::default();
+        let mut zones: BTreeMap<(String, NaiveDate), ZoneTotal> = BTreeMap::new();
+        let mut run_minutes: BTreeMap<String, f64> = BTreeMap::new();
+        for record in logbook.iter() {
+            let (started, completed) = match (record.started_at(), record.completed_at()) {
+                (Some(started), Some(completed)) => (started.with_timezone(&Utc), completed),
+                _ => continue,
+            };
+            if !in_range(started) {
+                continue;
+            }
+            let minutes = (completed.with_timezone(&Utc) - started).num_seconds() as f64 / 60.0;
+            report
+                .schedules
+                .entry(record.name.clone())
+                .or_default()
+                .runs += 1;
+            *run_minutes.entry(record.name.clone()).or_default() += minutes;
+
+            let period = self.period.start_of(calendar.date_of(started));
+            let valves = valves_of(record, calendar);
+            for valve in &valves {
+                let total = zones
+                    .entry((valve.clone(), period))
+                    .or_insert_with(|| ZoneTotal {
+                        zone: valve.clone(),
+                        period,
+                        runs: 0,
+                        minutes: 0.0,
+                        liters: None,
+                    });
+                total.runs += 1;
+                total.minutes += minutes;
+                let liters = match record.valve_liters.get(valve) {
+                    Some(liters) => Some(*liters),
+                    None if valves.len() == 1 => record.liters,
+                    None => None,
+                };
+                if let Some(liters) = liters {
+                    *total.liters.get_or_insert(0.0) += liters;
+                }
+            }
+        }
+        for (name, minutes) in run_minutes {
+            let summary = report.schedules.entry(name).or_default();
+            summary.average_minutes = minutes / summary.runs as f64;
+        }
+        report.zones = zones.into_values().collect();
+
+        for skip in logbook.iter_skipped() {
+            if !skip
+                .due_at()
+                .is_some_and(|due| in_range(due.with_timezone(&Utc)))
+            {
+                continue;
+            }
+            report
+                .schedules
+                .entry(skip.name.clone())
+                .or_default()
+                .skipped += 1;
+            *report
+                .skipped_by_reason
+                .entry(skip.reason.clone())
+                .or_default() += 1;
+        }
+
+        let days = (self.until - self.from).num_days() as u32 + 1;
+        for occurrence in calendar.project(start, days) {
+            let due = occurrence.start.with_timezone(&Utc);
+            if !in_range(due) || occurrence.end.with_timezone(&Utc) > now {
+                continue;
+            }
+            let started = logbook.iter().any(|record| {
+                record.name == occurrence.schedule
+                    && record
+                        .started_at()
+                        .is_some_and(|at| at >= occurrence.start && at < occurrence.end)
+            });
+            let skipped = logbook.iter_skipped().any(|skip| {
+                skip.name == occurrence.schedule && skip.due_at() == Some(occurrence.start)
+            });
+            if !started && !skipped {
+                report
+                    .schedules
+                    .entry(occurrence.schedule)
+                    .or_default()
+                    .missed += 1;
+            }
+        }
+        report
+    }
+}
+
+/// The valves a run opened, from the record or failing that its schedule's current valves.
+fn valves_of(record: &Record, calendar: &Calendar) -> Vec<String> {
+    if !record.valves.is_empty() {
+        return record.valves.clone();
+    }
+    if !record.valve_liters.is_empty() {
+        return record.valve_liters.keys().cloned().collect();
+    }
+    calendar
+        .get(&record.name)
+        .map(|schedule| schedule.valves().to_vec())
+        .unwrap_or_default()
+}
+
+/// Quotes a CSV field if it holds a separator, quote or line break.
+pub fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        String::from(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::VirtualClock;
+    use crate::logbook::Trigger;
+    use chrono::{FixedOffset, TimeZone};
+    use std::sync::Arc;
+
+    const SCHEDULE: &str = "\
+version: \"0.2\"
+timezone: UTC
+valves:
+  - name: beds valve
+    pin: 22
+  - name: pots valve
+    pin: 23
+schedules:
+  - name: beds
+    start_offsets_min: [420]
+    duration_min: 30
+    repeat_period_days: 1
+    valves: [beds valve]
+  - name: pots
+    start_offsets_min: [1080]
+    duration_min: 10
+    repeat_period_days: 1
+    valves: [pots valve]
+";
+
+    /// Three days of watering: beds runs every day but is skipped on the third, pots only runs
+    /// on the first day, metered.
+    fn garden() -> (Logbook, Calendar, DateTime<Utc>) {
+        let mut calendar = Calendar::new(Box::new(Vec::new()));
+        calendar
+            .initialize(&mut SCHEDULE.as_bytes())
+            .expect("schedule parses");
+        let clock = Arc::new(VirtualClock::new(Utc.ymd(2020, 6, 1).and_hms(7, 0, 0)));
+        let mut logbook = Logbook::with_clock(Box::new(Vec::new()), clock.clone());
+        let beds = vec![String::from("beds valve")];
+        let pots = vec![String::from("pots valve")];
+
+        for day in 0..2 {
+            clock.set(Utc.ymd(2020, 6, 1 + day).and_hms(7, 0, 0));
+            let run = logbook
+                .mark_started_by("beds", &beds, Trigger::Schedule)
+                .expect("start succeeds");
+            clock.advance(Duration::minutes(30 - 10 * day as i64));
+            logbook.mark_completed(run).expect("complete succeeds");
+        }
+        clock.set(Utc.ymd(2020, 6, 1).and_hms(18, 0, 0));
+        let run = logbook
+            .mark_started_by("pots", &pots, Trigger::Schedule)
+            .expect("start succeeds");
+        clock.advance(Duration::minutes(10));
+        logbook
+            .record_volume(run, &[(String::from("pots valve"), 12.5)])
+            .expect("volume succeeds");
+        logbook.mark_completed(run).expect("complete succeeds");
+        let due = FixedOffset::east(0).ymd(2020, 6, 3).and_hms(7, 0, 0);
+        logbook
+            .mark_skipped("beds", due, &beds, "rain delay")
+            .expect("skip succeeds");
+
+        (logbook, calendar, Utc.ymd(2020, 6, 4).and_hms(0, 0, 0))
+    }
+
+    #[test]
+    fn totals_zones_per_period_and_summarizes_schedules() {
+        let (logbook, calendar, now) = garden();
+        let statistics = Statistics {
+            from: NaiveDate::from_ymd(2020, 6, 1),
+            until: NaiveDate::from_ymd(2020, 6, 3),
+            period: Period::Day,
+        };
+
+        let report = statistics.compile(&logbook, &calendar, now);
+
+        let day = |d| NaiveDate::from_ymd(2020, 6, d);
+        assert_eq!(
+            report
+                .zones
+                .iter()
+                .map(|t| (t.zone.as_str(), t.period, t.runs, t.minutes, t.liters))
+                .collect::<Vec<_>>(),
+            vec![
+                ("beds valve", day(1), 1, 30.0, None),
+                ("beds valve", day(2), 1, 20.0, None),
+                ("pots valve", day(1), 1, 10.0, Some(12.5)),
+            ]
+        );
+        let beds = &report.schedules["beds"];
+        assert_eq!(
+            (beds.runs, beds.average_minutes, beds.skipped, beds.missed),
+            (2, 25.0, 1, 0)
+        );
+        let pots = &report.schedules["pots"];
+        assert_eq!((pots.runs, pots.missed), (1, 2));
+        assert_eq!(report.skipped_by_reason["rain delay"], 1);
+    }
+
+    #[test]
+    fn weeks_start_on_monday() {
+        let (logbook, calendar, now) = garden();
+        let statistics = Statistics {
+            from: NaiveDate::from_ymd(2020, 6, 1),
+            until: NaiveDate::from_ymd(2020, 6, 3),
+            period: Period::Week,
+        };
+
+        let report = statistics.compile(&logbook, &calendar, now);
+        let mut csv = Vec::new();
+        report.write_zones_csv(&mut csv).expect("write succeeds");
+
+        assert_eq!(
+            String::from_utf8(csv).expect("CSV is UTF-8"),
+            "period,zone,runs,minutes,liters\n\
+             2020-06-01,beds valve,2,50.0,\n\
+             2020-06-01,pots valve,1,10.0,12.5\n"
+        );
+    }
+
+    #[test]
+    fn csv_fields_are_quoted_when_needed() {
+        assert_eq!(csv_field("beds"), "beds");
+        assert_eq!(csv_field("beds, east"), "\"beds, east\"");
+        assert_eq!(csv_field("the \"big\" bed"), "\"the \"\"big\"\" bed\"");
+    }
+}
@@ -0,0 +1,66 @@
+use std::fmt;
+
+/// A plain text table with left aligned columns, for printing to a terminal.
+pub struct Table {
+    headers: Vec<String>,
+    rows: Vec<Vec<String>>,
+}
+
+impl Table {
+    /// Creates a new, empty table with the given column headers.
+    pub fn new(headers: &[&str]) -> Table {
+        Table {
+            headers: headers.iter().map(|h| String::from(*h)).collect(),
+            rows: Vec::new(),
+        }
+    }
+
+    /// Appends a row, which must have one cell per header.
+    pub fn push(&mut self, row: Vec<String>) {
+        assert_eq!(row.len(), self.headers.len(), "row has one cell per column");
+        self.rows.push(row);
+    }
+}
+
+impl fmt::Display for Table {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut widths: Vec<usize> = self.headers.iter().map(|h| h.chars().count()).collect();
+        for row in &self.rows {
+            for (width, cell) in widths.iter_mut().zip(row) {
+                *width = (*width).max(cell.chars().count());
+            }
+        }
+
+        let write_row = |f: &mut fmt::Formatter, row: &[String]| -> fmt::Result {
+            let line: Vec<String> = row
+                .iter()
+                .zip(&widths)
+                .map(|(cell, width)| format!("{:<width$}", cell, width = width))
+                .collect();
+            writeln!(f, "{}", line.join("  ").trim_end())
+        };
+
+        write_row(f, &self.headers)?;
+        for row in &self.rows {
+            write_row(f, row)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn columns_are_padded_to_widest_cell() {
+        let mut table = Table::new(&["Name", "Pin"]);
+        table.push(vec![String::from("Main valve"), String::from("18")]);
+        table.push(vec![String::from("Pots"), String::from("22")]);
+
+        assert_eq!(
+            table.to_string(),
+            "Name        Pin\nMain valve  18\nPots        22\n"
+        );
+    }
+}
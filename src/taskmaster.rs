@@ -1,20 +1,541 @@
-use super::environment::Environment;
+extern crate log;
 
-struct Taskmaster {
-    env: &Environment,
+use super::constants::TASKMASTER_PERIOD_SEC;
+use super::environment::{
+    Any, AsAny, Environment, EnvironmentBuilder, Lifecycle, Service, ServiceKit,
+};
+use super::error::{Error, Result};
+use super::calendar::Occurrence;
+use super::garden::{Garden, GardenState};
+use super::logbook::{RunId, Trigger};
+use chrono::{DateTime, Offset, Utc};
+use log::{error, info, warn};
+use std::collections::BTreeSet;
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time;
+
+// Background checking of any needed schedule fulfilments
+pub struct Taskmaster {}
+
+impl Taskmaster {
+    /// Completes runs that are over, then starts runs that are due.
+    ///
+    /// A run is due if its schedule has an occurrence in progress at `now` that has no record
+    /// started since the occurrence began. No runs are started while an alarm is active or
+    /// during a rain delay, the runs due are logged as skipped instead.
+    pub fn evaluate_schedules(state: &mut GardenState, now: DateTime<Utc>) -> Result<()> {
+        Taskmaster::complete_finished_runs(state, now)?;
+
+        let held_off = if state.has_active_alarm() {
+            Some("an alarm is active")
+        } else if state.is_rain_delayed(now) {
+            Some("rain delay")
+        } else {
+            None
+        };
+
+        let schedules: Vec<String> = state
+            .calendar
+            .list()
+            .map(|schedule| String::from(schedule.name()))
+            .collect();
+        for name in schedules {
+            let occurrence = match state.calendar.occurrence_at(&name, now) {
+                Some(o) => o,
+                None => continue,
+            };
+            let already_started = state.logbook.iter().any(|record| {
+                record.name == name
+                    && record
+                        .started_at()
+                        .is_some_and(|started| started >= occurrence.start)
+            });
+            if already_started {
+                continue;
+            }
+            if let Some(reason) = held_off {
+                if !state.logbook.was_skipped(&name, occurrence.start) {
+                    state.logbook.mark_skipped(
+                        &name,
+                        occurrence.start,
+                        &occurrence.valves,
+                        reason,
+                    )?;
+                }
+                continue;
+            }
+
+            info!("starting {} due at {}", name, occurrence.start);
+            match Taskmaster::start_run(state, &name, &occurrence.valves, Trigger::Schedule) {
+                Ok(run) => {
+                    state.running.insert(run, occurrence);
+                }
+                // The overlap policy refused it, a run started earlier is still going
+                Err(Error::AlreadyRunning(_)) => {
+                    if !state.logbook.was_skipped(&name, occurrence.start) {
+                        state.logbook.mark_skipped(
+                            &name,
+                            occurrence.start,
+                            &occurrence.valves,
+                            "already running",
+                        )?;
+                    }
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Starts the named schedule at `now` for its usual duration, outside its regular times.
+    ///
+    /// Fails if the schedule does not exist, an alarm is active, or it is already running and the
+    /// logbook's overlap policy refuses a second run.
+    pub fn run_now(state: &mut GardenState, name: &str, now: DateTime<Utc>) -> Result<Occurrence> {
+        let schedule = match state.calendar.get(name) {
+            Some(schedule) => schedule,
+            None => return Err(Error::ScheduleNotFound(String::from(name))),
+        };
+        if state.has_active_alarm() {
+            return Err(Error::AlarmActive);
+        }
+        let start = now.with_timezone(&now.offset().fix());
+        let run = Occurrence {
+            schedule: String::from(name),
+            start,
+            end: start + chrono::Duration::minutes(schedule.duration_min() as i64),
+            valves: schedule.valves().to_vec(),
+        };
+        info!("starting {} by hand until {}", name, run.end);
+        let id = Taskmaster::start_run(state, &run.schedule, &run.valves, Trigger::Manual)?;
+        state.running.insert(id, run.clone());
+        Ok(run)
+    }
+
+    /// Restarts runs that were in progress when the daemon stopped.
+    ///
+    /// Every unfinished run is marked as interrupted. Those whose occurrence is still in progress
+    /// at `now` are started again as recovery runs, reopening their valves until it ends.
+    pub fn begin_unfinished_schedules(state: &mut GardenState, now: DateTime<Utc>) -> Result<()> {
+        if state.has_active_alarm() {
+            warn!("not resuming unfinished runs while an alarm is active");
+            return Ok(());
+        }
+
+        let unfinished: Vec<(RunId, String)> = state
+            .logbook
+            .iter_incomplete()
+            .filter_map(|record| Some((record.id?, record.name.clone())))
+            .collect();
+        for (run, name) in unfinished {
+            match state.calendar.occurrence_at(&name, now) {
+                Some(occurrence) => {
+                    info!("resuming {} until {}", name, occurrence.end);
+                    state.logbook.mark_interrupted(run, "the daemon was stopped")?;
+                    let resumed =
+                        Taskmaster::start_run(state, &name, &occurrence.valves, Trigger::Recovery)?;
+                    state.running.insert(resumed, occurrence);
+                }
+                None => state.logbook.mark_interrupted(run, "the daemon was stopped")?,
+            }
+        }
+
+        Ok(())
+    }
 }
 
 impl Taskmaster {
-    fn new(env: &Environment) -> Taskmaster {
-        Taskmaster {
-            env
+    fn complete_finished_runs(state: &mut GardenState, now: DateTime<Utc>) -> Result<()> {
+        let finished: Vec<(RunId, String, Option<Occurrence>)> = state
+            .logbook
+            .iter_incomplete()
+            .filter_map(|record| {
+                let id = record.id?;
+                let run = state.running.get(&id).cloned().or_else(|| {
+                    let started = record.started_at()?.with_timezone(&Utc);
+                    state.calendar.occurrence_at(&record.name, started)
+                });
+                Some((id, record.name.clone(), run))
+            })
+            // A run that cannot be found in the calendar has nothing left to run
+            .filter(|(_, _, run)| run.as_ref().is_none_or(|run| now >= run.end))
+            .collect();
+
+        for (id, name, run) in finished {
+            info!("completing {}", name);
+            state.logbook.mark_completed(id)?;
+            state.running.remove(&id);
+
+            let valves = match (run, state.calendar.get(&name)) {
+                (Some(run), _) => run.valves,
+                (None, Some(schedule)) => schedule.valves().to_vec(),
+                (None, None) => {
+                    warn!("{} no longer exists, cannot tell which valves to close", name);
+                    Vec::new()
+                }
+            };
+            Taskmaster::close_valves(state, &valves)?;
+        }
+
+        Ok(())
+    }
+
+    /// Logs a run as started and opens its valves, logging it as failed if they will not open.
+    ///
+    /// Runs the logbook's overlap policy replaced are dropped and their valves closed, unless the
+    /// new run uses them.
+    fn start_run(
+        state: &mut GardenState,
+        name: &str,
+        zones: &[String],
+        trigger: Trigger,
+    ) -> Result<RunId> {
+        let run = state.logbook.mark_started_by(name, zones, trigger)?;
+        if let Err(e) = Taskmaster::open_valves(state, zones) {
+            state.logbook.mark_failed(run, &e.to_string())?;
+            if let Err(close) = Taskmaster::close_valves(state, zones) {
+                error!("failed to close valves of {}: {}", name, close);
+            }
+            return Err(e);
+        }
+
+        let replaced: Vec<RunId> = state
+            .running
+            .keys()
+            .filter(|id| {
+                state
+                    .logbook
+                    .get(**id)
+                    .is_none_or(|record| record.completed.is_some())
+            })
+            .copied()
+            .collect();
+        for id in replaced {
+            if let Some(occurrence) = state.running.remove(&id) {
+                Taskmaster::close_valves(state, &occurrence.valves)?;
+            }
+        }
+        Ok(run)
+    }
+
+    /// Opens the master valve, then the given zones.
+    fn open_valves(state: &mut GardenState, zones: &[String]) -> Result<()> {
+        let master = state.calendar.master_valve().map(String::from);
+        for name in master.iter().chain(zones) {
+            match state.valves.get_mut(name) {
+                Some(valve) => valve.open()?,
+                None => error!("cannot open unknown valve {}", name),
+            }
+        }
+        Ok(())
+    }
+
+    /// Closes the given zones unless another run still needs them, then the master valve once
+    /// nothing is running.
+    fn close_valves(state: &mut GardenState, zones: &[String]) -> Result<()> {
+        let running: Vec<(Option<RunId>, String)> = state
+            .logbook
+            .iter_incomplete()
+            .map(|record| (record.id, record.name.clone()))
+            .collect();
+        let in_use: BTreeSet<String> = running
+            .iter()
+            .filter_map(|(id, name)| match id.and_then(|id| state.running.get(&id)) {
+                Some(run) => Some(run.valves.clone()),
+                None => state.calendar.get(name).map(|s| s.valves().to_vec()),
+            })
+            .flatten()
+            .collect();
+
+        let mut to_close: Vec<String> = zones
+            .iter()
+            .filter(|zone| !in_use.contains(*zone))
+            .cloned()
+            .collect();
+        if running.is_empty() {
+            if let Some(master) = state.calendar.master_valve() {
+                to_close.push(String::from(master));
+            }
+        }
+
+        for name in &to_close {
+            match state.valves.get_mut(name) {
+                Some(valve) => valve.close()?,
+                None => error!("cannot close unknown valve {}", name),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Taskmaster {
+    /// Resumes unfinished runs, then evaluates the schedules every period until stopped.
+    async fn run(env: Arc<Environment>) -> io::Result<()> {
+        let clock = env.clock();
+        let period = Duration::from_secs(TASKMASTER_PERIOD_SEC);
+
+        {
+            let garden = env.get::<Garden>();
+            let mut state = garden.lock();
+            if let Err(e) = Taskmaster::begin_unfinished_schedules(&mut state, clock.now()) {
+                error!("resuming unfinished runs failed: {}", e);
+            }
+        }
+
+        loop {
+            {
+                let garden = env.get::<Garden>();
+                let mut state = garden.lock();
+                let now = clock.now();
+                state.apply_config_changes();
+                if let Err(e) = state.monitor_flow(now) {
+                    error!("flow monitoring failed: {}", e);
+                }
+                if let Err(e) = Taskmaster::evaluate_schedules(&mut state, now) {
+                    error!("evaluating schedules failed: {}", e);
+                }
+                if let Err(e) = state.compact_logbook(now) {
+                    error!("archiving old logbook records failed: {}", e);
+                }
+            }
+
+            time::delay_for(period).await;
+        }
+    }
+}
+
+impl Service for Taskmaster {
+    fn create(builder: &mut EnvironmentBuilder) -> io::Result<Taskmaster> {
+        ServiceKit::with_builder(builder)
+            .with_dep::<Garden>()?
+            .new();
+        Ok(Taskmaster {})
+    }
+
+    fn start<'a>(&'a self, env: &'a Arc<Environment>) -> Lifecycle<'a> {
+        env.spawn(Taskmaster::name(), Taskmaster::run(Arc::clone(env)));
+        Box::pin(async { Ok(()) })
+    }
+
+    fn name() -> &'static str {
+        "Taskmaster"
+    }
+}
+
+impl AsAny for Taskmaster {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::calendar::Calendar;
+    use crate::clock::{Clock, VirtualClock};
+    use crate::logbook::{AlarmKind, Logbook, RunOutcome};
+    use crate::valve::{Backend, ValveState, Valves};
+    use chrono::{Duration, TimeZone};
+    use chrono_tz::America::Los_Angeles;
+
+    const SCHEDULE: &str = r#"
+version: "0.2"
+timezone: America/Los_Angeles
+master_valve: main
+valves:
+  - name: main
+    pin: 18
+  - name: beds
+    pin: 22
+  - name: pots
+    pin: 23
+schedules:
+  - name: beds every other day
+    start_offsets_min: [420]
+    duration_min: 30
+    repeat_period_days: 2
+    valves:
+      - beds
+  - name: pots daily
+    start_offsets_min: [1080]
+    duration_min: 15
+    repeat_period_days: 1
+    valves:
+      - pots
+"#;
+
+    /// Simulates two weeks a minute at a time and checks every occurrence ran once, for its full
+    /// duration, leaving every valve closed.
+    #[test]
+    fn simulates_two_weeks_of_schedules() {
+        let start = Los_Angeles.ymd(2020, 7, 1).and_hms(0, 0, 0).with_timezone(&Utc);
+        let clock = Arc::new(VirtualClock::new(start));
+        let mut state = garden(&clock);
+
+        while clock.now() < start + Duration::days(14) {
+            Taskmaster::evaluate_schedules(&mut state, clock.now()).expect("evaluate succeeds");
+            clock.advance(Duration::minutes(1));
+        }
+
+        let runs = |name: &str| state.logbook.iter().filter(|r| r.name == name).count();
+        assert_eq!(runs("beds every other day"), 7);
+        assert_eq!(runs("pots daily"), 14);
+        for record in state.logbook.iter() {
+            let started = record.started_at().expect("started");
+            let completed = record.completed_at().expect("completed");
+            let expected = if record.name == "pots daily" { 15 } else { 30 };
+            assert_eq!(completed - started, Duration::minutes(expected));
         }
+        assert!(state.valves.open_valves().expect("read states").is_empty());
+    }
+
+    #[test]
+    fn due_run_opens_master_and_zone_valves() {
+        let start = Los_Angeles.ymd(2020, 7, 1).and_hms(7, 10, 0).with_timezone(&Utc);
+        let clock = Arc::new(VirtualClock::new(start));
+        let mut state = garden(&clock);
+
+        Taskmaster::evaluate_schedules(&mut state, clock.now()).expect("evaluate succeeds");
+
+        assert_eq!(
+            state.valves.open_valves().expect("read states"),
+            vec![String::from("beds"), String::from("main")]
+        );
+        assert_eq!(state.logbook.iter_incomplete().count(), 1);
     }
 
-    fn evaluate_schedules() {
-        // Look for any schedules that are supposed to be started
+    #[test]
+    fn active_alarm_prevents_new_runs() {
+        let start = Los_Angeles.ymd(2020, 7, 1).and_hms(7, 10, 0).with_timezone(&Utc);
+        let clock = Arc::new(VirtualClock::new(start));
+        let mut state = garden(&clock);
+        state
+            .logbook
+            .raise_alarm(AlarmKind::Leak, 3.0, vec![])
+            .expect("raise_alarm succeeds");
+
+        Taskmaster::evaluate_schedules(&mut state, clock.now()).expect("evaluate succeeds");
+
+        assert_eq!(state.logbook.iter().count(), 0);
+        assert!(state.valves.open_valves().expect("read states").is_empty());
     }
 
-    fn begin_unfinished_schedules() {
+    #[test]
+    fn rain_delay_prevents_new_runs_until_it_ends() {
+        let start = Los_Angeles.ymd(2020, 7, 1).and_hms(7, 10, 0).with_timezone(&Utc);
+        let clock = Arc::new(VirtualClock::new(start));
+        let mut state = garden(&clock);
+        state.set_rain_delay(Some(start + Duration::minutes(5)));
+
+        Taskmaster::evaluate_schedules(&mut state, clock.now()).expect("evaluate succeeds");
+        assert_eq!(state.logbook.iter().count(), 0);
+
+        clock.advance(Duration::minutes(5));
+        Taskmaster::evaluate_schedules(&mut state, clock.now()).expect("evaluate succeeds");
+        assert_eq!(state.logbook.iter().count(), 1);
+    }
+
+    #[test]
+    fn held_off_run_is_logged_as_skipped_once() {
+        let start = Los_Angeles.ymd(2020, 7, 1).and_hms(7, 10, 0).with_timezone(&Utc);
+        let clock = Arc::new(VirtualClock::new(start));
+        let mut state = garden(&clock);
+        state.set_rain_delay(Some(start + Duration::hours(1)));
+
+        for _ in 0..3 {
+            Taskmaster::evaluate_schedules(&mut state, clock.now()).expect("evaluate succeeds");
+            clock.advance(Duration::minutes(1));
+        }
+
+        let skipped: Vec<(&str, &str)> = state
+            .logbook
+            .iter_skipped()
+            .map(|skip| (skip.name.as_str(), skip.reason.as_str()))
+            .collect();
+        assert_eq!(skipped, vec![("beds every other day", "rain delay")]);
+    }
+
+    #[test]
+    fn run_by_hand_lasts_its_duration() {
+        let start = Los_Angeles.ymd(2020, 7, 1).and_hms(12, 0, 0).with_timezone(&Utc);
+        let clock = Arc::new(VirtualClock::new(start));
+        let mut state = garden(&clock);
+
+        let run = Taskmaster::run_now(&mut state, "pots daily", clock.now()).expect("run starts");
+        assert_eq!(run.end - run.start, Duration::minutes(15));
+        assert!(Taskmaster::run_now(&mut state, "pots daily", clock.now()).is_err());
+
+        clock.advance(Duration::minutes(15));
+        Taskmaster::evaluate_schedules(&mut state, clock.now()).expect("evaluate succeeds");
+        assert_eq!(state.logbook.iter_incomplete().count(), 0);
+        assert!(state.valves.open_valves().expect("read states").is_empty());
+    }
+
+    #[test]
+    fn unfinished_run_in_progress_is_resumed() {
+        let start = Los_Angeles.ymd(2020, 7, 1).and_hms(7, 0, 0).with_timezone(&Utc);
+        let clock = Arc::new(VirtualClock::new(start));
+        let mut state = garden(&clock);
+        state
+            .logbook
+            .mark_started_by("beds every other day", &[], Trigger::Schedule)
+            .expect("mark_started_by succeeds");
+
+        // Restarted mid run, with every valve closed
+        clock.advance(Duration::minutes(10));
+        Taskmaster::begin_unfinished_schedules(&mut state, clock.now()).expect("resume succeeds");
+
+        match state.valves.get("beds").expect("valve exists").get_state() {
+            Ok(ValveState::Open) => (),
+            _ => panic!("beds valve reopened"),
+        }
+        let records: Vec<_> = state.logbook.iter().collect();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].outcome, Some(RunOutcome::Interrupted));
+        assert_eq!(records[1].trigger, Some(Trigger::Recovery));
+        assert_eq!(records[1].completed, None);
+        assert!(state.running.contains_key(&records[1].id.expect("run has an id")));
+    }
+
+    #[test]
+    fn unfinished_run_that_is_over_is_interrupted() {
+        let start = Los_Angeles.ymd(2020, 7, 1).and_hms(7, 0, 0).with_timezone(&Utc);
+        let clock = Arc::new(VirtualClock::new(start));
+        let mut state = garden(&clock);
+        state
+            .logbook
+            .mark_started_by("beds every other day", &[], Trigger::Schedule)
+            .expect("mark_started_by succeeds");
+
+        // Restarted after the run should have ended
+        clock.advance(Duration::hours(1));
+        Taskmaster::begin_unfinished_schedules(&mut state, clock.now()).expect("resume succeeds");
+
+        let record = state.logbook.iter().next().expect("record exists");
+        assert_eq!(record.completed_at(), Some(clock.now().into()));
+        assert_eq!(record.outcome, Some(RunOutcome::Interrupted));
+        assert!(record.error.is_some());
+        assert!(state.valves.open_valves().expect("read states").is_empty());
+    }
+
+    fn garden(clock: &Arc<VirtualClock>) -> GardenState {
+        let clock = Arc::clone(clock) as Arc<dyn Clock>;
+        let mut calendar = Calendar::with_clock(Box::new(Vec::<u8>::new()), Arc::clone(&clock));
+        calendar
+            .initialize(&mut SCHEDULE.as_bytes())
+            .expect("initialize succeeds");
+        let logbook = Logbook::with_clock(Box::new(Vec::<u8>::new()), clock);
+
+        let mut valves = Valves::with_backend(Backend::Mock);
+        for valve in calendar.valves() {
+            valves
+                .register_new_valve(valve.name.clone(), valve.pin)
+                .expect("mock valves always register");
+        }
+
+        GardenState::new(calendar, logbook, valves)
     }
 }
@@ -1,14 +1,49 @@
+use super::error::{Error, Result};
 use std::thread;
 use std::time::Duration;
 use sysfs_gpio::{Direction, Pin};
 use std::collections::HashMap;
+use log::info;
+use serde::{Deserialize, Serialize};
+
+/// How valves are driven.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Backend {
+    /// Valves are driven through sysfs GPIO.
+    Gpio,
+
+    /// Valves only exist in memory, for simulation and tests.
+    Mock,
+
+    /// Valves only exist in memory and every action is logged, for staging on a live system.
+    DryRun,
+}
 
 #[derive(Debug)]
 pub struct Valves {
     valves: HashMap<String, Valve>,
+    backend: Backend,
 }
 
 impl Valves {
+    /// Creates a new, empty set of valves driven through GPIO.
+    pub fn new() -> Valves {
+        Valves::with_backend(Backend::Gpio)
+    }
+
+    /// Creates a new, empty set of valves driven by the given backend.
+    pub fn with_backend(backend: Backend) -> Valves {
+        Valves {
+            valves: HashMap::new(),
+            backend,
+        }
+    }
+
+    /// How the valves are driven.
+    pub fn backend(&self) -> Backend {
+        self.backend
+    }
 
     /// Gets a valve by name.
     pub fn get(&self, name: &str) -> Option<&Valve> {
@@ -21,8 +56,46 @@ impl Valves {
     }
 
     /// Registers a new valve connected to the given GPIO pin.
-    pub fn register_new_valve(&mut self, name: String, pin: u64) {
-        self.valves.insert(name.clone(), Valve::new(name, pin));
+    ///
+    /// Fails if the pin cannot be exported, in which case no valve is registered.
+    pub fn register_new_valve(&mut self, name: String, pin: u64) -> Result<()> {
+        let valve = match self.backend {
+            Backend::Gpio => Valve::new(name.clone(), pin)?,
+            Backend::Mock => Valve::mock(name.clone(), pin),
+            Backend::DryRun => Valve::dry_run(name.clone(), pin),
+        };
+        self.valves.insert(name, valve);
+        Ok(())
+    }
+
+    /// Unregisters a valve, returning it if it existed.
+    pub fn remove(&mut self, name: &str) -> Option<Valve> {
+        self.valves.remove(name)
+    }
+
+    /// Returns the names of all valves, sorted by name.
+    pub fn names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.valves.keys().cloned().collect();
+        names.sort();
+        names
+    }
+
+    /// Returns the names of all currently open valves, sorted by name.
+    pub fn open_valves(&self) -> Result<Vec<String>> {
+        let mut open = Vec::new();
+        for valve in self.valves.values() {
+            if let ValveState::Open = valve.get_state()? {
+                open.push(valve.name.clone());
+            }
+        }
+        open.sort();
+        Ok(open)
+    }
+}
+
+impl Default for Valves {
+    fn default() -> Valves {
+        Valves::new()
     }
 }
 
@@ -30,7 +103,19 @@ impl Valves {
 pub struct Valve {
     name: String,
     id: u64,
-    pin: OutputPin,
+    pin: Output,
+}
+
+/// The output driving a valve.
+#[derive(Debug)]
+enum Output {
+    Gpio(OutputPin),
+
+    /// The last value written, valves start closed.
+    Mock(u8),
+
+    /// Like `Mock`, logging each value written.
+    DryRun(u8),
 }
 
 /// The possible states of a controlled valve.
@@ -43,29 +128,64 @@ pub enum ValveState {
 }
 
 impl Valve {
-    /// Creates a new valve connected to the given GPIO pin.
-    pub fn new(name: String, pin: u64) -> Valve {
-        let output_pin = OutputPin::export(pin).expect("exporting pin will not fail");
+    /// Creates a new valve connected to the given GPIO pin, exporting the pin.
+    pub fn new(name: String, pin: u64) -> Result<Valve> {
+        let output_pin = OutputPin::export(pin).map_err(|source| Error::Gpio { pin, source })?;
+        Ok(Valve {
+            name,
+            id: pin,
+            pin: Output::Gpio(output_pin),
+        })
+    }
+
+    /// Creates a new valve that only exists in memory, starting closed.
+    pub fn mock(name: String, pin: u64) -> Valve {
         Valve {
             name,
             id: pin,
-            pin: output_pin,
+            pin: Output::Mock(0),
         }
     }
 
+    /// Creates a new valve that only exists in memory, starting closed, and logs what it would
+    /// do to the GPIO pin.
+    pub fn dry_run(name: String, pin: u64) -> Valve {
+        Valve {
+            name,
+            id: pin,
+            pin: Output::DryRun(0),
+        }
+    }
+
+    /// The name of the valve.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The GPIO pin driving the valve.
+    pub fn pin(&self) -> u64 {
+        self.id
+    }
+
     /// Opens the valve.
     pub fn open(&mut self) -> Result<()> {
-        self.pin.set_value(1)
+        self.set_value(1)
     }
 
     /// Closes the valve.
     pub fn close(&mut self) -> Result<()> {
-        self.pin.set_value(0)
+        self.set_value(0)
     }
 
     /// Retrieves the current valve state.
     pub fn get_state(&self) -> Result<ValveState> {
-        let value = self.pin.get_value()?;
+        let value = match &self.pin {
+            Output::Gpio(pin) => pin.get_value().map_err(|source| Error::Gpio {
+                pin: self.id,
+                source,
+            })?,
+            Output::Mock(value) | Output::DryRun(value) => *value,
+        };
         match value {
             0 => Ok(ValveState::Closed),
             _ => Ok(ValveState::Open),
@@ -73,8 +193,26 @@ impl Valve {
     }
 }
 
-pub type Error = sysfs_gpio::Error;
-pub type Result<T> = sysfs_gpio::Result<T>;
+impl Valve {
+    fn set_value(&mut self, value: u8) -> Result<()> {
+        match &mut self.pin {
+            Output::Gpio(pin) => pin.set_value(value).map_err(|source| Error::Gpio {
+                pin: self.id,
+                source,
+            }),
+            Output::Mock(current) => {
+                *current = value;
+                Ok(())
+            }
+            Output::DryRun(current) => {
+                let action = if value == 0 { "close" } else { "open" };
+                info!("dry run: would {} {} on pin {}", action, self.name, self.id);
+                *current = value;
+                Ok(())
+            }
+        }
+    }
+}
 
 /// RAII guard for a `sysfs_gpio::Pin`.
 ///